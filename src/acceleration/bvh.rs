@@ -1,180 +1,683 @@
 
+use std::thread;
+
 use crate::objects::Instance;
 use crate::acceleration::AccelerationStructure;
 use crate::cg_tools::{Ray, BoundingBox, Transformation};
-use crate::math::{Point, Direction};
+use crate::math::{Point, Vector};
 use crate::scene::Intersection;
+use crate::settings;
+use crate::statistics;
 
-enum Axis {
-    XAxis,
-    YAxis,
-    ZAxis
-}
+const SAH_BINS: usize = 12;
+const TRAVERSAL_COST: f64 = 1.0;
+const INTERSECTION_COST: f64 = 2.0;
+const MAX_LEAF_SIZE: usize = 16;
+// Traversal stacks hold at most one entry per level, the tree is kept below MAX_TREE_DEPTH levels by splitting at the
+// median below MAX_SAH_DEPTH, which adds at most log2(primitives / MAX_LEAF_SIZE) <= 28 levels for 32 bit indices.
+pub const MAX_TREE_DEPTH: usize = 60;
+const MAX_SAH_DEPTH: usize = 32;
+const STACK_SIZE: usize = MAX_TREE_DEPTH + 4;
+const PARALLEL_BUILD_SIZE: usize = 4096;
+const REBUILD_COST_RATIO: f64 = 1.5;
 
-enum BVHNode {
-    Composite(Box<BoundingVolumeHierarchy>,Box<BoundingVolumeHierarchy>),
-    Leaf(Vec<Instance>)
+//////////////////
+//BVHNode
+//////////////////
+// Interior nodes store their first child right after themselves and the index of the second child in offset,
+// leaves store the range of their primitives.
+#[derive(Copy, Clone, Debug)]
+pub struct BVHNode {
+    bbox: BoundingBox,
+    offset: u32,
+    count: u32,
+    axis: u8
 }
 
 impl BVHNode {
-    fn leaf(instances: Vec<Instance>) -> BVHNode{
-        BVHNode::Leaf(instances)
+    pub fn leaf(bbox: BoundingBox, primitives: std::ops::Range<usize>) -> BVHNode {
+        BVHNode{bbox, offset: primitives.start as u32, count: primitives.len() as u32, axis: 0}
     }
 
-    fn composite(instances1: Vec<Instance>, instances2: Vec<Instance>, split_axis: Axis) -> BVHNode{
-        let bvh1 = BoundingVolumeHierarchy::new(instances1);
-        let bvh2 = BoundingVolumeHierarchy::new(instances2);
-        BVHNode::Composite(Box::new(bvh1), Box::new(bvh2))
+    pub fn interior(bbox: BoundingBox, second_child: usize, axis: usize) -> BVHNode {
+        BVHNode{bbox, offset: second_child as u32, count: 0, axis: axis as u8}
     }
 
-    fn bounding_box(&self, transformation: &Transformation) -> BoundingBox {
-        match self {
-            BVHNode::Leaf(instances) => {
-                instances.iter().map(|o|{
-                    o.bounding_box().transformed(transformation)
-                }).fold(BoundingBox::new(Point::max_point(), Point::min_point()), |acc, bbox|{
-                    acc.union(&bbox)
-                })
-            },
-            BVHNode::Composite(bvh1, bvh2) => {
-                let bbox1 = bvh1.bounding_box(transformation);
-                let bbox2 = bvh2.bounding_box(transformation);
-                bbox1.union(&bbox2)
-            }
-        }
-    }
+    pub fn bbox(&self) -> &BoundingBox { &self.bbox }
+    pub fn is_leaf(&self) -> bool { self.count > 0 }
+    pub fn primitives(&self) -> std::ops::Range<usize> { self.offset as usize..(self.offset + self.count) as usize }
+    pub fn second_child(&self) -> usize { self.offset as usize }
+    pub fn axis(&self) -> usize { self.axis as usize }
 }
 
-pub struct BoundingVolumeHierarchy {
-    bbox: BoundingBox,
-    root: BVHNode
+enum Split {
+    Leaf,
+    Bin{axis: usize, bin: usize},
+    Median{axis: usize}
 }
 
-impl BoundingVolumeHierarchy {
+//////////////////
+//LinearBVH
+//////////////////
+pub struct LinearBVH {
+    nodes: Vec<BVHNode>
+}
 
-    pub fn new(instances: Vec<Instance>) -> BoundingVolumeHierarchy {
-        let root = BoundingVolumeHierarchy::split_instances(instances, &Axis::XAxis);
-        let bbox = root.bounding_box(&Transformation::new());
-        BoundingVolumeHierarchy{bbox, root}
+impl LinearBVH {
+    // Returns the hierarchy together with the order in which the primitives have to be stored
+    pub fn build(bounds: &[BoundingBox]) -> (LinearBVH, Vec<usize>) {
+        let centroids: Vec<Point> = bounds.iter().map(|bbox| bbox.centroid()).collect();
+        let mut order: Vec<usize> = (0..bounds.len()).collect();
+        let mut nodes = Vec::with_capacity(2*bounds.len());
+        if !bounds.is_empty() {
+            let threads = settings::get().amt_threads.max(1);
+            LinearBVH::build_node(bounds, &centroids, &mut order, 0, 0, &mut nodes, threads);
+        }
+        (LinearBVH{nodes}, order)
     }
 
-    fn split_instances(mut instances: Vec<Instance>, split_axis: &Axis) -> BVHNode {
-        if instances.len() < 4 {
-            return BVHNode::leaf(instances);
-        }
+    fn build_node(bounds: &[BoundingBox], centroids: &[Point], indices: &mut [usize], offset: usize, depth: usize, nodes: &mut Vec<BVHNode>, threads: usize) -> usize {
+        let bbox = indices.iter().fold(BoundingBox::empty(), |acc, i| acc.union(&bounds[*i]));
+        let index = nodes.len();
+        nodes.push(BVHNode{bbox, offset: offset as u32, count: indices.len() as u32, axis: 0});
 
-        instances.sort_by(|a,b|{
-            let bbox1 = a.bounding_box();
-            let bbox2 = b.bounding_box();
-            if let Some(ord) = bbox1.min().x.partial_cmp(&bbox2.min().x) {
-                ord
+        let centroid_bounds = indices.iter().fold(BoundingBox::empty(), |acc, i| acc.include(centroids[*i]));
+        let split = if depth < MAX_SAH_DEPTH { LinearBVH::find_split(bounds, centroids, indices, &bbox, &centroid_bounds) } else { median_split(indices.len(), &centroid_bounds) };
+        let (mid, axis) = match split {
+            Split::Leaf => return index,
+            Split::Bin{axis, bin} => {
+                let mut mid = 0;
+                for i in 0..indices.len() {
+                    if bin_of(centroids[indices[i]], &centroid_bounds, axis) <= bin {
+                        indices.swap(i, mid);
+                        mid += 1;
+                    }
+                }
+                (mid, axis)
+            },
+            Split::Median{axis} => {
+                let mid = indices.len() / 2;
+                indices.select_nth_unstable_by(mid, |a, b| {
+                    centroids[*a].component(axis).partial_cmp(&centroids[*b].component(axis)).unwrap_or(std::cmp::Ordering::Equal)
+                });
+                (mid, axis)
             }
-            else { std::cmp::Ordering::Equal }
-        });
+        };
 
-        let chunk_size = (instances.len() + 1) / 2; //round up
-        let (instances1, instances2): (Vec<Instance>,Vec<Instance>) = instances.drain(..).enumerate()
-            .fold( (Vec::new(),Vec::new()),|(mut vec1,mut vec2), (i,o)|{
-            if i < chunk_size { vec1.push(o); }
-            else { vec2.push(o); }
-            (vec1,vec2)
-        });
+        let parallel = threads > 1 && indices.len() >= PARALLEL_BUILD_SIZE;
+        let (first, second) = indices.split_at_mut(mid);
+        let second_index = if parallel {
+            // the second subtree is built on its own thread and appended afterwards
+            let second_threads = threads / 2;
+            let second_nodes = thread::scope(|scope| {
+                let handle = scope.spawn(|| {
+                    let mut second_nodes = Vec::with_capacity(2*second.len());
+                    LinearBVH::build_node(bounds, centroids, second, offset + mid, depth + 1, &mut second_nodes, second_threads);
+                    second_nodes
+                });
+                LinearBVH::build_node(bounds, centroids, first, offset, depth + 1, nodes, threads - second_threads);
+                handle.join().expect("BVH build thread panicked")
+            });
 
-        let next_split_axis = match *split_axis {
-            Axis::XAxis => Axis::YAxis,
-            Axis::YAxis => Axis::ZAxis,
-            Axis::ZAxis => Axis::XAxis,
+            let base = nodes.len();
+            nodes.extend(second_nodes.into_iter().map(|mut node| {
+                if !node.is_leaf() { node.offset += base as u32; }
+                node
+            }));
+            base
+        }
+        else {
+            LinearBVH::build_node(bounds, centroids, first, offset, depth + 1, nodes, 1);
+            LinearBVH::build_node(bounds, centroids, second, offset + mid, depth + 1, nodes, 1)
         };
-        BVHNode::composite(instances1, instances2, next_split_axis)
+        nodes[index].offset = second_index as u32;
+        nodes[index].count = 0;
+        nodes[index].axis = axis as u8;
+        index
     }
 
-    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
-        if self.bbox.intersect(&ray).is_none() {
-            return None;
-        }
-
-        match &self.root {
-            BVHNode::Composite(bvh1, bvh2) =>{
-                let int1 = bvh1.bbox.intersect(&ray);
-                let int2 = bvh2.bbox.intersect(&ray);
-                match (int1,int2) {
-                    (None,None) => None,
-                    (Some(_),None) => bvh1.intersect(ray),
-                    (None,Some(_)) => bvh2.intersect(ray),
-                    (Some((t1,_,_)),Some((t2,_,_))) => {
-                        let (first,second) = if t1 < t2 { (bvh1, bvh2) } else { (bvh2,bvh1) };
-                        let int1 = bvh1.intersect(ray);
-                        if int1.is_some() && int1.unwrap().t() < t2 { return int1; }
-                        let int2 = bvh2.intersect(ray);
-                        Intersection::closest_intersection(int1,int2)
-                    }
+    fn find_split(bounds: &[BoundingBox], centroids: &[Point], indices: &[usize], bbox: &BoundingBox, centroid_bounds: &BoundingBox) -> Split {
+        if indices.len() < 2 {
+            return Split::Leaf;
+        }
+
+        let extent = centroid_bounds.extent();
+        let largest_axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+
+        // binned surface area heuristic over every axis with a non degenerate centroid extent
+        let mut best: Option<(f64, usize, usize)> = None;
+        for axis in 0..3 {
+            let axis_extent = extent.component(axis);
+            if axis_extent.is_nan() || axis_extent <= 0.0 { continue }
+            let mut bins = [(BoundingBox::empty(), 0usize); SAH_BINS];
+            for i in indices {
+                let bin = &mut bins[bin_of(centroids[*i], centroid_bounds, axis)];
+                bin.0 = bin.0.union(&bounds[*i]);
+                bin.1 += 1;
+            }
+
+            let mut right_areas = [(0.0, 0usize); SAH_BINS];
+            let (mut right_box, mut right_count) = (BoundingBox::empty(), 0);
+            for i in (1..SAH_BINS).rev() {
+                right_box = right_box.union(&bins[i].0);
+                right_count += bins[i].1;
+                right_areas[i] = (right_box.surface_area(), right_count);
+            }
+
+            let (mut left_box, mut left_count) = (BoundingBox::empty(), 0);
+            for i in 0..SAH_BINS - 1 {
+                left_box = left_box.union(&bins[i].0);
+                left_count += bins[i].1;
+                let (right_area, right_count) = right_areas[i + 1];
+                if left_count == 0 || right_count == 0 { continue }
+
+                let cost = TRAVERSAL_COST + INTERSECTION_COST*(left_box.surface_area()*left_count as f64 + right_area*right_count as f64) / bbox.surface_area();
+                if cost.is_finite() && best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, axis, i));
                 }
-            },
-            BVHNode::Leaf(instances) => {
-                instances.iter()
-                    .map(|o| o.intersect( ray ) )
-                    .fold(None, Intersection::closest_intersection)
             }
         }
+
+        let leaf_cost = INTERSECTION_COST*indices.len() as f64;
+        match best {
+            Some((cost, _, _)) if cost >= leaf_cost && indices.len() <= MAX_LEAF_SIZE => Split::Leaf,
+            Some((_, axis, bin)) => Split::Bin{axis, bin},
+            // unbounded boxes make the heuristic meaningless, fall back to a median split
+            None if indices.len() > MAX_LEAF_SIZE && extent.component(largest_axis) > 0.0 => Split::Median{axis: largest_axis},
+            None => Split::Leaf
+        }
     }
 
-    fn visible(&self, ray: &Ray, distance: f64) -> bool {
-        if self.bbox.intersect(&ray).is_none() {
-            return false;
+    // Checks that the nodes form a valid hierarchy over primitive_count primitives, e.g. when read from a file
+    // Only accepts nodes in the layout written by build, every node has to be reached exactly once
+    // and the depth has to fit into the traversal stacks.
+    pub fn from_nodes(nodes: Vec<BVHNode>, primitive_count: usize) -> Option<LinearBVH> {
+        if nodes.is_empty() || LinearBVH::validate(&nodes, 0, 1, primitive_count) == Some(nodes.len()) {
+            Some(LinearBVH{nodes})
         }
+        else {
+            None
+        }
+    }
 
-        match &self.root {
-            BVHNode::Composite(bvh1, bvh2) =>{
-                let int1 = bvh1.bbox.intersect(&ray);
-                let int2 = bvh2.bbox.intersect(&ray);
-                match (int1,int2) {
-                    (None,None) => true,
-                    (Some(_),None) => bvh1.visible(ray, distance),
-                    (None,Some(_)) => bvh2.visible(ray, distance),
-                    (Some((t1,_,_)),Some((t2,_,_))) => {
-                        let (first,second) = if t1 < t2 { (bvh1, bvh2) } else { (bvh2,bvh1) };
-                        if !first.visible(ray, distance) { return false; }
-                        second.visible(ray, distance)
+    // Returns the index following the subtree starting at index
+    fn validate(nodes: &[BVHNode], index: usize, depth: usize, primitive_count: usize) -> Option<usize> {
+        let node = nodes.get(index)?;
+        if depth > MAX_TREE_DEPTH { return None }
+        if node.is_leaf() {
+            return if node.primitives().end <= primitive_count { Some(index + 1) } else { None };
+        }
+        if node.axis() >= 3 { return None }
+        let end = LinearBVH::validate(nodes, index + 1, depth + 1, primitive_count)?;
+        if node.second_child() != end { return None }
+        LinearBVH::validate(nodes, end, depth + 1, primitive_count)
+    }
+
+    pub fn nodes(&self) -> &[BVHNode] { &self.nodes }
+
+    // Recomputes the node boxes bottom-up from the primitive bounds given in storage order,
+    // children are always stored after their parent so a reverse sweep visits them first.
+    pub fn refit(&mut self, bounds: &[BoundingBox]) {
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            self.nodes[i].bbox = if node.is_leaf() {
+                node.primitives().fold(BoundingBox::empty(), |acc, p| acc.union(&bounds[p]))
+            }
+            else {
+                self.nodes[i + 1].bbox.union(&self.nodes[node.second_child()].bbox)
+            };
+        }
+    }
+
+    // Surface area heuristic cost of the whole hierarchy, used to detect degraded trees
+    pub fn cost(&self) -> f64 {
+        let root_area = match self.nodes.first() {
+            Some(root) => root.bbox.surface_area(),
+            None => return 0.0
+        };
+        self.nodes.iter().map(|node| {
+            let area = node.bbox.surface_area() / root_area;
+            if node.is_leaf() { INTERSECTION_COST*area*node.count as f64 } else { TRAVERSAL_COST*area }
+        }).sum()
+    }
+
+    pub fn closest_hit<T, F>(&self, ray: &Ray, mut intersect: F) -> Option<T> where F: FnMut(usize, f64) -> Option<(f64, T)> {
+        let mut closest = None;
+        let mut t_max = ray.t_max();
+        self.traverse(ray, |range, _| {
+            for i in range {
+                if let Some((t, hit)) = intersect(i, t_max) {
+                    if t < t_max {
+                        t_max = t;
+                        closest = Some(hit);
                     }
                 }
-            },
-            BVHNode::Leaf(instances) => {
-                for object in instances {
-                    if let Some(intersect) = object.intersect( &ray ) {
-                        let dist = (intersect.point() - ray.origin()).length();
-                        if dist < distance {
-                            return false;
+            }
+            (t_max, false)
+        });
+        closest
+    }
+
+    pub fn any_hit<F>(&self, ray: &Ray, mut occluded: F) -> bool where F: FnMut(usize) -> bool {
+        let mut hit = false;
+        self.traverse(ray, |range, t_max| {
+            hit = range.into_iter().any(&mut occluded);
+            (t_max, hit)
+        });
+        hit
+    }
+
+    // Closest hits for a packet of coherent rays, intersect gets the primitive, the ray index and its maximum distance
+    pub fn closest_hits<T, F>(&self, rays: &[Ray], mut intersect: F) -> Vec<Option<T>> where F: FnMut(usize, usize, f64) -> Option<(f64, T)> {
+        let mut closest: Vec<Option<T>> = (0..rays.len()).map(|_| None).collect();
+        self.traverse_packet(rays, |range, active, t_max| {
+            for r in active {
+                for i in range.clone() {
+                    if let Some((t, hit)) = intersect(i, *r, t_max[*r]) {
+                        if t < t_max[*r] {
+                            t_max[*r] = t;
+                            closest[*r] = Some(hit);
                         }
                     }
                 }
-                true
             }
+        });
+        closest
+    }
+
+    // The packet shares one walk through the tree, a node is skipped if interval arithmetic over the whole packet
+    // proves it is missed, otherwise only rays from the first one hitting it onwards are considered below it.
+    fn traverse_packet<F>(&self, rays: &[Ray], mut leaf: F) where F: FnMut(std::ops::Range<usize>, &[usize], &mut [f64]) {
+        if self.nodes.is_empty() || rays.is_empty() { return }
+        let inv_directions: Vec<Vector> = rays.iter().map(|ray| {
+            let d = ray.direction();
+            Vector::new(1.0/d.x, 1.0/d.y, 1.0/d.z)
+        }).collect();
+        let packet = PacketBounds::new(rays, &inv_directions);
+        let t_min = rays.iter().fold(f64::INFINITY, |acc, ray| acc.min(ray.t_min()));
+        let mut t_max: Vec<f64> = rays.iter().map(|ray| ray.t_max()).collect();
+        let mut active = Vec::with_capacity(rays.len());
+
+        let mut stack = [(0usize, 0usize); STACK_SIZE];
+        let mut stack_size = 1;
+        while stack_size > 0 {
+            stack_size -= 1;
+            let (current, first) = stack[stack_size];
+            let node = &self.nodes[current];
+            statistics::node_visit();
+
+            let packet_t_max = t_max[first..].iter().fold(f64::NEG_INFINITY, |acc, t| acc.max(*t));
+            if packet.misses(&node.bbox, t_min, packet_t_max) { continue }
+            let hits = |r: usize, t_max: &[f64]| node.bbox.hit(rays[r].origin(), inv_directions[r], rays[r].t_min(), t_max[r]);
+            let first = match (first..rays.len()).find(|r| hits(*r, &t_max)) {
+                Some(first) => first,
+                None => continue
+            };
+
+            if node.is_leaf() {
+                active.clear();
+                active.extend((first..rays.len()).filter(|r| *r == first || hits(*r, &t_max)));
+                leaf(node.primitives(), &active, &mut t_max);
+            }
+            else {
+                let negative = rays[first].direction().component(node.axis()) < 0.0;
+                let (near, far) = if negative { (node.second_child(), current + 1) } else { (current + 1, node.second_child()) };
+                stack[stack_size] = (far, first);
+                stack[stack_size + 1] = (near, first);
+                stack_size += 2;
+            }
+        }
+    }
+
+    // Visits the leaves front to back, the callback returns the new maximum distance and whether to stop
+    fn traverse<F>(&self, ray: &Ray, mut leaf: F) where F: FnMut(std::ops::Range<usize>, f64) -> (f64, bool) {
+        if self.nodes.is_empty() { return }
+        let (t_min, mut t_max) = (ray.t_min(), ray.t_max());
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let inv_direction = Vector::new(1.0/direction.x, 1.0/direction.y, 1.0/direction.z);
+
+        let mut stack = [0usize; STACK_SIZE];
+        let mut stack_size = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            statistics::node_visit();
+            if node.bbox.hit(origin, inv_direction, t_min, t_max) {
+                if node.is_leaf() {
+                    let (new_t_max, stop) = leaf(node.primitives(), t_max);
+                    if stop { return }
+                    t_max = new_t_max;
+                }
+                else {
+                    let (near, far) = if direction.component(node.axis()) < 0.0 { (node.second_child(), current + 1) } else { (current + 1, node.second_child()) };
+                    stack[stack_size] = far;
+                    stack_size += 1;
+                    current = near;
+                    continue;
+                }
+            }
+            if stack_size == 0 { return }
+            stack_size -= 1;
+            current = stack[stack_size];
         }
     }
 }
 
+//////////////////
+//PacketBounds
+//////////////////
+// Ranges of the origins and inverse directions of a packet, only usable for culling if the directions of all rays
+// have the same sign on every axis
+struct PacketBounds {
+    origin_min: [f64; 3],
+    origin_max: [f64; 3],
+    inv_min: [f64; 3],
+    inv_max: [f64; 3],
+    coherent: bool
+}
+
+impl PacketBounds {
+    fn new(rays: &[Ray], inv_directions: &[Vector]) -> PacketBounds {
+        let mut bounds = PacketBounds{origin_min: [f64::INFINITY; 3], origin_max: [f64::NEG_INFINITY; 3],
+            inv_min: [f64::INFINITY; 3], inv_max: [f64::NEG_INFINITY; 3], coherent: true};
+        for (ray, inv) in rays.iter().zip(inv_directions) {
+            for axis in 0..3 {
+                let (o, i) = (ray.origin().component(axis), inv.component(axis));
+                bounds.origin_min[axis] = bounds.origin_min[axis].min(o);
+                bounds.origin_max[axis] = bounds.origin_max[axis].max(o);
+                bounds.inv_min[axis] = bounds.inv_min[axis].min(i);
+                bounds.inv_max[axis] = bounds.inv_max[axis].max(i);
+            }
+        }
+        bounds.coherent = (0..3).all(|axis| {
+            let (min, max) = (bounds.inv_min[axis], bounds.inv_max[axis]);
+            min.is_finite() && max.is_finite() && (min > 0.0 || max < 0.0)
+        });
+        bounds
+    }
+
+    fn misses(&self, bbox: &BoundingBox, t_min: f64, t_max: f64) -> bool {
+        if !self.coherent { return false }
+        let (mut entry, mut exit) = (t_min, t_max);
+        for axis in 0..3 {
+            let (near, far) = if self.inv_min[axis] > 0.0 {
+                (bbox.min().component(axis), bbox.max().component(axis))
+            } else {
+                (bbox.max().component(axis), bbox.min().component(axis))
+            };
+            let products = |plane: f64| {
+                let (a, b) = (plane - self.origin_max[axis], plane - self.origin_min[axis]);
+                [a*self.inv_min[axis], a*self.inv_max[axis], b*self.inv_min[axis], b*self.inv_max[axis]]
+            };
+            entry = entry.max(products(near).iter().fold(f64::INFINITY, |acc, t| acc.min(*t)));
+            exit = exit.min(products(far).iter().fold(f64::NEG_INFINITY, |acc, t| acc.max(*t)));
+        }
+        entry > exit
+    }
+}
+
+// Halves the primitives until they fit into a leaf, even if all centroids coincide
+fn median_split(count: usize, centroid_bounds: &BoundingBox) -> Split {
+    if count <= MAX_LEAF_SIZE { return Split::Leaf }
+    let extent = centroid_bounds.extent();
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+    Split::Median{axis}
+}
+
+fn bin_of(centroid: Point, centroid_bounds: &BoundingBox, axis: usize) -> usize {
+    let min = centroid_bounds.min().component(axis);
+    let extent = centroid_bounds.max().component(axis) - min;
+    let offset = (centroid.component(axis) - min) / extent;
+    ((offset*SAH_BINS as f64) as usize).min(SAH_BINS - 1)
+}
+
+//////////////////
+//BoundingVolumeHierarchy
+//////////////////
+// Instances are stored in the order of the leaves, order maps them back to their original index
+pub struct BoundingVolumeHierarchy {
+    bvh: LinearBVH,
+    instances: Vec<Instance>,
+    order: Vec<usize>,
+    build_cost: f64
+}
+
+impl BoundingVolumeHierarchy {
+
+    pub fn new(instances: Vec<Instance>) -> BoundingVolumeHierarchy {
+        let mut bvh = BoundingVolumeHierarchy{bvh: LinearBVH{nodes: Vec::new()}, instances: Vec::new(), order: Vec::new(), build_cost: 0.0};
+        bvh.build(instances);
+        bvh
+    }
+
+    fn build(&mut self, instances: Vec<Instance>) {
+        let bounds: Vec<BoundingBox> = instances.iter().map(|inst| *inst.bounding_box()).collect();
+        let (bvh, order) = LinearBVH::build(&bounds);
+
+        self.instances = super::storage_order(instances, &order);
+        self.build_cost = bvh.cost();
+        self.bvh = bvh;
+        self.order = order;
+    }
+
+    fn rebuild(&mut self) {
+        // restore the construction order so the indices given to update stay stable
+        let instances = std::mem::take(&mut self.instances);
+        self.build(super::construction_order(instances, &self.order));
+    }
+}
+
 impl AccelerationStructure for BoundingVolumeHierarchy {
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
-        //println!("{:?}",self.bbox);
-        if self.bbox.intersect(&ray).is_none() {
-            return None;
-        }
+        self.bvh.closest_hit(ray, |i, t_max| {
+            self.instances[i].intersect(&ray.with_t_max(t_max)).map(|int| (int.t(), int))
+        })
+    }
 
-        self.intersect(ray)
+    fn occluded(&self, ray: &Ray) -> bool {
+        self.bvh.any_hit(ray, |i| self.instances[i].occluded(ray))
     }
 
-    fn visible(&self, from: Point, to: Point) -> bool {
-        let dir = to - from;
-        let distance = dir.length();
-        let ray = Ray::new(from, Direction::from(dir));
-        if self.bbox.intersect(&ray).is_none() {
-            return true;
+    fn intersect_packet(&self, rays: &[Ray]) -> Vec<Option<Intersection<'_>>> {
+        self.bvh.closest_hits(rays, |i, r, t_max| {
+            self.instances[i].intersect(&rays[r].with_t_max(t_max)).map(|int| (int.t(), int))
+        })
+    }
+
+    fn update(&mut self, update: &mut dyn FnMut(usize, &mut Instance)) {
+        for (position, inst) in self.instances.iter_mut().enumerate() {
+            update(self.order[position], inst);
         }
+        let bounds: Vec<BoundingBox> = self.instances.iter().map(|inst| *inst.bounding_box()).collect();
+        self.bvh.refit(&bounds);
 
-        self.visible(&ray, distance)
+        // moving instances apart makes boxes overlap, rebuild once the tree got too expensive
+        let cost = self.bvh.cost();
+        if cost.is_finite() && self.build_cost.is_finite() && cost > REBUILD_COST_RATIO*self.build_cost {
+            self.rebuild();
+        }
+    }
+
+    fn instances(&self) -> Vec<&Instance> {
+        let mut instances = vec![None; self.instances.len()];
+        self.order.iter().zip(&self.instances).for_each(|(i, inst)| instances[*i] = Some(inst));
+        instances.into_iter().flatten().collect()
     }
 
     fn bounding_box(&self, transformation: &Transformation) -> BoundingBox {
-        self.root.bounding_box(transformation)
+        self.instances.iter()
+            .map(|inst| inst.bounding_box().transformed(transformation))
+            .fold(BoundingBox::empty(), |acc, bbox| acc.union(&bbox))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Direction;
+    use crate::acceleration::tests::{value, spheres, moved, rays, assert_matches_brute_force};
+
+    fn depth(nodes: &[BVHNode], index: usize) -> usize {
+        let node = &nodes[index];
+        if node.is_leaf() { 1 } else { 1 + depth(nodes, index + 1).max(depth(nodes, node.second_child())) }
+    }
+
+    // Every box is half as far from the origin as the previous one, so the heuristic only splits off a few at a time
+    fn clustered_boxes() -> Vec<BoundingBox> {
+        (0..1000).map(|i| {
+            let x = 0.5f64.powi(i);
+            BoundingBox::new(Point::new(x, 0.0, 0.0), Point::new(x*1.001, 1.0, 1.0))
+        }).collect()
+    }
+
+    #[test]
+    fn clustered_input_stays_within_the_stack() {
+        let bounds = clustered_boxes();
+        let (bvh, order) = LinearBVH::build(&bounds);
+        assert!(depth(bvh.nodes(), 0) <= MAX_TREE_DEPTH, "depth {}", depth(bvh.nodes(), 0));
+
+        let ray = Ray::new(Point::new(-1.0, 0.5, 0.5), Direction::new(1.0, 0.0, 0.0));
+        let inv_direction = Vector::new(1.0, f64::INFINITY, f64::INFINITY);
+        let entry = |i: usize| bounds[i].interval(ray.origin(), inv_direction, 0.0, f64::INFINITY).map(|(t, _)| t);
+        let expected = (0..bounds.len()).filter_map(entry).fold(f64::INFINITY, f64::min);
+        let closest = bvh.closest_hit(&ray, |i, _| entry(order[i]).map(|t| (t, t)));
+        assert_eq!(closest, Some(expected));
+        assert!(bvh.any_hit(&ray, |i| entry(order[i]).is_some()));
+    }
+
+    #[test]
+    fn separated_clusters_are_split_apart() {
+        // two clusters far apart along z, each spread a little along x
+        let bounds: Vec<BoundingBox> = (0..64).map(|i| {
+            let (x, z) = ((i % 32) as f64*0.5, if i < 32 { 0.0 } else { 100.0 });
+            BoundingBox::new(Point::new(x, 0.0, z), Point::new(x + 1.0, 1.0, z + 1.0))
+        }).collect();
+        let (bvh, _) = LinearBVH::build(&bounds);
+        let nodes = bvh.nodes();
+        let (root, first, second) = (&nodes[0], &nodes[1], &nodes[nodes[0].second_child()]);
+        assert!(!root.is_leaf());
+        assert_eq!(root.axis(), 2);
+        assert!(first.bbox().max().z <= 1.0 && second.bbox().min().z >= 100.0);
+        assert!(nodes.iter().filter(|node| node.is_leaf()).all(|node| node.primitives().len() <= MAX_LEAF_SIZE));
+    }
+
+    #[test]
+    fn coincident_centroids_are_split() {
+        let bounds = vec![BoundingBox::new(Point::new(0.0, 0.0, 0.0), Point::new(1.0, 1.0, 1.0)); 1000];
+        let (bvh, _) = LinearBVH::build(&bounds);
+        assert!(depth(bvh.nodes(), 0) <= MAX_TREE_DEPTH);
+    }
+
+    #[test]
+    fn parallel_build_matches_sequential_build() {
+        let bounds: Vec<BoundingBox> = (0..3*PARALLEL_BUILD_SIZE).map(|i| {
+            let min = Point::new(value(3*i)*100.0, value(3*i + 1)*100.0, value(3*i + 2)*100.0);
+            BoundingBox::new(min, min + Vector::new(1.0, 1.0, 1.0)*value(7*i))
+        }).collect();
+        let centroids: Vec<Point> = bounds.iter().map(|bbox| bbox.centroid()).collect();
+        let build = |threads: usize| {
+            let (mut order, mut nodes) = ((0..bounds.len()).collect::<Vec<usize>>(), Vec::new());
+            LinearBVH::build_node(&bounds, &centroids, &mut order, 0, 0, &mut nodes, threads);
+            (order, nodes)
+        };
+
+        let (sequential_order, sequential) = build(1);
+        let (parallel_order, parallel) = build(8);
+        assert_eq!(parallel_order, sequential_order);
+        assert_eq!(parallel.len(), sequential.len());
+        for (p, s) in parallel.iter().zip(&sequential) {
+            assert_eq!((p.offset, p.count, p.axis), (s.offset, s.count, s.axis));
+            assert_eq!((p.bbox().min(), p.bbox().max()), (s.bbox().min(), s.bbox().max()));
+        }
+    }
+
+    #[test]
+    fn cached_nodes_are_validated() {
+        let bounds = clustered_boxes();
+        let (bvh, _) = LinearBVH::build(&bounds);
+        assert!(LinearBVH::from_nodes(bvh.nodes().to_vec(), bounds.len()).is_some());
+        assert!(LinearBVH::from_nodes(bvh.nodes().to_vec(), bounds.len() - 1).is_none());
+        assert!(LinearBVH::from_nodes(bvh.nodes()[..bvh.nodes().len() - 1].to_vec(), bounds.len()).is_none());
+
+        let bbox = BoundingBox::new(Point::new(0.0, 0.0, 0.0), Point::new(1.0, 1.0, 1.0));
+        // both children of the root point at the same leaf
+        let shared = vec![BVHNode::interior(bbox, 1, 0), BVHNode::leaf(bbox, 0..1)];
+        assert!(LinearBVH::from_nodes(shared, 1).is_none());
+        // the second child points back into the first subtree
+        let cycle = vec![BVHNode::interior(bbox, 3, 0), BVHNode::interior(bbox, 2, 0), BVHNode::leaf(bbox, 0..1), BVHNode::interior(bbox, 1, 0)];
+        assert!(LinearBVH::from_nodes(cycle, 1).is_none());
+
+        // a chain deeper than the traversal stacks
+        let chain = |levels: usize| -> Vec<BVHNode> {
+            let mut nodes = Vec::new();
+            for i in 0..levels {
+                nodes.push(BVHNode::interior(bbox, 2*i + 2, 0));
+                nodes.push(BVHNode::leaf(bbox, 0..1));
+            }
+            // the last interior node ends in two leaves
+            nodes.pop();
+            nodes.push(BVHNode::leaf(bbox, 0..1));
+            nodes.push(BVHNode::leaf(bbox, 0..1));
+            nodes
+        };
+        assert!(LinearBVH::from_nodes(chain(MAX_TREE_DEPTH - 1), 1).is_some());
+        assert!(LinearBVH::from_nodes(chain(MAX_TREE_DEPTH + 1), 1).is_none());
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        assert_matches_brute_force(&BoundingVolumeHierarchy::new(spheres(300)), spheres(300));
+    }
+
+    #[test]
+    fn packets_match_single_rays() {
+        let bvh = BoundingVolumeHierarchy::new(spheres(300));
+        // a coherent packet like the camera rays of a chunk, and one pointing every which way
+        let origin = Point::new(0.0, 0.0, -30.0);
+        let coherent: Vec<Ray> = (0..64).map(|i| {
+            let target = Point::new((i % 8) as f64*2.0 - 8.0, (i / 8) as f64 - 4.0, 0.0);
+            Ray::new(origin, Direction::from(target - origin))
+        }).collect();
+        for packet in [coherent, rays()] {
+            let hits = bvh.intersect_packet(&packet);
+            assert_eq!(hits.len(), packet.len());
+            assert!(hits.iter().any(|hit| hit.is_some()));
+            for (ray, hit) in packet.iter().zip(hits) {
+                assert_eq!(hit.map(|int| int.t()), bvh.intersect(ray).map(|int| int.t()), "packet hit of {:?}", ray);
+            }
+        }
+        assert!(bvh.intersect_packet(&[]).is_empty());
+    }
+
+    #[test]
+    fn refit_finds_the_same_hits_as_a_rebuild() {
+        let mut refitted = BoundingVolumeHierarchy::new(spheres(300));
+        let (order, build_cost) = (refitted.order.clone(), refitted.build_cost);
+        refitted.update(&mut |i, inst| moved(i, inst, 0.2));
+        // small movements keep the tree
+        assert_eq!(refitted.order, order);
+        assert_eq!(refitted.build_cost, build_cost);
+
+        let mut instances = spheres(300);
+        instances.iter_mut().enumerate().for_each(|(i, inst)| moved(i, inst, 0.2));
+        let rebuilt = BoundingVolumeHierarchy::new(instances);
+        for ray in rays() {
+            assert_eq!(refitted.intersect(&ray).map(|int| int.t()), rebuilt.intersect(&ray).map(|int| int.t()));
+            assert_eq!(refitted.occluded(&ray), rebuilt.occluded(&ray));
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn degraded_tree_is_rebuilt() {
+        let mut bvh = BoundingVolumeHierarchy::new(spheres(300));
+        let build_cost = bvh.build_cost;
+        bvh.update(&mut |i, inst| moved(i, inst, 40.0));
+        assert_ne!(bvh.build_cost, build_cost);
+        assert!(bvh.bvh.cost() <= REBUILD_COST_RATIO*bvh.build_cost);
+
+        let mut instances = spheres(300);
+        instances.iter_mut().enumerate().for_each(|(i, inst)| moved(i, inst, 40.0));
+        assert_matches_brute_force(&bvh, instances);
+    }
+}
@@ -0,0 +1,283 @@
+
+use crate::objects::Instance;
+use crate::acceleration::AccelerationStructure;
+use crate::cg_tools::{Ray, BoundingBox, Transformation};
+use crate::math::{Point, Vector};
+use crate::scene::Intersection;
+use crate::statistics;
+
+const CELLS_PER_OBJECT: f64 = 3.0;
+const MAX_RESOLUTION: usize = 128;
+const DENSE_CELL_SIZE: usize = 32;
+
+enum Cell {
+    Objects(Vec<usize>),
+    Grid(Box<Grid>)
+}
+
+//////////////////
+//Grid
+//////////////////
+struct Grid {
+    bbox: BoundingBox,
+    resolution: [usize; 3],
+    cell_size: Vector,
+    cells: Vec<Cell>
+}
+
+impl Grid {
+    fn build(bounds: &[BoundingBox], objects: Vec<usize>, bbox: BoundingBox, refine: bool) -> Grid {
+        // cube root of the wanted cell count per unit volume gives cells per unit length
+        let extent = bbox.extent();
+        let volume = extent.x.max(f64::EPSILON)*extent.y.max(f64::EPSILON)*extent.z.max(f64::EPSILON);
+        let cells_per_length = (CELLS_PER_OBJECT*objects.len() as f64 / volume).cbrt();
+        let resolution = [0, 1, 2].map(|axis| {
+            ((extent.component(axis)*cells_per_length).round() as usize).clamp(1, MAX_RESOLUTION)
+        });
+        let cell_size = Vector::new(extent.x/resolution[0] as f64, extent.y/resolution[1] as f64, extent.z/resolution[2] as f64);
+
+        let mut grid = Grid{bbox, resolution, cell_size, cells: Vec::new()};
+        let mut cells: Vec<Vec<usize>> = vec![Vec::new(); resolution[0]*resolution[1]*resolution[2]];
+        for object in objects {
+            let clipped = bounds[object].intersection(&bbox);
+            let (min, max) = (grid.cell_of(clipped.min()), grid.cell_of(clipped.max()));
+            for z in min[2]..=max[2] {
+                for y in min[1]..=max[1] {
+                    for x in min[0]..=max[0] {
+                        cells[grid.index([x, y, z])].push(object);
+                    }
+                }
+            }
+        }
+
+        let cell_count = cells.len();
+        grid.cells = cells.into_iter().enumerate().map(|(i, objects)| {
+            if refine && objects.len() > DENSE_CELL_SIZE && cell_count > 1 {
+                let cell_bounds = grid.cell_bounds(i);
+                Cell::Grid(Box::new(Grid::build(bounds, objects, cell_bounds, false)))
+            }
+            else { Cell::Objects(objects) }
+        }).collect();
+        grid
+    }
+
+    fn cell_of(&self, point: Point) -> [usize; 3] {
+        [0, 1, 2].map(|axis| {
+            let offset = (point.component(axis) - self.bbox.min().component(axis)) / self.cell_size.component(axis);
+            (offset.max(0.0) as usize).min(self.resolution[axis] - 1)
+        })
+    }
+
+    fn index(&self, cell: [usize; 3]) -> usize {
+        cell[0] + self.resolution[0]*(cell[1] + self.resolution[1]*cell[2])
+    }
+
+    fn cell_bounds(&self, index: usize) -> BoundingBox {
+        let x = index % self.resolution[0];
+        let y = (index / self.resolution[0]) % self.resolution[1];
+        let z = index / (self.resolution[0]*self.resolution[1]);
+        let min = self.bbox.min();
+        let corner = |x: usize, y: usize, z: usize| Point::new(
+            min.x + x as f64*self.cell_size.x,
+            min.y + y as f64*self.cell_size.y,
+            min.z + z as f64*self.cell_size.z
+        );
+        BoundingBox::new(corner(x, y, z), corner(x + 1, y + 1, z + 1))
+    }
+
+    // 3D-DDA over the cells pierced by the ray, the callback gets the objects of a cell and the distance
+    // at which the ray leaves it and returns whether to stop.
+    fn traverse<F>(&self, ray: &Ray, t_min: f64, t_max: f64, leaf: &mut F) -> bool where F: FnMut(&[usize], f64) -> bool {
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let inv_direction = Vector::new(1.0/direction.x, 1.0/direction.y, 1.0/direction.z);
+        let (t0, t1) = match self.bbox.interval(origin, inv_direction, t_min, t_max) {
+            Some(interval) => interval,
+            None => return false
+        };
+
+        let entry = origin + t0**direction;
+        let mut cell = self.cell_of(entry).map(|c| c as isize);
+        let mut next_t = [f64::INFINITY; 3];
+        let mut delta_t = [f64::INFINITY; 3];
+        let mut step = [0isize; 3];
+        let mut out = [0isize; 3];
+        for axis in 0..3 {
+            let d = direction.component(axis);
+            let min = self.bbox.min().component(axis);
+            let size = self.cell_size.component(axis);
+            let inv = inv_direction.component(axis);
+            if d > 0.0 {
+                next_t[axis] = t0 + (min + (cell[axis] + 1) as f64*size - entry.component(axis))*inv;
+                delta_t[axis] = size*inv;
+                step[axis] = 1;
+                out[axis] = self.resolution[axis] as isize;
+            }
+            else if d < 0.0 {
+                next_t[axis] = t0 + (min + cell[axis] as f64*size - entry.component(axis))*inv;
+                delta_t[axis] = -size*inv;
+                step[axis] = -1;
+                out[axis] = -1;
+            }
+        }
+
+        let mut t_enter = t0;
+        loop {
+            let axis = if next_t[0] < next_t[1] && next_t[0] < next_t[2] { 0 } else if next_t[1] < next_t[2] { 1 } else { 2 };
+            let t_exit = next_t[axis].min(t1);
+
+            statistics::node_visit();
+            let index = self.index([cell[0] as usize, cell[1] as usize, cell[2] as usize]);
+            let stop = match &self.cells[index] {
+                Cell::Objects(objects) => !objects.is_empty() && leaf(objects, t_exit),
+                Cell::Grid(grid) => grid.traverse(ray, t_enter, t_exit, leaf)
+            };
+            if stop { return true }
+
+            if next_t[axis] > t1 { return false }
+            cell[axis] += step[axis];
+            if cell[axis] == out[axis] { return false }
+            t_enter = next_t[axis];
+            next_t[axis] += delta_t[axis];
+        }
+    }
+}
+
+//////////////////
+//UniformGrid
+//////////////////
+// Cells hold the scene's instances, a mesh is a single object in them and keeps its triangles in its own BVH.
+// Objects with unbounded boxes (e.g. planes) can not be placed in cells and are tested for every ray
+pub struct UniformGrid {
+    grid: Option<Grid>,
+    unbounded: Vec<usize>,
+    instances: Vec<Instance>,
+    hierarchical: bool
+}
+
+impl UniformGrid {
+    pub fn new(instances: Vec<Instance>, hierarchical: bool) -> UniformGrid {
+        let mut grid = UniformGrid{grid: None, unbounded: Vec::new(), instances, hierarchical};
+        grid.build();
+        grid
+    }
+
+    fn build(&mut self) {
+        let bounds: Vec<BoundingBox> = self.instances.iter().map(|inst| *inst.bounding_box()).collect();
+        let is_finite = |bbox: &BoundingBox| bbox.surface_area().is_finite() && bbox.extent().x >= 0.0;
+        let (bounded, unbounded): (Vec<usize>, Vec<usize>) = (0..self.instances.len()).partition(|i| is_finite(&bounds[*i]));
+
+        self.grid = if bounded.is_empty() { None } else {
+            let bbox = bounded.iter().fold(BoundingBox::empty(), |acc, i| acc.union(&bounds[*i]));
+            Some(Grid::build(&bounds, bounded, bbox, self.hierarchical))
+        };
+        self.unbounded = unbounded;
+    }
+}
+
+impl AccelerationStructure for UniformGrid {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let mut closest: Option<Intersection> = self.unbounded.iter()
+            .fold(None, |closest: Option<Intersection>, i| {
+                let ray = ray.with_t_max(closest.map_or(ray.t_max(), |int| int.t()));
+                Intersection::closest_intersection(closest, self.instances[*i].intersect(&ray))
+            });
+
+        if let Some(grid) = &self.grid {
+            let t_max = closest.map_or(ray.t_max(), |int| int.t());
+            grid.traverse(ray, ray.t_min(), t_max, &mut |objects: &[usize], t_exit| {
+                for i in objects {
+                    let ray = ray.with_t_max(closest.map_or(ray.t_max(), |int| int.t()));
+                    if let Some(int) = self.instances[*i].intersect(&ray) {
+                        closest = Some(int);
+                    }
+                }
+                // objects can span several cells, only a hit inside this cell is guaranteed to be the closest
+                closest.is_some_and(|int| int.t() <= t_exit)
+            });
+        }
+        closest
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        if self.unbounded.iter().any(|i| self.instances[*i].occluded(ray)) {
+            return true;
+        }
+        match &self.grid {
+            Some(grid) => grid.traverse(ray, ray.t_min(), ray.t_max(), &mut |objects: &[usize], _| {
+                objects.iter().any(|i| self.instances[*i].occluded(ray))
+            }),
+            None => false
+        }
+    }
+
+    fn update(&mut self, update: &mut dyn FnMut(usize, &mut Instance)) {
+        self.instances.iter_mut().enumerate().for_each(|(i, inst)| update(i, inst));
+        self.build();
+    }
+
+    fn instances(&self) -> Vec<&Instance> {
+        self.instances.iter().collect()
+    }
+
+    fn bounding_box(&self, transformation: &Transformation) -> BoundingBox {
+        self.instances.iter()
+            .map(|inst| inst.bounding_box().transformed(transformation))
+            .fold(BoundingBox::empty(), |acc, bbox| acc.union(&bbox))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::acceleration::tests::{spheres, assert_matches_brute_force};
+    use crate::cg_tools::Color;
+    use crate::math::Normal;
+    use crate::objects::{Plane, Lambertian};
+
+    fn with_floor(mut instances: Vec<Instance>) -> Vec<Instance> {
+        let material = Box::new(Lambertian::new(Color::gray_scale(1.0)));
+        instances.push(Instance::new(Arc::new(Plane::new(Point::new(0.0, -4.0, 0.0), Normal::new(0.0, 1.0, 0.0), false, material))));
+        instances
+    }
+
+    #[test]
+    fn resolution_is_limited() {
+        let bbox = BoundingBox::new(Point::new(0.0, 0.0, 0.0), Point::new(1000.0, 0.0, 0.0));
+        let bounds = vec![bbox; 1000];
+        let grid = Grid::build(&bounds, (0..1000).collect(), bbox, false);
+        assert_eq!(grid.resolution, [MAX_RESOLUTION, 1, 1]);
+        // points outside of the grid fall into the border cells
+        assert_eq!(grid.cell_of(Point::new(-5.0, 2.0, 0.0)), [0, 0, 0]);
+        assert_eq!(grid.cell_of(Point::new(2000.0, 0.0, 0.0)), [MAX_RESOLUTION - 1, 0, 0]);
+    }
+
+    #[test]
+    fn dense_cells_are_refined() {
+        // all boxes end up in the same cell of the top level grid
+        let bounds: Vec<BoundingBox> = (0..100).map(|i| {
+            let min = if i == 0 { Point::new(99.0, 99.0, 99.0) } else { Point::new(0.0, 0.0, i as f64*0.001) };
+            BoundingBox::new(min, min + Vector::new(1.0, 1.0, 0.001))
+        }).collect();
+        let bbox = bounds.iter().fold(BoundingBox::empty(), |acc, b| acc.union(b));
+        let is_refined = |cell: &Cell| matches!(cell, Cell::Grid(_));
+        assert!(Grid::build(&bounds, (0..100).collect(), bbox, true).cells.iter().any(is_refined));
+        assert!(!Grid::build(&bounds, (0..100).collect(), bbox, false).cells.iter().any(is_refined));
+    }
+
+    #[test]
+    fn unbounded_objects_are_tested_for_every_ray() {
+        let grid = UniformGrid::new(with_floor(spheres(100)), true);
+        assert_eq!(grid.unbounded, vec![100]);
+        assert_matches_brute_force(&grid, with_floor(spheres(100)));
+        assert!(UniformGrid::new(with_floor(Vec::new()), false).grid.is_none());
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        assert_matches_brute_force(&UniformGrid::new(spheres(300), false), spheres(300));
+        assert_matches_brute_force(&UniformGrid::new(spheres(300), true), spheres(300));
+    }
+}
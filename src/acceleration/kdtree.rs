@@ -0,0 +1,321 @@
+
+use crate::objects::Instance;
+use crate::acceleration::AccelerationStructure;
+use crate::cg_tools::{Ray, BoundingBox, Transformation};
+use crate::math::{Point, Vector};
+use crate::scene::Intersection;
+use crate::statistics;
+
+const TRAVERSAL_COST: f64 = 1.0;
+const INTERSECTION_COST: f64 = 80.0;
+const EMPTY_BONUS: f64 = 0.5;
+const MAX_LEAF_SIZE: usize = 1;
+const MAX_BAD_REFINES: u32 = 3;
+const STACK_SIZE: usize = 64;
+
+//////////////////
+//KdNode
+//////////////////
+// The child below the split plane directly follows its parent
+#[derive(Copy, Clone, Debug)]
+enum KdNode {
+    Interior{axis: usize, split: f64, above: usize},
+    Leaf{start: usize, count: usize}
+}
+
+#[derive(Copy, Clone)]
+enum EdgeKind {
+    Start,
+    End
+}
+
+struct Edge {
+    t: f64,
+    kind: EdgeKind
+}
+
+//////////////////
+//KdTree
+//////////////////
+// Splits the scene's instances only, the triangles of a mesh stay in the mesh's own BVH.
+// Objects with unbounded boxes (e.g. planes) have no area to split and are tested for every ray
+pub struct KdTree {
+    nodes: Vec<KdNode>,
+    indices: Vec<usize>,
+    unbounded: Vec<usize>,
+    instances: Vec<Instance>,
+    bbox: BoundingBox
+}
+
+impl KdTree {
+    pub fn new(instances: Vec<Instance>) -> KdTree {
+        let mut tree = KdTree{nodes: Vec::new(), indices: Vec::new(), unbounded: Vec::new(), instances, bbox: BoundingBox::empty()};
+        tree.build();
+        tree
+    }
+
+    fn build(&mut self) {
+        let bounds: Vec<BoundingBox> = self.instances.iter().map(|inst| *inst.bounding_box()).collect();
+        let is_finite = |bbox: &BoundingBox| bbox.surface_area().is_finite() && bbox.extent().x >= 0.0;
+        let (bounded, unbounded): (Vec<usize>, Vec<usize>) = (0..self.instances.len()).partition(|i| is_finite(&bounds[*i]));
+        let bbox = bounded.iter().fold(BoundingBox::empty(), |acc, i| acc.union(&bounds[*i]));
+        let max_depth = (8.0 + 1.3*(bounded.len().max(1) as f64).log2()).round() as u32;
+
+        self.nodes.clear();
+        self.indices.clear();
+        self.bbox = bbox;
+        self.build_node(&bounds, bounded, bbox, max_depth, 0);
+        self.unbounded = unbounded;
+    }
+
+    fn build_node(&mut self, bounds: &[BoundingBox], primitives: Vec<usize>, node_bounds: BoundingBox, depth: u32, mut bad_refines: u32) {
+        let index = self.nodes.len();
+        let (axis, split, cost) = match KdTree::find_split(bounds, &primitives, &node_bounds) {
+            Some(best) if primitives.len() > MAX_LEAF_SIZE && depth > 0 => best,
+            _ => return self.push_leaf(primitives)
+        };
+
+        let leaf_cost = INTERSECTION_COST*primitives.len() as f64;
+        if cost > leaf_cost { bad_refines += 1; }
+        if (cost > 4.0*leaf_cost && primitives.len() < 16) || bad_refines == MAX_BAD_REFINES {
+            return self.push_leaf(primitives);
+        }
+
+        // primitive bounds are clipped to the node, so only the part inside the node decides the side
+        let (mut below, mut above) = (Vec::new(), Vec::new());
+        for p in primitives {
+            let clipped = bounds[p].intersection(&node_bounds);
+            if clipped.min().component(axis) <= split { below.push(p); }
+            if clipped.max().component(axis) >= split { above.push(p); }
+        }
+
+        let (mut below_max, mut above_min) = (node_bounds.max(), node_bounds.min());
+        set_component(&mut below_max, axis, split);
+        set_component(&mut above_min, axis, split);
+
+        self.nodes.push(KdNode::Interior{axis, split, above: 0});
+        self.build_node(bounds, below, BoundingBox::new(node_bounds.min(), below_max), depth - 1, bad_refines);
+        let above_index = self.nodes.len();
+        self.build_node(bounds, above, BoundingBox::new(above_min, node_bounds.max()), depth - 1, bad_refines);
+        self.nodes[index] = KdNode::Interior{axis, split, above: above_index};
+    }
+
+    fn push_leaf(&mut self, primitives: Vec<usize>) {
+        self.nodes.push(KdNode::Leaf{start: self.indices.len(), count: primitives.len()});
+        self.indices.extend(primitives);
+    }
+
+    fn find_split(bounds: &[BoundingBox], primitives: &[usize], node_bounds: &BoundingBox) -> Option<(usize, f64, f64)> {
+        let total_area = node_bounds.surface_area();
+        if !total_area.is_finite() || total_area <= 0.0 { return None }
+        let extent = node_bounds.extent();
+
+        let mut best: Option<(usize, f64, f64)> = None;
+        for axis in 0..3 {
+            let mut edges: Vec<Edge> = primitives.iter().flat_map(|p| {
+                let clipped = bounds[*p].intersection(node_bounds);
+                [Edge{t: clipped.min().component(axis), kind: EdgeKind::Start}, Edge{t: clipped.max().component(axis), kind: EdgeKind::End}]
+            }).collect();
+            edges.sort_by(|a, b| {
+                a.t.partial_cmp(&b.t).unwrap_or(std::cmp::Ordering::Equal)
+                    .then((a.kind as u8).cmp(&(b.kind as u8)))
+            });
+
+            let (other1, other2) = ((axis + 1) % 3, (axis + 2) % 3);
+            let cap_area = 2.0*extent.component(other1)*extent.component(other2);
+            let side_length = extent.component(other1) + extent.component(other2);
+            let (axis_min, axis_max) = (node_bounds.min().component(axis), node_bounds.max().component(axis));
+
+            let (mut below, mut above) = (0, primitives.len());
+            for edge in &edges {
+                if let EdgeKind::End = edge.kind { above -= 1; }
+                if edge.t > axis_min && edge.t < axis_max {
+                    let below_area = cap_area + 2.0*(edge.t - axis_min)*side_length;
+                    let above_area = cap_area + 2.0*(axis_max - edge.t)*side_length;
+                    let bonus = if below == 0 || above == 0 { EMPTY_BONUS } else { 0.0 };
+                    let cost = TRAVERSAL_COST + INTERSECTION_COST*(1.0 - bonus)*(below_area*below as f64 + above_area*above as f64)/total_area;
+                    if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                        best = Some((axis, edge.t, cost));
+                    }
+                }
+                if let EdgeKind::Start = edge.kind { below += 1; }
+            }
+        }
+        best
+    }
+
+    // Walks the leaves along the ray front to back, the callback returns whether the traversal can stop
+    // once the current segment has been handled.
+    fn traverse<F>(&self, ray: &Ray, mut leaf: F) where F: FnMut(&[usize], f64) -> bool {
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let inv_direction = Vector::new(1.0/direction.x, 1.0/direction.y, 1.0/direction.z);
+        let (mut t_min, mut t_max) = match self.bbox.interval(origin, inv_direction, ray.t_min(), ray.t_max()) {
+            Some(interval) => interval,
+            None => return
+        };
+
+        let mut stack = [(0usize, 0.0, 0.0); STACK_SIZE];
+        let mut stack_size = 0;
+        let mut current = 0;
+        loop {
+            statistics::node_visit();
+            match self.nodes[current] {
+                KdNode::Interior{axis, split, above} => {
+                    let o = origin.component(axis);
+                    let t_plane = (split - o)*inv_direction.component(axis);
+                    let below_first = o < split || (o == split && direction.component(axis) <= 0.0);
+                    let (first, second) = if below_first { (current + 1, above) } else { (above, current + 1) };
+
+                    if t_plane > t_max || t_plane <= 0.0 {
+                        current = first;
+                    }
+                    else if t_plane < t_min {
+                        current = second;
+                    }
+                    else {
+                        stack[stack_size] = (second, t_plane, t_max);
+                        stack_size += 1;
+                        current = first;
+                        t_max = t_plane;
+                    }
+                    continue;
+                },
+                KdNode::Leaf{start, count} => {
+                    if leaf(&self.indices[start..start + count], t_max) { return }
+                }
+            }
+            if stack_size == 0 { return }
+            stack_size -= 1;
+            let (node, node_t_min, node_t_max) = stack[stack_size];
+            current = node;
+            t_min = node_t_min;
+            t_max = node_t_max;
+        }
+    }
+}
+
+fn set_component(point: &mut Point, axis: usize, value: f64) {
+    *point = match axis {
+        0 => Point::new(value, point.y, point.z),
+        1 => Point::new(point.x, value, point.z),
+        _ => Point::new(point.x, point.y, value)
+    };
+}
+
+impl AccelerationStructure for KdTree {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let mut closest: Option<Intersection> = self.unbounded.iter()
+            .fold(None, |closest: Option<Intersection>, i| {
+                let ray = ray.with_t_max(closest.map_or(ray.t_max(), |int| int.t()));
+                Intersection::closest_intersection(closest, self.instances[*i].intersect(&ray))
+            });
+
+        let clipped = ray.with_t_max(closest.map_or(ray.t_max(), |int| int.t()));
+        self.traverse(&clipped, |primitives, t_max| {
+            for p in primitives {
+                let ray = ray.with_t_max(closest.map_or(ray.t_max(), |int| int.t()));
+                if let Some(int) = self.instances[*p].intersect(&ray) {
+                    closest = Some(int);
+                }
+            }
+            // primitives can span several leaves, only a hit inside this leaf is guaranteed to be the closest
+            closest.is_some_and(|int| int.t() <= t_max)
+        });
+        closest
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        if self.unbounded.iter().any(|i| self.instances[*i].occluded(ray)) {
+            return true;
+        }
+        let mut occluded = false;
+        self.traverse(ray, |primitives, _| {
+            occluded = primitives.iter().any(|p| self.instances[*p].occluded(ray));
+            occluded
+        });
+        occluded
+    }
+
+    fn update(&mut self, update: &mut dyn FnMut(usize, &mut Instance)) {
+        // split planes can not be moved, the tree is always rebuilt
+        self.instances.iter_mut().enumerate().for_each(|(i, inst)| update(i, inst));
+        self.build();
+    }
+
+    fn instances(&self) -> Vec<&Instance> {
+        self.instances.iter().collect()
+    }
+
+    fn bounding_box(&self, transformation: &Transformation) -> BoundingBox {
+        self.instances.iter()
+            .map(|inst| inst.bounding_box().transformed(transformation))
+            .fold(BoundingBox::empty(), |acc, bbox| acc.union(&bbox))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::acceleration::tests::{spheres, assert_matches_brute_force};
+    use crate::cg_tools::Color;
+    use crate::math::Normal;
+    use crate::objects::{Plane, Lambertian};
+
+    fn depth(nodes: &[KdNode], index: usize) -> usize {
+        match nodes[index] {
+            KdNode::Interior{above, ..} => 1 + depth(nodes, index + 1).max(depth(nodes, above)),
+            KdNode::Leaf{..} => 1
+        }
+    }
+
+    #[test]
+    fn split_separates_distant_boxes() {
+        let bounds = vec![
+            BoundingBox::new(Point::new(0.0, 0.0, 0.0), Point::new(1.0, 1.0, 1.0)),
+            BoundingBox::new(Point::new(0.0, 0.0, 9.0), Point::new(1.0, 1.0, 10.0))
+        ];
+        let node_bounds = bounds[0].union(&bounds[1]);
+        let (axis, split, _) = KdTree::find_split(&bounds, &[0, 1], &node_bounds).unwrap();
+        assert_eq!(axis, 2);
+        assert!((1.0..=9.0).contains(&split), "{}", split);
+        // a flat node has no area to split
+        let flat = BoundingBox::new(Point::new(0.0, 0.0, 0.0), Point::new(1.0, 1.0, 0.0));
+        assert!(KdTree::find_split(&[flat], &[0], &flat).is_none());
+    }
+
+    #[test]
+    fn overlapping_instances_stop_refining() {
+        let tree = KdTree::new((0..50).map(|_| spheres(1).pop().unwrap()).collect());
+        assert!(depth(&tree.nodes, 0) <= MAX_BAD_REFINES as usize + 1, "depth {}", depth(&tree.nodes, 0));
+        assert_matches_brute_force(&tree, (0..50).map(|_| spheres(1).pop().unwrap()).collect());
+    }
+
+    #[test]
+    fn unbounded_objects_are_tested_for_every_ray() {
+        let with_floor = |mut instances: Vec<Instance>| {
+            let material = Box::new(Lambertian::new(Color::gray_scale(1.0)));
+            instances.push(Instance::new(Arc::new(Plane::new(Point::new(0.0, -4.0, 0.0), Normal::new(0.0, 1.0, 0.0), false, material))));
+            instances
+        };
+        let tree = KdTree::new(with_floor(spheres(100)));
+        assert_eq!(tree.unbounded, vec![100]);
+        assert!(depth(&tree.nodes, 0) > 1);
+        assert_matches_brute_force(&tree, with_floor(spheres(100)));
+        assert_matches_brute_force(&KdTree::new(with_floor(Vec::new())), with_floor(Vec::new()));
+    }
+
+    #[test]
+    fn small_trees_do_not_panic() {
+        for count in [0, 1, 2, 3] {
+            assert_matches_brute_force(&KdTree::new(spheres(count)), spheres(count));
+        }
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        assert_matches_brute_force(&KdTree::new(spheres(300)), spheres(300));
+    }
+}
@@ -1,28 +1,64 @@
 mod bvh;
+mod kdtree;
+mod grid;
+mod wide_bvh;
+
+pub use self::bvh::{BVHNode, LinearBVH};
+
+use std::time::Instant;
 
 use crate::cg_tools::{BoundingBox, Ray, Transformation};
 use crate::math::{Point, Direction};
 use crate::scene::Intersection;
 use crate::objects::Instance;
 use crate::settings;
+use crate::statistics;
 
+// Only selects the top level structure over the instances, the triangles of a mesh are always found through the
+// mesh's own BVH
 #[derive(Copy, Clone, Debug)]
 pub enum AccelerationStructureKind {
     BruteForce,
-    BVH
+    BVH,
+    BVH4,
+    BVH8,
+    KdTree,
+    Grid{hierarchical: bool}
 }
 
 pub trait AccelerationStructure : Send + Sync {
     fn intersect(&self, ray: &Ray) -> Option<Intersection>;
-    fn visible(&self, from: Point, to: Point) -> bool;
+    fn occluded(&self, ray: &Ray) -> bool;
     fn bounding_box(&self, transformation: &Transformation) -> BoundingBox;
+    // Lets the callback modify every instance, identified by its index in the construction order,
+    // and brings the structure up to date afterwards
+    fn update(&mut self, update: &mut dyn FnMut(usize, &mut Instance));
+    // Every instance, in the order the structure was created from
+    fn instances(&self) -> Vec<&Instance>;
+
+    fn intersect_packet(&self, rays: &[Ray]) -> Vec<Option<Intersection<'_>>> {
+        rays.iter().map(|ray| self.intersect(ray)).collect()
+    }
+
+    fn visible(&self, from: Point, to: Point) -> bool {
+        let dir = to - from;
+        let ray = Ray::new(from, Direction::from(dir)).with_interval(0.0, dir.length());
+        !self.occluded(&ray)
+    }
 }
 
 pub fn create_acceleration_structure(instances : Vec<Instance>) -> Box<dyn AccelerationStructure> {
-    match settings::get().acceleration_structure {
+    let now = Instant::now();
+    let acc_structure: Box<dyn AccelerationStructure> = match settings::get().acceleration_structure {
         AccelerationStructureKind::BruteForce => Box::new(BruteForce::new(instances)),
         AccelerationStructureKind::BVH => Box::new(bvh::BoundingVolumeHierarchy::new(instances)),
-    }
+        AccelerationStructureKind::BVH4 => Box::new(wide_bvh::WideBVH::<4>::new(instances)),
+        AccelerationStructureKind::BVH8 => Box::new(wide_bvh::WideBVH::<8>::new(instances)),
+        AccelerationStructureKind::KdTree => Box::new(kdtree::KdTree::new(instances)),
+        AccelerationStructureKind::Grid{hierarchical} => Box::new(grid::UniformGrid::new(instances, hierarchical)),
+    };
+    statistics::acceleration_build(now.elapsed());
+    acc_structure
 }
 
 struct BruteForce{
@@ -35,26 +71,22 @@ impl BruteForce {
 
 impl AccelerationStructure for BruteForce {
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
-        self.instances.iter()
-            .map(|inst| inst.intersect( ray ) )
-            .fold(None, Intersection::closest_intersection)
+        self.instances.iter().fold(None, |closest: Option<Intersection>, inst| {
+            let ray = ray.with_t_max(closest.map_or(ray.t_max(), |int| int.t()));
+            Intersection::closest_intersection(closest, inst.intersect( &ray ))
+        })
     }
 
-    fn visible(&self, from: Point, to: Point) -> bool {
-        let dir = to - from;
-        let distance = dir.length();
-        let ray = Ray::new(from, Direction::from(dir));
-
-        for instance in &self.instances {
-            let opt_int = instance.intersect( &ray );
-            if let Some(intersect) = opt_int {
-                let dist = (intersect.point() - from).length();
-                if dist < distance {
-                    return false;
-                }
-            }
-        }
-        true
+    fn occluded(&self, ray: &Ray) -> bool {
+        self.instances.iter().any(|inst| inst.occluded(ray))
+    }
+
+    fn update(&mut self, update: &mut dyn FnMut(usize, &mut Instance)) {
+        self.instances.iter_mut().enumerate().for_each(|(i, inst)| update(i, inst));
+    }
+
+    fn instances(&self) -> Vec<&Instance> {
+        self.instances.iter().collect()
     }
 
     fn bounding_box(&self, transformation: &Transformation) -> BoundingBox {
@@ -65,4 +97,126 @@ impl AccelerationStructure for BruteForce {
             });
         bbox
     }
-}
\ No newline at end of file
+}
+// Hierarchies store their instances in the order of their leaves, order maps every stored position to the index
+// the instance had when the structure was created
+fn storage_order(instances: Vec<Instance>, order: &[usize]) -> Vec<Instance> {
+    let mut instances: Vec<Option<Instance>> = instances.into_iter().map(Some).collect();
+    order.iter().map(|i| instances[*i].take().unwrap()).collect()
+}
+
+fn construction_order(instances: Vec<Instance>, order: &[usize]) -> Vec<Instance> {
+    let mut original: Vec<Option<Instance>> = (0..instances.len()).map(|_| None).collect();
+    for (inst, index) in instances.into_iter().zip(order) {
+        original[*index] = Some(inst);
+    }
+    original.into_iter().map(|inst| inst.unwrap()).collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+
+    use super::{AccelerationStructure, BruteForce, bvh, wide_bvh, kdtree, grid};
+    use crate::objects::{Instance, Sphere, Lambertian};
+    use crate::cg_tools::{Ray, Transformation, Color};
+    use crate::math::{Point, Vector, Direction};
+
+    // Deterministic value in [0, 1) so failures can be reproduced
+    pub fn value(i: usize) -> f64 {
+        let x = (i as f64 * 12.9898 + 78.233).sin() * 43758.5453;
+        x - x.floor()
+    }
+
+    // Spheres of different sizes in a few clusters, some of them overlapping
+    pub fn spheres(count: usize) -> Vec<Instance> {
+        (0..count).map(|i| {
+            let cluster = Vector::new((i % 3) as f64 * 8.0 - 8.0, 0.0, (i % 2) as f64 * 6.0);
+            let offset = Vector::new(value(3*i)*4.0 - 2.0, value(3*i + 1)*4.0 - 2.0, value(3*i + 2)*4.0 - 2.0);
+            let sphere = Arc::new(Sphere::new(Box::new(Lambertian::new(Color::gray_scale(1.0)))));
+            Instance::transformed(sphere, Transformation::new()
+                .scale_all(0.2 + value(7*i)*0.8)
+                .translate(cluster + offset))
+        }).collect()
+    }
+
+    // Rays from all around the scene towards it, including axis aligned ones
+    pub fn rays() -> Vec<Ray> {
+        let mut rays: Vec<Ray> = (0..400).map(|i| {
+            let origin = Point::new(value(5*i)*60.0 - 30.0, value(5*i + 1)*60.0 - 30.0, value(5*i + 2)*60.0 - 30.0);
+            let target = Point::new(value(5*i + 3)*20.0 - 10.0, value(5*i + 4)*6.0 - 3.0, value(11*i)*10.0 - 2.0);
+            Ray::new(origin, Direction::from(target - origin))
+        }).collect();
+        for i in 0..40 {
+            let origin = Point::new(value(13*i)*20.0 - 10.0, value(13*i + 1)*6.0 - 3.0, -20.0);
+            rays.push(Ray::new(origin, Direction::new(0.0, 0.0, 1.0)));
+            rays.push(Ray::new(origin, Direction::new(0.0, 0.0, 1.0)).with_interval(21.0, 24.0));
+        }
+        rays
+    }
+
+    // Moves the instance with the given construction index by up to distance along every axis
+    pub fn moved(index: usize, inst: &mut Instance, distance: f64) {
+        let offset = Vector::new(value(17*index) - 0.5, value(17*index + 1) - 0.5, value(17*index + 2) - 0.5);
+        inst.set_transformation(inst.transformation().clone().translate(offset*(2.0*distance)));
+    }
+
+    // The structure has to find exactly the same closest hits and occlusions as testing every instance
+    pub fn assert_matches_brute_force(structure: &dyn AccelerationStructure, instances: Vec<Instance>) {
+        let brute_force = BruteForce::new(instances);
+        for ray in rays() {
+            let expected = brute_force.intersect(&ray).map(|int| int.t());
+            assert_eq!(structure.intersect(&ray).map(|int| int.t()), expected, "closest hit of {:?}", ray);
+            assert_eq!(structure.occluded(&ray), expected.is_some(), "occlusion of {:?}", ray);
+        }
+    }
+
+    #[test]
+    fn intervals_limit_hits_on_scaled_instances() {
+        let sphere = Arc::new(Sphere::new(Box::new(Lambertian::new(Color::gray_scale(1.0)))));
+        let instance = Instance::transformed(sphere, Transformation::new().scale_all(2.0));
+        let ray = Ray::new(Point::new(0.0, 0.0, -10.0), Direction::new(0.0, 0.0, 1.0));
+
+        assert!((instance.intersect(&ray).unwrap().t() - 8.0).abs() < 1e-9);
+        // starting behind the entry point finds the exit
+        assert!((instance.intersect(&ray.with_interval(9.0, 20.0)).unwrap().t() - 12.0).abs() < 1e-9);
+        assert!(instance.intersect(&ray.with_t_max(7.0)).is_none());
+        assert!(instance.occluded(&ray.with_t_max(9.0)));
+        assert!(!instance.occluded(&ray.with_t_max(7.0)));
+        assert!(!instance.occluded(&ray.with_interval(13.0, 20.0)));
+    }
+
+    #[test]
+    fn visibility_ends_at_the_target() {
+        let sphere = Arc::new(Sphere::new(Box::new(Lambertian::new(Color::gray_scale(1.0)))));
+        let brute_force = BruteForce::new(vec![Instance::transformed(sphere, Transformation::new().scale_all(2.0))]);
+
+        assert!(brute_force.visible(Point::new(0.0, 0.0, -10.0), Point::new(0.0, 0.0, -5.0)));
+        assert!(!brute_force.visible(Point::new(0.0, 0.0, -10.0), Point::new(0.0, 0.0, 10.0)));
+        assert!(brute_force.visible(Point::new(0.0, 3.0, -10.0), Point::new(0.0, 3.0, 10.0)));
+    }
+
+    #[test]
+    fn updated_structures_match_brute_force() {
+        let structures: Vec<Box<dyn AccelerationStructure>> = vec![
+            Box::new(BruteForce::new(spheres(200))),
+            Box::new(bvh::BoundingVolumeHierarchy::new(spheres(200))),
+            Box::new(wide_bvh::WideBVH::<4>::new(spheres(200))),
+            Box::new(kdtree::KdTree::new(spheres(200))),
+            Box::new(grid::UniformGrid::new(spheres(200), true))
+        ];
+        let moved_spheres = || -> Vec<Instance> {
+            let mut instances = spheres(200);
+            instances.iter_mut().enumerate().for_each(|(i, inst)| moved(i, inst, 3.0));
+            instances
+        };
+
+        for mut structure in structures {
+            structure.update(&mut |i, inst| moved(i, inst, 3.0));
+            // the instances keep their construction order
+            let expected = moved_spheres();
+            assert!(structure.instances().iter().zip(&expected).all(|(inst, e)| inst.transformation().matrix() == e.transformation().matrix()));
+            assert_matches_brute_force(structure.as_ref(), expected);
+        }
+    }
+}
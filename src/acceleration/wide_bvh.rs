@@ -0,0 +1,299 @@
+
+use crate::objects::Instance;
+use crate::acceleration::AccelerationStructure;
+use crate::acceleration::bvh::{BVHNode, LinearBVH, MAX_TREE_DEPTH};
+use crate::cg_tools::{Ray, BoundingBox, Transformation};
+use crate::scene::Intersection;
+use crate::statistics;
+
+// every level of the tree pushes at most W - 1 more entries than it pops
+const STACK_SIZE: usize = 8*MAX_TREE_DEPTH;
+const EMPTY_LANE: u32 = u32::MAX;
+// relative error of the f32 slab distances, they are widened by it so no box is missed
+const ROUNDING_ERROR: f32 = 4.0*f32::EPSILON;
+
+//////////////////
+//WideNode
+//////////////////
+// Child boxes are stored per coordinate so all lanes can be tested together,
+// a lane is a leaf if its count is non zero and unused lanes hold an empty box.
+#[derive(Copy, Clone, Debug)]
+struct WideNode<const W: usize> {
+    min: [[f32; W]; 3],
+    max: [[f32; W]; 3],
+    child: [u32; W],
+    count: [u32; W]
+}
+
+impl<const W: usize> WideNode<W> {
+    fn empty() -> WideNode<W> {
+        WideNode{min: [[f32::INFINITY; W]; 3], max: [[f32::NEG_INFINITY; W]; 3], child: [EMPTY_LANE; W], count: [0; W]}
+    }
+
+    fn set_bounds(&mut self, lane: usize, bbox: &BoundingBox) {
+        for axis in 0..3 {
+            self.min[axis][lane] = round_down(bbox.min().component(axis));
+            self.max[axis][lane] = round_up(bbox.max().component(axis));
+        }
+    }
+
+    // Entry distance of every lane, infinite for lanes that are missed or unused. The slack of an axis
+    // bounds how far the slabs move along the ray because the origin was rounded to f32.
+    fn hit(&self, origin: &[f32; 3], inv_direction: &[f32; 3], slack: &[f32; 3], t_min: f32, t_max: f32) -> [f32; W] {
+        let mut near = [t_min; W];
+        let mut far = [t_max; W];
+        for axis in 0..3 {
+            for lane in 0..W {
+                let t0 = (self.min[axis][lane] - origin[axis])*inv_direction[axis];
+                let t1 = (self.max[axis][lane] - origin[axis])*inv_direction[axis];
+                let (lo, hi) = (t0.min(t1) - slack[axis], t0.max(t1) + slack[axis]);
+                near[lane] = near[lane].max(lo - lo.abs()*ROUNDING_ERROR);
+                far[lane] = far[lane].min(hi + hi.abs()*ROUNDING_ERROR);
+            }
+        }
+        let mut entry = [f32::INFINITY; W];
+        for lane in 0..W {
+            if self.child[lane] != EMPTY_LANE && near[lane] <= far[lane] { entry[lane] = near[lane]; }
+        }
+        entry
+    }
+}
+
+fn round_down(value: f64) -> f32 {
+    let rounded = value as f32;
+    if rounded as f64 > value { next_f32(rounded, false) } else { rounded }
+}
+
+fn round_up(value: f64) -> f32 {
+    let rounded = value as f32;
+    if (rounded as f64) < value { next_f32(rounded, true) } else { rounded }
+}
+
+fn next_f32(value: f32, up: bool) -> f32 {
+    if !value.is_finite() { return value }
+    if value == 0.0 { return if up { f32::from_bits(1) } else { -f32::from_bits(1) } }
+    let bits = value.to_bits();
+    if (value > 0.0) == up { f32::from_bits(bits + 1) } else { f32::from_bits(bits - 1) }
+}
+
+//////////////////
+//WideBVH
+//////////////////
+// Binary BVH collapsed into nodes with W children, instances are stored in the order of the leaves.
+// Leaves are whole instances, the triangles of a mesh are still searched by the binary BVH of the mesh.
+pub struct WideBVH<const W: usize> {
+    nodes: Vec<WideNode<W>>,
+    instances: Vec<Instance>,
+    order: Vec<usize>
+}
+
+impl<const W: usize> WideBVH<W> {
+    pub fn new(instances: Vec<Instance>) -> WideBVH<W> {
+        let mut bvh = WideBVH{nodes: Vec::new(), instances: Vec::new(), order: Vec::new()};
+        bvh.build(instances);
+        bvh
+    }
+
+    fn build(&mut self, instances: Vec<Instance>) {
+        let bounds: Vec<BoundingBox> = instances.iter().map(|inst| *inst.bounding_box()).collect();
+        let (binary, order) = LinearBVH::build(&bounds);
+
+        self.nodes.clear();
+        match binary.nodes().first() {
+            None => (),
+            Some(root) if root.is_leaf() => {
+                let mut node = WideNode::empty();
+                node.set_bounds(0, root.bbox());
+                node.child[0] = root.primitives().start as u32;
+                node.count[0] = root.primitives().len() as u32;
+                self.nodes.push(node);
+            },
+            Some(_) => { self.collapse(binary.nodes(), 0); }
+        }
+
+        self.instances = super::storage_order(instances, &order);
+        self.order = order;
+    }
+
+    // Pulls up the grandchildren of the largest interior children until the node is full
+    fn collapse(&mut self, binary: &[BVHNode], index: usize) -> u32 {
+        let mut children = vec![index + 1, binary[index].second_child()];
+        while children.len() < W {
+            let largest = children.iter().enumerate()
+                .filter(|(_, child)| !binary[**child].is_leaf())
+                .max_by(|(_, a), (_, b)| {
+                    binary[**a].bbox().surface_area().partial_cmp(&binary[**b].bbox().surface_area()).unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .map(|(lane, _)| lane);
+            match largest {
+                Some(lane) => {
+                    let child = children.swap_remove(lane);
+                    children.push(child + 1);
+                    children.push(binary[child].second_child());
+                },
+                None => break
+            }
+        }
+
+        let wide_index = self.nodes.len();
+        self.nodes.push(WideNode::empty());
+        let mut node = WideNode::empty();
+        for (lane, child) in children.into_iter().enumerate() {
+            let binary_node = &binary[child];
+            node.set_bounds(lane, binary_node.bbox());
+            if binary_node.is_leaf() {
+                node.child[lane] = binary_node.primitives().start as u32;
+                node.count[lane] = binary_node.primitives().len() as u32;
+            }
+            else {
+                node.child[lane] = self.collapse(binary, child);
+            }
+        }
+        self.nodes[wide_index] = node;
+        wide_index as u32
+    }
+
+    // Visits the leaves roughly front to back, the callback returns the new maximum distance and whether to stop
+    fn traverse<F>(&self, ray: &Ray, mut leaf: F) where F: FnMut(std::ops::Range<usize>, f64) -> (f64, bool) {
+        if self.nodes.is_empty() { return }
+        let direction = ray.direction();
+        let origin = [ray.origin().x as f32, ray.origin().y as f32, ray.origin().z as f32];
+        let inv_direction = [(1.0/direction.x) as f32, (1.0/direction.y) as f32, (1.0/direction.z) as f32];
+        let mut slack = [0.0f32; 3];
+        for axis in 0..3 {
+            let error = (ray.origin().component(axis) - origin[axis] as f64).abs();
+            if error > 0.0 { slack[axis] = round_up(error*(1.0/direction.component(axis)).abs()); }
+        }
+        let t_min = round_down(ray.t_min());
+        let mut t_max = ray.t_max();
+
+        // entries are (child, count, entry distance) with a count of zero marking inner nodes
+        let mut stack = [(0u32, 0u32, 0.0f32); STACK_SIZE];
+        let mut stack_size = 1;
+        while stack_size > 0 {
+            stack_size -= 1;
+            let (child, count, entry) = stack[stack_size];
+            if entry as f64 > t_max { continue }
+
+            if count > 0 {
+                let (new_t_max, stop) = leaf(child as usize..(child + count) as usize, t_max);
+                if stop { return }
+                t_max = new_t_max;
+                continue;
+            }
+
+            statistics::node_visit();
+            let node = &self.nodes[child as usize];
+            let entries = node.hit(&origin, &inv_direction, &slack, t_min, round_up(t_max));
+
+            // push the hit lanes far to near so the nearest one is popped first
+            let mut hits = [(0.0f32, 0usize); W];
+            let mut hit_count = 0;
+            for (lane, entry) in entries.iter().enumerate() {
+                if entry.is_finite() {
+                    let mut i = hit_count;
+                    while i > 0 && hits[i - 1].0 < *entry {
+                        hits[i] = hits[i - 1];
+                        i -= 1;
+                    }
+                    hits[i] = (*entry, lane);
+                    hit_count += 1;
+                }
+            }
+            for (entry, lane) in &hits[..hit_count] {
+                stack[stack_size] = (node.child[*lane], node.count[*lane], *entry);
+                stack_size += 1;
+            }
+        }
+    }
+}
+
+impl<const W: usize> AccelerationStructure for WideBVH<W> {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let mut closest: Option<Intersection> = None;
+        self.traverse(ray, |range, t_max| {
+            for i in range {
+                let ray = ray.with_t_max(closest.map_or(t_max, |int| int.t()));
+                if let Some(int) = self.instances[i].intersect(&ray) {
+                    closest = Some(int);
+                }
+            }
+            (closest.map_or(t_max, |int| int.t()), false)
+        });
+        closest
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        let mut hit = false;
+        self.traverse(ray, |mut range, t_max| {
+            hit = range.any(|i| self.instances[i].occluded(ray));
+            (t_max, hit)
+        });
+        hit
+    }
+
+    fn bounding_box(&self, transformation: &Transformation) -> BoundingBox {
+        self.instances.iter()
+            .map(|inst| inst.bounding_box().transformed(transformation))
+            .fold(BoundingBox::empty(), |acc, bbox| acc.union(&bbox))
+    }
+
+    fn update(&mut self, update: &mut dyn FnMut(usize, &mut Instance)) {
+        for (position, inst) in self.instances.iter_mut().enumerate() {
+            update(self.order[position], inst);
+        }
+        // the collapsed nodes are not refitted, the tree is always rebuilt
+        let instances = std::mem::take(&mut self.instances);
+        self.build(super::construction_order(instances, &self.order));
+    }
+
+    fn instances(&self) -> Vec<&Instance> {
+        let mut instances = vec![None; self.instances.len()];
+        self.order.iter().zip(&self.instances).for_each(|(i, inst)| instances[*i] = Some(inst));
+        instances.into_iter().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acceleration::tests::{spheres, assert_matches_brute_force};
+    use crate::math::Point;
+
+    #[test]
+    fn unused_lanes_are_missed() {
+        let mut node = WideNode::<4>::empty();
+        node.set_bounds(0, &BoundingBox::new(Point::new(-1.0, -1.0, -1.0), Point::new(1.0, 1.0, 1.0)));
+        node.child[0] = 0;
+        node.count[0] = 1;
+        let entries = node.hit(&[0.0, 0.0, -5.0], &[f32::INFINITY, f32::INFINITY, 1.0], &[0.0; 3], 0.0, f32::INFINITY);
+        assert_eq!(entries[0], 4.0*(1.0 - ROUNDING_ERROR));
+        assert!(entries[1..].iter().all(|entry| entry.is_infinite()));
+    }
+
+    #[test]
+    fn entry_is_not_behind_the_exact_distance() {
+        // -3.3 has no exact f32 representation, the slack has to cover the rounding of the origin
+        let mut node = WideNode::<4>::empty();
+        node.set_bounds(0, &BoundingBox::new(Point::new(0.0, 0.0, 0.1), Point::new(1.0, 1.0, 1.0)));
+        node.child[0] = 0;
+        node.count[0] = 1;
+        let slack = [0.0, 0.0, round_up((-3.3 - (-3.3f32) as f64).abs())];
+        let entries = node.hit(&[0.5, 0.5, -3.3], &[f32::INFINITY, f32::INFINITY, 1.0], &slack, 0.0, f32::INFINITY);
+        assert!((entries[0] as f64) <= 3.4, "{}", entries[0]);
+    }
+
+    #[test]
+    fn small_trees_do_not_panic() {
+        for count in [1, 2, 3, 5, 7] {
+            assert_matches_brute_force(&WideBVH::<4>::new(spheres(count)), spheres(count));
+            assert_matches_brute_force(&WideBVH::<8>::new(spheres(count)), spheres(count));
+        }
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        assert_matches_brute_force(&WideBVH::<4>::new(spheres(300)), spheres(300));
+        assert_matches_brute_force(&WideBVH::<8>::new(spheres(300)), spheres(300));
+    }
+}
@@ -3,9 +3,11 @@ mod structures;
 mod transformation;
 mod color;
 mod units;
+mod texture;
 
 pub use self::color::{Color,ColorModel,WhiteReference};
 pub use self::sampling::SamplingTechnique;
 pub use self::structures::{BoundingBox,Ray};
 pub use self::transformation::Transformation;
-pub use self::units::Radiance;
\ No newline at end of file
+pub use self::units::Radiance;
+pub use self::texture::Texture;
\ No newline at end of file
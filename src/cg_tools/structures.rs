@@ -3,7 +3,7 @@ extern crate itertools;
 use self::itertools::iproduct;
 
 use super::Transformation;
-use crate::math::{Point, Direction, Normal, EPSILON};
+use crate::math::{Point, Vector, Direction, Normal, EPSILON};
 
 //////////////////
 //Ray
@@ -11,22 +11,41 @@ use crate::math::{Point, Direction, Normal, EPSILON};
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Ray{
     origin:Point,
-    direction:Direction
+    direction:Direction,
+    t_min: f64,
+    t_max: f64
 }
 
 impl Ray {
     pub fn new(origin: Point, direction: Direction) -> Ray{
-        Ray{origin, direction: direction}
+        Ray{origin, direction, t_min: 0.0, t_max: f64::INFINITY}
+    }
+
+    pub fn with_interval(mut self, t_min: f64, t_max: f64) -> Ray{
+        self.t_min = t_min;
+        self.t_max = t_max;
+        self
+    }
+
+    pub fn with_t_max(mut self, t_max: f64) -> Ray{
+        self.t_max = t_max;
+        self
     }
 
     pub fn origin(&self) -> Point { self.origin }
     pub fn direction(&self) -> Direction { self.direction }
+    pub fn t_min(&self) -> f64 { self.t_min }
+    pub fn t_max(&self) -> f64 { self.t_max }
+
+    pub fn in_interval(&self, t: f64) -> bool {
+        t > self.t_min && t < self.t_max
+    }
 }
 
 //////////////////
 //BoundingBox
 //////////////////
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub struct BoundingBox {
     min: Point,
     max: Point
@@ -43,9 +62,31 @@ impl BoundingBox {
         BoundingBox{min, max}
     }
 
+    pub fn empty() -> BoundingBox{
+        BoundingBox{min: Point::max_point(), max: Point::min_point()}
+    }
+
     pub fn min(&self) -> Point { self.min }
     pub fn max(&self) -> Point { self.max }
 
+    pub fn centroid(&self) -> Point {
+        Point::from((*self.min + *self.max)*0.5)
+    }
+
+    pub fn extent(&self) -> Vector {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let extent = self.extent();
+        if extent.x < 0.0 || extent.y < 0.0 || extent.z < 0.0 { return 0.0 }
+        2.0*(extent.x*extent.y + extent.y*extent.z + extent.z*extent.x)
+    }
+
+    pub fn include(&self, point: Point) -> BoundingBox {
+        BoundingBox{min: self.min.min(point), max: self.max.max(point)}
+    }
+
     pub fn union(&self, bounds: &BoundingBox) -> BoundingBox {
         let min = bounds.min.min(self.min);
         let max = bounds.max.max(self.max);
@@ -70,6 +111,29 @@ impl BoundingBox {
         BoundingBox::new(min, max)
     }
 
+    pub fn intersection(&self, bounds: &BoundingBox) -> BoundingBox {
+        BoundingBox{min: self.min.max(bounds.min), max: self.max.min(bounds.max)}
+    }
+
+    pub fn hit(&self, origin: Point, inv_direction: Vector, t_min: f64, t_max: f64) -> bool {
+        self.interval(origin, inv_direction, t_min, t_max).is_some()
+    }
+
+    pub fn interval(&self, origin: Point, inv_direction: Vector, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        let (mut t_near, mut t_far) = (t_min, t_max);
+        for axis in 0..3 {
+            let o = origin.component(axis);
+            let inv = inv_direction.component(axis);
+            let mut t0 = (self.min.component(axis) - o)*inv;
+            let mut t1 = (self.max.component(axis) - o)*inv;
+            if t0 > t1 { std::mem::swap(&mut t0, &mut t1) }
+            t_near = t_near.max(t0);
+            t_far = t_far.min(t1);
+            if t_near > t_far { return None }
+        }
+        Some((t_near, t_far))
+    }
+
     pub fn intersect(&self, ray: &Ray) -> Option<(f64, Point, Normal)> {
         let (origin, direction) = (ray.origin(), ray.direction());
         if self.contains(&origin) {
@@ -102,7 +166,7 @@ impl BoundingBox {
         //if tzmax < tmax { tmax = tzmax; }
 
         let t = tmin;
-        if t <= 0.0 { return None }
+        if !ray.in_interval(t) { return None }
         let point = origin + t**direction;
         let normal = match (t, point) {
             (t,p) if t == txmin && p.x.abs() < EPSILON => Normal::new(-1.0, 0.0, 0.0),
@@ -0,0 +1,77 @@
+use image::DynamicImage;
+
+use super::Color;
+use crate::settings;
+
+//////////////////
+//Texture
+//////////////////
+// Linear RGB texels decoded from PNG, JPEG or BMP images
+pub struct Texture {
+    width: usize,
+    height: usize,
+    texels: Vec<(f32,f32,f32)>
+}
+
+impl Texture {
+    // Images in files hold gamma encoded colors
+    pub fn load(file_path: &str) -> Result<Texture, String> {
+        let image = image::open(file_path).map_err(|err| format!("{}: {}", file_path, err))?;
+        Ok(Texture::from_image(image, true))
+    }
+
+    // An encoded image in memory, its format is guessed from the bytes. Images holding data instead of colors,
+    // like normal maps, are not gamma encoded.
+    pub fn from_memory(bytes: &[u8], gamma_encoded: bool) -> Result<Texture, String> {
+        let image = image::load_from_memory(bytes).map_err(|err| err.to_string())?;
+        Ok(Texture::from_image(image, gamma_encoded))
+    }
+
+    fn from_image(image: DynamicImage, gamma_encoded: bool) -> Texture {
+        let image = image.into_rgb8();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let gamma = if gamma_encoded { settings::get().gamma as f32 } else { 1.0 };
+        let decode = |value: u8| (value as f32 / 255.0).powf(gamma);
+        let texels = image.pixels().map(|pixel| (decode(pixel[0]), decode(pixel[1]), decode(pixel[2]))).collect();
+        Texture{width, height, texels}
+    }
+
+    fn texel(&self, x: isize, y: isize) -> (f32,f32,f32) {
+        let x = x.rem_euclid(self.width as isize) as usize;
+        let y = y.rem_euclid(self.height as isize) as usize;
+        self.texels[x + y*self.width]
+    }
+
+    // Bilinear lookup with repeating coordinates, v points up as in OBJ files
+    pub fn sample(&self, (u, v): (f64, f64)) -> Color {
+        let x = u*self.width as f64 - 0.5;
+        let y = (1.0 - v)*self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = ((x - x0) as f32, (y - y0) as f32);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        let lerp = |a: (f32,f32,f32), b: (f32,f32,f32), f: f32| (a.0 + (b.0 - a.0)*f, a.1 + (b.1 - a.1)*f, a.2 + (b.2 - a.2)*f);
+        let top = lerp(self.texel(x0, y0), self.texel(x0 + 1, y0), fx);
+        let bottom = lerp(self.texel(x0, y0 + 1), self.texel(x0 + 1, y0 + 1), fx);
+        let (r, g, b) = lerp(top, bottom, fy);
+        Color::new_rgb(r as f64, g as f64, b as f64)
+    }
+
+    pub fn height(&self, uv: (f64, f64)) -> f64 {
+        let (r, g, b) = self.sample(uv).rgb();
+        (r + g + b) / 3.0
+    }
+
+    // Change of the height per unit of u and v, estimated over one texel
+    pub fn gradient(&self, (u, v): (f64, f64)) -> (f64, f64) {
+        let (du, dv) = (1.0/self.width as f64, 1.0/self.height as f64);
+        let height = self.height((u, v));
+        ((self.height((u + du, v)) - height)/du, (self.height((u, v + dv)) - height)/dv)
+    }
+}
+
+impl std::fmt::Debug for Texture {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Texture({}x{})", self.width, self.height)
+    }
+}
@@ -13,6 +13,11 @@ impl Transformation {
         Transformation{matrix: mat.clone(), inverted: mat}
     }
 
+    // None if the matrix can not be inverted
+    pub fn from_matrix(matrix: Matrix) -> Option<Transformation> {
+        matrix.inverted_affine().map(|inverted| Transformation{matrix, inverted})
+    }
+
     pub fn matrix(&self) -> Matrix{ self.matrix }
     pub fn inverted(&self) -> Matrix{ self.inverted }
 
@@ -37,4 +42,35 @@ impl Transformation {
         self.inverted = self.inverted * Matrix::rotated(axis,-radians);
         self
     }
+
+    // Rotation given by the unit quaternion x*i + y*j + z*k + w
+    pub fn rotate_quaternion(mut self, x: f64, y: f64, z: f64, w: f64) -> Transformation {
+        let rotation = Matrix::from([
+            [1.0 - 2.0*(y*y + z*z), 2.0*(x*y - z*w), 2.0*(x*z + y*w), 0.0],
+            [2.0*(x*y + z*w), 1.0 - 2.0*(x*x + z*z), 2.0*(y*z - x*w), 0.0],
+            [2.0*(x*z - y*w), 2.0*(y*z + x*w), 1.0 - 2.0*(x*x + y*y), 0.0],
+            [0.0, 0.0, 0.0, 1.0]
+        ]);
+        self.matrix = rotation * self.matrix;
+        self.inverted = self.inverted * rotation.transpose();
+        self
+    }
+
+    // Rotation by the given angle around an axis through the origin
+    pub fn rotate_axis(self, axis: Vector, radians: f64) -> Transformation {
+        let axis = axis.normalize();
+        let sin = (radians/2.0).sin();
+        self.rotate_quaternion(axis.x*sin, axis.y*sin, axis.z*sin, (radians/2.0).cos())
+    }
+
+    pub fn inverse(self) -> Transformation {
+        Transformation{matrix: self.inverted, inverted: self.matrix}
+    }
+
+    // Applies the given transformation after this one
+    pub fn then(mut self, transformation: &Transformation) -> Transformation {
+        self.matrix = transformation.matrix * self.matrix;
+        self.inverted = self.inverted * transformation.inverted;
+        self
+    }
 }
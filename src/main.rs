@@ -5,19 +5,22 @@ mod math;
 mod cg_tools;
 mod camera;
 mod objects;
+mod pbrt_import;
 mod scene;
+mod scene_file;
 mod settings;
 mod statistics;
 mod thread_pool;
 
 use std::f64::consts::{PI,FRAC_PI_2,FRAC_PI_4};
+use std::path::Path;
 use std::sync::Arc;
 
 use objects::*;
 use camera::{PerspectiveCamera};
 use scene::{Scene};
 use math::{Point, Vector, Normal, Direction, RotationAxis};
-use cg_tools::{Transformation,Color,WhiteReference};
+use cg_tools::{Transformation,Color,WhiteReference,BoundingBox};
 
 
 fn main() {
@@ -33,9 +36,75 @@ fn main() {
     };
 
     settings::set(settings);
-    let scene = default_scene();
+    // a scene file given on the command line replaces the default scene, --frames renders a turntable animation
+    // and --write converts the scene to the native scene description instead of rendering it
+    let mut scene_path = None;
+    let mut frames = None;
+    let mut output_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => frames = Some(args.next().and_then(|n| n.parse::<usize>().ok()).expect("--frames needs a number of frames")),
+            "--write" => output_path = Some(args.next().expect("--write needs a file path")),
+            _ => scene_path = Some(arg)
+        }
+    }
+    let scene = match scene_path {
+        Some(file_path) => load_scene(&file_path).unwrap_or_else(|err| panic!("Could not read scene: {}", err)),
+        None => default_scene()
+    };
+
+    if let Some(output_path) = output_path {
+        scene_file::write_scene(&output_path, &scene).unwrap_or_else(|err| panic!("Could not write scene: {}", err));
+        return;
+    }
+
+    match frames {
+        Some(frames) => {
+            let animate = turntable(&scene, frames);
+            renderer::render_animation(scene, frames, animate);
+        },
+        None => renderer::render(scene)
+    }
+}
+
+// The format is chosen by the extension of the file, anything else is read as native scene description
+fn load_scene(file_path: &str) -> Result<Scene, ImportError> {
+    match Path::new(file_path).extension().and_then(|extension| extension.to_str()).map(str::to_lowercase).as_deref() {
+        Some("gltf") | Some("glb") => {
+            let GltfScene{instances, lights, cameras} = parse_gltf(file_path)?;
+            // the first camera of the file is used, without one the scene is seen from the default camera
+            let camera = cameras.into_iter().next().unwrap_or_else(default_camera);
+            Ok(Scene::new(instances, lights, camera))
+        },
+        // the film and sampler of the file replace the resolution and samples per pixel
+        Some("pbrt") => {
+            let (scene, settings) = pbrt_import::parse_pbrt(file_path)?;
+            settings::set(settings);
+            Ok(scene)
+        },
+        _ => scene_file::parse_scene(file_path)
+    }
+}
 
-    renderer::render(scene);
+// Turns every instance once around the vertical axis through the center of the bounded instances
+fn turntable(scene: &Scene, frames: usize) -> impl FnMut(usize, usize, &mut Instance) {
+    let instances = scene.instances();
+    let transformations: Vec<Transformation> = instances.iter().map(|inst| inst.transformation().clone()).collect();
+    let bounded: Vec<BoundingBox> = instances.iter().map(|inst| *inst.bounding_box())
+        .filter(|bbox| bbox.surface_area().is_finite() && bbox.extent().x >= 0.0).collect();
+    let center = match bounded.is_empty() {
+        true => Point::origin(),
+        false => bounded.iter().fold(BoundingBox::empty(), |acc, bbox| acc.union(bbox)).centroid()
+    };
+    let offset = center - Point::origin();
+    move |frame, index, inst| {
+        let angle = 2.0*PI*frame as f64 / frames as f64;
+        inst.set_transformation(transformations[index].clone()
+            .translate(offset.invert())
+            .rotate(RotationAxis::Yaxis, angle)
+            .translate(offset));
+    }
 }
 
 fn default_camera() -> PerspectiveCamera
@@ -73,12 +142,38 @@ fn default_scene() -> Scene {
     let box_object = Arc::new( BoxObject::new_from_origin(Point::new(1.,1.,1.),Box::new(Lambertian::new(Color::gray_scale(1.))) ) );
     //instances.push(Instance::transformed(box_object,Transformation::new()
     //    .translate(Vector::new(-4.,2.,4.))));
+    //let bubble = Arc::new( Sphere::new(Box::new(ThinDielectric::with_film(ThinFilm::new(380.,1.33), Color::gray_scale(1.))) ) );
+    //instances.push(Instance::transformed(bubble,Transformation::new()
+    //    .scale_all(0.5)
+    //    .translate(Vector::new(-1.,0.,2.))));
+    //let window = Arc::new( Rectangle::unit_square(true,Box::new(ThinDielectric::new(1.5, Color::gray_scale(1.))) ) );
+    //instances.push(Instance::transformed(window,Transformation::new()
+    //    .scale_all(2.)
+    //    .rotate(RotationAxis::Xaxis, -FRAC_PI_2)
+    //    .translate(Vector::new(-1.,-1.,1.))));
 
     let transformation = Transformation::new().translate(Vector::new(-2.0,0.0,7.0));
     let chair_mesh = Arc::new( parse_obj("obj\\chair\\chair.obj").expect("Could not read obj") );
     let diamond_mesh = Arc::new( parse_obj("obj\\diamond.obj").expect("Could not read obj") );
     instances.push(Instance::transformed(chair_mesh, transformation.clone()));
     instances.push(Instance::transformed(diamond_mesh, transformation));
+    //let mut teddy_mesh = parse_obj("obj\\teddy.obj").expect("Could not read obj");
+    //teddy_mesh.set_material(Box::new(Subsurface::new(Color::new_rgb(0.9,0.6,0.4), 0.05, 1.3)));
+    //instances.push(Instance::transformed(Arc::new(teddy_mesh), Transformation::new()
+    //    .scale_all(0.05)
+    //    .translate(Vector::new(1.,0.,3.))));
+
+    //let teapot_mesh = Arc::new( parse_obj("obj\\teapot.obj").expect("Could not read obj") );
+    //for i in 0..1000 {
+    //    instances.push(Instance::transformed(teapot_mesh.clone(), Transformation::new()
+    //        .scale_all(0.1)
+    //        .rotate(RotationAxis::Yaxis, i as f64)
+    //        .translate(Vector::new((i % 40) as f64*0.5 - 10., -1., (i / 40) as f64*0.5 + 4.))));
+    //}
+
+    //let smoke_transformation = Transformation::new().scale_all(2.).translate(Vector::new(-1.,-1.,3.));
+    //let smoke = GridMedium::load("vol\\smoke.raw", smoke_transformation.clone(), 8., Color::gray_scale(0.9), 0.2).expect("Could not read grid");
+    //instances.push(Instance::transformed(Arc::new(Volume::new()), smoke_transformation).with_medium(Box::new(smoke)));
 
     let mut lights: Vec<Box<dyn Light>> = Vec::new();
     let position = math::Point::new(-2.,2.,0.);
@@ -90,6 +185,9 @@ fn default_scene() -> Scene {
     lights.push( Box::new(SurfaceLight::transformed(surface, transformation,1000., Color::white(WhiteReference::E))) );
     lights.push( Box::new(PointLight::new(position,600., Color::white(WhiteReference::E))) );
     //lights.push( Box::new(PointLight::new(position2,2000., Color::gray_scale(1.))) );
+    //lights.push( Box::new(SpotLight::new(Point::new(0.,4.,3.), Direction::new(0.,-1.,0.), FRAC_PI_4/2., FRAC_PI_4, 800., Color::white(WhiteReference::E))) );
 
+    //let fog = Box::new( HomogeneousMedium::new(Color::gray_scale(0.01), Color::gray_scale(0.05), 0.3) );
+    //return Scene::new(instances, lights, default_camera()).with_medium(fog);
     Scene::new(instances, lights, default_camera())
 }
\ No newline at end of file
@@ -19,6 +19,13 @@ impl BaseVector {
     pub fn from_value(value: f64) -> BaseVector { BaseVector{x: value, y: value, z: value} }
 
     pub fn as_tuple(&self) -> (f64,f64,f64) { (self.x,self.y,self.z) }
+    pub fn component(&self, axis: usize) -> f64 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z
+        }
+    }
     pub fn sum(&self) -> f64 {
         self.x + self.y + self.z
     }
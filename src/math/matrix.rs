@@ -84,6 +84,29 @@ impl Matrix {
         swap(&mut self.base, 3, 2);
         self
     }
+
+    // Inverse of a matrix whose last row is 0 0 0 1, None if it is singular
+    pub fn inverted_affine(&self) -> Option<Matrix> {
+        let m = &self.base;
+        let cofactor = |i: usize, j: usize| {
+            let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+            let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+            m[r0][c0]*m[r1][c1] - m[r0][c1]*m[r1][c0]
+        };
+        let det = m[0][0]*cofactor(0, 0) + m[0][1]*cofactor(0, 1) + m[0][2]*cofactor(0, 2);
+        if det.abs() < 1e-300 { return None }
+
+        let mut inverted = Self::identiy();
+        for i in 0..3 {
+            for j in 0..3 {
+                inverted.base[i][j] = cofactor(j, i) / det;
+            }
+        }
+        for i in 0..3 {
+            inverted.base[i][3] = -(inverted.base[i][0]*m[0][3] + inverted.base[i][1]*m[1][3] + inverted.base[i][2]*m[2][3]);
+        }
+        Some(inverted)
+    }
 }
 
 impl From<[[f64; 3]; 3]> for Matrix {
@@ -98,6 +121,12 @@ impl From<[[f64; 3]; 3]> for Matrix {
     }
 }
 
+impl From<[[f64; 4]; 4]> for Matrix {
+    fn from(base: [[f64; 4]; 4]) -> Self {
+        Matrix{ base }
+    }
+}
+
 impl fmt::Debug for Matrix {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "\n[{}, {}, {}, {}\n {}, {}, {}, {}\n {}, {}, {}, {}\n {}, {}, {}, {}]", self.base[0][0], self.base[0][1], self.base[0][2], self.base[0][3], self.base[1][0], self.base[1][1], self.base[1][2], self.base[1][3], self.base[2][0], self.base[2][1], self.base[2][2], self.base[2][3], self.base[3][0], self.base[3][1], self.base[3][2], self.base[3][3])
@@ -237,6 +237,13 @@ impl Normal {
     pub fn cross(&self, other: &Vector) -> Normal {
         Normal::from(self.0.cross(other))
     }
+
+    pub fn orthonormal_basis(&self) -> (Direction, Direction) {
+        let helper = if self.x.abs() > 0.9 { Vector::new(0.0, 1.0, 0.0) } else { Vector::new(1.0, 0.0, 0.0) };
+        let tangent = Direction::from(self.0.cross(&helper));
+        let bitangent = Direction::from(self.0.cross(&tangent));
+        (tangent, bitangent)
+    }
 }
 
 impl From<Vector> for Normal {
@@ -1,73 +1,77 @@
 
-use std::sync::Arc;
-
-use super::{Object, Material, Plane};
+use super::{Object, ObjectDescription, Material, Plane};
 use crate::cg_tools::{Transformation, BoundingBox, Ray};
 use crate::math::{Point, Normal, EPSILON};
 use crate::scene::Intersection;
 use crate::statistics;
 
-pub trait Face : Object {
-    fn as_object(self: Box<Self>) -> Arc<dyn Object>;
-    fn double_sided(&self) -> bool;
-}
-
 //////////////////
 //Triangle
 //////////////////
 #[derive(Debug)]
 pub struct Triangle {
     vertices : [Point; 3],
+    normals: Option<[Normal; 3]>,
     double_sided: bool,
     material : Box<dyn Material>
 }
 
 impl Triangle{
     pub fn new(vertices : [Point; 3], double_sided: bool, material: Box<dyn Material>) -> Triangle{
-        Triangle{vertices, double_sided, material}
+        Triangle{vertices, normals: None, double_sided, material}
+    }
+
+    // Vertex normals interpolated over the face for smooth shading
+    pub fn with_normals(mut self, normals: [Normal; 3]) -> Triangle{
+        self.normals = Some(normals);
+        self
     }
 
     fn moller_trumbore(&self, ray: &Ray) -> Option<Intersection> {
-        let (origin, direction) = (ray.origin(), ray.direction());
-        let edge1 = self.vertices[1] - self.vertices[0];
-        let edge2 = self.vertices[2] - self.vertices[0];
-        let h = (*direction).cross(&edge2);
-        let det = edge1.dot(&h);
-        if (!self.double_sided || det > -EPSILON) && det < EPSILON {
-            return None;
-        }
-        let inv_det = 1.0 / det;
-        let s = origin - self.vertices[0];
-        let u = inv_det * (s.dot(&h));
-        if u < 0.0 || u > 1.0 {
-            return None;
-        }
-        let q = s.cross(&edge1);
-        let v = inv_det * direction.dot(&q);
-        if v < 0.0 || u + v > 1.0 {
-            return None;
-        }
-        let t = inv_det * edge2.dot(&q);
-        if t > 0.0 {
-            let point = origin + t * *direction;
-            let mut normal = Normal::from(edge1.cross(&edge2));
-            if det < 0. {
-                normal = normal.invert();
-            }
-            let int = Intersection::new(t, point, normal, self.material());
-            Some(int)
-        } else {
-            return None;
-        }
+        let (t, u, v, det) = intersect_triangle(&self.vertices, self.double_sided, ray)?;
+        let point = ray.origin() + t * *ray.direction();
+        let normal = triangle_normal(&self.vertices, self.normals.as_ref(), u, v, det);
+        Some(Intersection::new(t, point, normal, self.material()))
     }
 }
 
-impl Face for Triangle {
-    fn as_object(self: Box<Self>) -> Arc<dyn Object> { Arc::new(*self) }
+// Moller-Trumbore, returns the distance, the barycentric coordinates of the second and third vertex and the determinant
+pub fn intersect_triangle(vertices: &[Point; 3], double_sided: bool, ray: &Ray) -> Option<(f64, f64, f64, f64)> {
+    let (origin, direction) = (ray.origin(), ray.direction());
+    let edge1 = vertices[1] - vertices[0];
+    let edge2 = vertices[2] - vertices[0];
+    let h = (*direction).cross(&edge2);
+    let det = edge1.dot(&h);
+    if (!double_sided || det > -EPSILON) && det < EPSILON {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = origin - vertices[0];
+    let u = inv_det * (s.dot(&h));
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+    let q = s.cross(&edge1);
+    let v = inv_det * direction.dot(&q);
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = inv_det * edge2.dot(&q);
+    if ray.in_interval(t) { Some((t, u, v, det)) } else { None }
+}
 
-    fn double_sided(&self) -> bool {
-        self.double_sided
+// Normal facing the side the ray came from, interpolated from the vertex normals if there are any
+pub fn triangle_normal(vertices: &[Point; 3], normals: Option<&[Normal; 3]>, u: f64, v: f64, det: f64) -> Normal {
+    let mut normal = Normal::from((vertices[1] - vertices[0]).cross(&(vertices[2] - vertices[0])));
+    if det < 0. {
+        normal = normal.invert();
     }
+    if let Some(normals) = normals {
+        // the shading normal stays on the side of the face the ray hit
+        let shading = Normal::from((1.0 - u - v)**normals[0] + u**normals[1] + v**normals[2]);
+        normal = if shading.dot(&normal) < 0.0 { shading.invert() } else { shading };
+    }
+    normal
 }
 
 impl Object for Triangle{
@@ -90,6 +94,10 @@ impl Object for Triangle{
     }
 
     fn material(&self) -> &dyn Material { self.material.as_ref() }
+
+    fn describe(&self) -> Option<ObjectDescription<'_>> {
+        Some(ObjectDescription::Triangle{vertices: self.vertices, normals: self.normals, double_sided: self.double_sided})
+    }
 }
 
 //////////////////
@@ -121,14 +129,6 @@ impl Rectangle{
     pub fn points(&self) -> [Point; 4] { self.points }
 }
 
-impl Face for Rectangle {
-    fn as_object(self: Box<Self>) -> Arc<dyn Object> { Arc::new(*self) }
-
-    fn double_sided(&self) -> bool {
-        self.plane.double_sided()
-    }
-}
-
 impl Object for Rectangle {
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         if let Some(intersect) = self.plane.intersect(ray){
@@ -154,4 +154,8 @@ impl Object for Rectangle {
     }
 
     fn material(&self) -> &dyn Material { self.plane.material() }
+
+    fn describe(&self) -> Option<ObjectDescription<'_>> {
+        Some(ObjectDescription::Rectangle{points: self.points, double_sided: self.plane.double_sided()})
+    }
 }
\ No newline at end of file
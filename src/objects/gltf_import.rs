@@ -0,0 +1,635 @@
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::{Mesh, IndexedTriangle, Instance, Light, PointLight, SpotLight, Material, Lambertian, MetallicRoughness, ImportError};
+use super::json::Json;
+use super::obj_import::complete_normals;
+use crate::camera::PerspectiveCamera;
+use crate::cg_tools::{Color, Texture, Transformation};
+use crate::math::{Point, Vector, Direction, Normal, Matrix};
+use crate::settings;
+
+const GLB_MAGIC: &[u8; 4] = b"glTF";
+const CHUNK_JSON: u32 = 0x4E4F534A;
+const CHUNK_BIN: u32 = 0x004E4942;
+
+//////////////////
+//GltfScene
+//////////////////
+// Content of the default scene of a glTF file, placed by the transformations of the node hierarchy
+pub struct GltfScene {
+    pub instances: Vec<Instance>,
+    pub lights: Vec<Box<dyn Light>>,
+    pub cameras: Vec<PerspectiveCamera>
+}
+
+// Reads .gltf files with external or embedded buffers as well as binary .glb files
+pub fn parse_gltf(file_path: &str) -> Result<GltfScene, ImportError> {
+    let bytes = fs::read(file_path)?;
+    let (json, binary) = if bytes.starts_with(GLB_MAGIC) { read_glb(file_path, &bytes)? } else { (&bytes[..], None) };
+    let text = std::str::from_utf8(json).map_err(|_| ImportError::invalid(file_path, "JSON is not valid UTF-8".to_string()))?;
+    let document = Json::parse(text).map_err(|(line, message)| ImportError::parse(file_path, line, message))?;
+
+    let directory = Path::new(file_path).parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut gltf = Gltf{file_path, directory, document, buffers: vec![], meshes: HashMap::new(), textures: HashMap::new()};
+    gltf.load_buffers(binary)?;
+    let scene = gltf.load_scene()?;
+
+    println!("Imported scene: {}", file_path);
+    println!("Amount of instances: {}", scene.instances.len());
+    Ok(scene)
+}
+
+// Header followed by chunks, the first one holds the JSON and the optional second one the binary buffer
+fn read_glb<'a>(file_path: &str, bytes: &'a [u8]) -> Result<(&'a [u8], Option<&'a [u8]>), ImportError> {
+    let u32_at = |offset: usize| bytes.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    if u32_at(4) != Some(2) {
+        return Err(ImportError::invalid(file_path, "Only version 2 of binary glTF is supported".to_string()));
+    }
+    let (mut json, mut binary) = (None, None);
+    let mut offset = 12;
+    while let (Some(length), Some(kind)) = (u32_at(offset), u32_at(offset + 4)) {
+        let start = offset + 8;
+        let data = bytes.get(start..start + length as usize)
+            .ok_or_else(|| ImportError::invalid(file_path, "Chunk exceeds the end of the file".to_string()))?;
+        match kind {
+            CHUNK_JSON => json = json.or(Some(data)),
+            CHUNK_BIN => binary = binary.or(Some(data)),
+            _ => ()
+        }
+        offset = start + length as usize;
+    }
+    let json = json.ok_or_else(|| ImportError::invalid(file_path, "Binary glTF without JSON chunk".to_string()))?;
+    Ok((json, binary))
+}
+
+struct Gltf<'a> {
+    file_path: &'a str,
+    directory: PathBuf,
+    document: Json,
+    buffers: Vec<Vec<u8>>,
+    meshes: HashMap<usize, Option<Arc<Mesh>>>,
+    // decoded per texture and whether its colors are gamma encoded
+    textures: HashMap<(usize, bool), Option<Arc<Texture>>>
+}
+
+impl<'a> Gltf<'a> {
+    fn invalid(&self, message: String) -> ImportError {
+        ImportError::invalid(self.file_path, message)
+    }
+
+    fn array(&self, name: &str, index: usize) -> Result<&Json, ImportError> {
+        self.document.get(name).and_then(|items| items.at(index)).ok_or_else(|| self.invalid(format!("Missing {} {}", name, index)))
+    }
+
+    // Buffers are data URIs, files next to the glTF file or the binary chunk of a .glb file
+    fn load_buffers(&mut self, binary: Option<&[u8]>) -> Result<(), ImportError> {
+        let descriptions = self.document.get("buffers").map_or(vec![], |b| b.items().to_vec());
+        for (i, buffer) in descriptions.iter().enumerate() {
+            let data = match buffer.get("uri").and_then(Json::as_str) {
+                Some(uri) if uri.starts_with("data:") => {
+                    let encoded = uri.split_once(";base64,").map(|(_, data)| data)
+                        .ok_or_else(|| self.invalid(format!("Buffer {} is not base64 encoded", i)))?;
+                    decode_base64(encoded).ok_or_else(|| self.invalid(format!("Invalid base64 data in buffer {}", i)))?
+                },
+                Some(uri) => fs::read(self.directory.join(decode_uri(uri)))?,
+                None if i == 0 => binary.ok_or_else(|| self.invalid("Buffer without uri outside of a .glb file".to_string()))?.to_vec(),
+                None => return Err(self.invalid(format!("Buffer {} without uri", i)))
+            };
+            let length = buffer.get("byteLength").and_then(Json::as_usize).unwrap_or(0);
+            if data.len() < length {
+                return Err(self.invalid(format!("Buffer {} is shorter than its byteLength", i)));
+            }
+            self.buffers.push(data);
+        }
+        Ok(())
+    }
+
+    fn load_scene(&mut self) -> Result<GltfScene, ImportError> {
+        let nodes = self.document.get("nodes").map_or(vec![], |n| n.items().to_vec());
+        let children = |node: &Json| -> Vec<usize> { node.get("children").map_or(vec![], |c| c.items().iter().filter_map(Json::as_usize).collect()) };
+        let scene_index = self.document.get("scene").and_then(Json::as_usize).unwrap_or(0);
+        let roots: Vec<usize> = match self.document.get("scenes").and_then(|s| s.at(scene_index)) {
+            Some(scene) => scene.get("nodes").map_or(vec![], |n| n.items().iter().filter_map(Json::as_usize).collect()),
+            // without scenes every node which is no child is a root
+            None => {
+                let all_children: HashSet<usize> = nodes.iter().flat_map(children).collect();
+                (0..nodes.len()).filter(|i| !all_children.contains(i)).collect()
+            }
+        };
+
+        let mut scene = GltfScene{instances: vec![], lights: vec![], cameras: vec![]};
+        let mut stack: Vec<(usize, Transformation, usize)> = roots.into_iter().rev().map(|root| (root, Transformation::new(), 0)).collect();
+        while let Some((index, parent, depth)) = stack.pop() {
+            if depth > nodes.len() {
+                return Err(self.invalid("Node hierarchy contains a cycle".to_string()));
+            }
+            let node = nodes.get(index).ok_or_else(|| self.invalid(format!("Missing nodes {}", index)))?;
+            let transformation = self.node_transformation(node)?.then(&parent);
+
+            if let Some(mesh) = node.get("mesh").and_then(Json::as_usize) {
+                if let Some(mesh) = self.mesh(mesh)? {
+                    scene.instances.push(Instance::transformed(mesh, transformation.clone()));
+                }
+            }
+            if let Some(camera) = node.get("camera").and_then(Json::as_usize) {
+                scene.cameras.extend(self.camera(camera, &transformation)?);
+            }
+            let light = node.get("extensions").and_then(|e| e.get("KHR_lights_punctual")).and_then(|l| l.get("light")).and_then(Json::as_usize);
+            if let Some(light) = light {
+                scene.lights.extend(self.light(light, &transformation)?);
+            }
+            stack.extend(children(node).into_iter().rev().map(|child| (child, transformation.clone(), depth + 1)));
+        }
+        Ok(scene)
+    }
+
+    // Either a column major matrix or translation, rotation and scale applied as T*R*S
+    fn node_transformation(&self, node: &Json) -> Result<Transformation, ImportError> {
+        if let Some(m) = node.get("matrix").and_then(Json::numbers).filter(|m| m.len() == 16) {
+            let matrix = Matrix::from([
+                [m[0], m[4], m[8], m[12]],
+                [m[1], m[5], m[9], m[13]],
+                [m[2], m[6], m[10], m[14]],
+                [0.0, 0.0, 0.0, 1.0]
+            ]);
+            return Transformation::from_matrix(matrix).ok_or_else(|| self.invalid("Node matrix can not be inverted".to_string()));
+        }
+        let vector = |name: &str, default: Vec<f64>| node.get(name).and_then(Json::numbers).filter(|v| v.len() == default.len()).unwrap_or(default);
+        let (s, r, t) = (vector("scale", vec![1.0; 3]), vector("rotation", vec![0.0, 0.0, 0.0, 1.0]), vector("translation", vec![0.0; 3]));
+        let length = r.iter().map(|c| c*c).sum::<f64>().sqrt().max(f64::MIN_POSITIVE);
+        Ok(Transformation::new()
+            .scale(s[0], s[1], s[2])
+            .rotate_quaternion(r[0]/length, r[1]/length, r[2]/length, r[3]/length)
+            .translate(Vector::new(t[0], t[1], t[2])))
+    }
+
+    // One mesh per glTF mesh with a material per primitive, None if no primitive consists of triangles
+    fn mesh(&mut self, index: usize) -> Result<Option<Arc<Mesh>>, ImportError> {
+        if let Some(mesh) = self.meshes.get(&index) { return Ok(mesh.clone()) }
+        let description = self.array("meshes", index)?.clone();
+
+        let mut vertices: Vec<Point> = vec![];
+        let mut normals: Vec<Normal> = vec![];
+        let mut uvs: Vec<(f64, f64)> = vec![];
+        let mut colors: Vec<Color> = vec![];
+        let mut has_colors = false;
+        let mut triangles: Vec<[usize; 3]> = vec![];
+        let mut triangle_normals: Vec<Option<[usize; 3]>> = vec![];
+        let mut triangle_uvs: Vec<Option<[u32; 3]>> = vec![];
+        let mut triangle_materials: Vec<u32> = vec![];
+        let mut materials: Vec<Box<dyn Material>> = vec![];
+
+        for primitive in description.get("primitives").map_or(&[][..], Json::items) {
+            let mode = primitive.get("mode").and_then(Json::as_usize).unwrap_or(4);
+            if !(4..=6).contains(&mode) {
+                println!("Skipped primitive of mesh {}, mode {} is not supported", index, mode);
+                continue;
+            }
+            let attribute = |name: &str| primitive.get("attributes").and_then(|a| a.get(name)).and_then(Json::as_usize);
+            let position = attribute("POSITION").ok_or_else(|| self.invalid(format!("Primitive of mesh {} without POSITION", index)))?;
+            let positions = self.accessor(position, &[3])?;
+            let (base, count) = (vertices.len(), positions.len()/3);
+            vertices.extend(positions.chunks(3).map(|p| Point::new(p[0], p[1], p[2])));
+
+            let normal_base = match attribute("NORMAL") {
+                Some(accessor) => {
+                    let values = self.accessor(accessor, &[3])?;
+                    if values.len() != positions.len() { return Err(self.invalid(format!("NORMAL of mesh {} does not match POSITION", index))) }
+                    normals.extend(values.chunks(3).map(|n| Normal::new(n[0], n[1], n[2])));
+                    Some(normals.len() - count)
+                },
+                None => None
+            };
+            // texture coordinates start at the top left corner of the image
+            let uv_base = match attribute("TEXCOORD_0") {
+                Some(accessor) => {
+                    let values = self.accessor(accessor, &[2])?;
+                    if values.len() != 2*count { return Err(self.invalid(format!("TEXCOORD_0 of mesh {} does not match POSITION", index))) }
+                    uvs.extend(values.chunks(2).map(|uv| (uv[0], 1.0 - uv[1])));
+                    Some(uvs.len() - count)
+                },
+                None => None
+            };
+            match attribute("COLOR_0") {
+                Some(accessor) => {
+                    let values = self.accessor(accessor, &[3, 4])?;
+                    let components = values.len() / count.max(1);
+                    if values.len() != components*count { return Err(self.invalid(format!("COLOR_0 of mesh {} does not match POSITION", index))) }
+                    colors.extend(values.chunks(components).map(|c| Color::new_rgb(c[0], c[1], c[2])));
+                    has_colors = true;
+                },
+                None => colors.extend(std::iter::repeat_n(Color::gray_scale(1.0), count))
+            }
+
+            let indices: Vec<usize> = match primitive.get("indices").and_then(Json::as_usize) {
+                Some(accessor) => self.accessor(accessor, &[1])?.into_iter().map(|i| i as usize).collect(),
+                None => (0..count).collect()
+            };
+            if let Some(i) = indices.iter().find(|i| **i >= count) {
+                return Err(self.invalid(format!("Index {} of mesh {} out of range, {} vertices defined", i, index, count)));
+            }
+            let corners: Vec<[usize; 3]> = match mode {
+                4 => indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect(),
+                // every second triangle of a strip is flipped to keep the winding order
+                5 => (2..indices.len()).map(|i| if i % 2 == 0 { [indices[i - 2], indices[i - 1], indices[i]] } else { [indices[i - 1], indices[i - 2], indices[i]] }).collect(),
+                _ => (2..indices.len()).map(|i| [indices[0], indices[i - 1], indices[i]]).collect()
+            };
+
+            let material = materials.len() as u32;
+            materials.push(self.material(primitive.get("material").and_then(Json::as_usize)));
+            for t in corners {
+                triangles.push(t.map(|i| base + i));
+                triangle_normals.push(normal_base.map(|b| t.map(|i| b + i)));
+                triangle_uvs.push(uv_base.map(|b| t.map(|i| (b + i) as u32)));
+                triangle_materials.push(material);
+            }
+        }
+
+        let mesh = if triangles.is_empty() { None } else {
+            let triangle_normals = complete_normals(&vertices, &triangles, triangle_normals, &mut normals, settings::get().crease_angle);
+            let triangles = triangles.iter().zip(triangle_normals).zip(triangle_uvs).zip(triangle_materials).map(|(((t, n), uv), material)| IndexedTriangle{
+                vertices: t.map(|i| i as u32),
+                normals: Some(n.map(|i| i as u32)),
+                uvs: uv,
+                material
+            }).collect();
+            let mesh = Mesh::new(vertices, normals, uvs, triangles, materials);
+            Some(Arc::new(if has_colors { mesh.with_colors(colors) } else { mesh }))
+        };
+        self.meshes.insert(index, mesh.clone());
+        Ok(mesh)
+    }
+
+    // Elements of the accessor one after another, normalized integers are mapped to [0, 1] or [-1, 1]
+    fn accessor(&self, index: usize, allowed_components: &[usize]) -> Result<Vec<f64>, ImportError> {
+        let accessor = self.array("accessors", index)?;
+        let invalid = |message: &str| self.invalid(format!("Accessor {}: {}", index, message));
+        let count = accessor.get("count").and_then(Json::as_usize).ok_or_else(|| invalid("missing count"))?;
+        let components = match accessor.get("type").and_then(Json::as_str) {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            _ => return Err(invalid("unsupported type"))
+        };
+        if !allowed_components.contains(&components) {
+            return Err(invalid("unexpected type"));
+        }
+        let component_type = accessor.get("componentType").and_then(Json::as_usize).unwrap_or(0);
+        let (size, range) = match component_type {
+            5120 => (1, i8::MAX as f64),
+            5121 => (1, u8::MAX as f64),
+            5122 => (2, i16::MAX as f64),
+            5123 => (2, u16::MAX as f64),
+            5125 => (4, u32::MAX as f64),
+            5126 => (4, 1.0),
+            _ => return Err(invalid("unsupported componentType"))
+        };
+        if accessor.get("sparse").is_some() {
+            return Err(invalid("sparse accessors are not supported"));
+        }
+        let normalized = accessor.get("normalized").and_then(Json::as_bool).unwrap_or(false);
+
+        // accessors without buffer view are zero
+        let view = match accessor.get("bufferView").and_then(Json::as_usize) {
+            Some(view) => self.array("bufferViews", view)?,
+            None => return count.checked_mul(components).map(|n| vec![0.0; n]).ok_or_else(|| invalid("count is too large"))
+        };
+        let buffer = view.get("buffer").and_then(Json::as_usize).and_then(|b| self.buffers.get(b)).ok_or_else(|| invalid("missing buffer"))?;
+        let view_offset = view.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+        let view_length = view.get("byteLength").and_then(Json::as_usize).unwrap_or(0);
+        let accessor_offset = accessor.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+        let stride = view.get("byteStride").and_then(Json::as_usize).unwrap_or(size*components);
+        // offsets and counts of a corrupt file must not overflow
+        let offset = view_offset.checked_add(accessor_offset);
+        let end = offset.and_then(|offset| match count {
+            0 => Some(offset),
+            _ => stride.checked_mul(count - 1)?.checked_add(offset)?.checked_add(size*components)
+        });
+        let view_end = view_offset.checked_add(view_length);
+        let offset = match (offset, end, view_end) {
+            (Some(offset), Some(end), Some(view_end)) if end <= view_end && end <= buffer.len() => offset,
+            _ => return Err(invalid("data exceeds its buffer view"))
+        };
+
+        let read = |at: usize| -> f64 {
+            let b = &buffer[at..at + size];
+            match component_type {
+                5120 => b[0] as i8 as f64,
+                5121 => b[0] as f64,
+                5122 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                5123 => u16::from_le_bytes([b[0], b[1]]) as f64,
+                5125 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                _ => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64
+            }
+        };
+        let mut values = Vec::with_capacity(count*components);
+        for i in 0..count {
+            for c in 0..components {
+                let value = read(offset + i*stride + c*size);
+                values.push(if normalized { (value/range).max(-1.0) } else { value });
+            }
+        }
+        Ok(values)
+    }
+
+    // Factors and textures of the metallic-roughness model, textures which can not be decoded leave their factors
+    fn material(&mut self, index: Option<usize>) -> Box<dyn Material> {
+        let description = match index.and_then(|i| self.document.get("materials").and_then(|m| m.at(i))) {
+            Some(description) => description.clone(),
+            None => return Box::new(Lambertian::new(Color::gray_scale(1.0)))
+        };
+        let pbr = description.get("pbrMetallicRoughness");
+        let number = |name: &str, default: f64| pbr.and_then(|p| p.get(name)).and_then(Json::as_f64).unwrap_or(default);
+        let base = pbr.and_then(|p| p.get("baseColorFactor")).and_then(Json::numbers).filter(|c| c.len() == 4).unwrap_or(vec![1.0; 4]);
+        let emissive = description.get("emissiveFactor").and_then(Json::numbers).filter(|c| c.len() == 3).unwrap_or(vec![0.0; 3]);
+
+        let mut material = MetallicRoughness::new(Color::new_rgb(base[0], base[1], base[2]), number("metallicFactor", 1.0), number("roughnessFactor", 1.0))
+            .with_emission(Color::new_rgb(emissive[0], emissive[1], emissive[2]));
+        if description.get("alphaMode").and_then(Json::as_str) == Some("BLEND") {
+            material = material.with_dissolve(base[3]);
+        }
+        if let Some(texture) = self.texture(pbr.and_then(|p| p.get("baseColorTexture")), true) {
+            material = material.with_base_color_map(texture);
+        }
+        if let Some(texture) = self.texture(pbr.and_then(|p| p.get("metallicRoughnessTexture")), false) {
+            material = material.with_metallic_roughness_map(texture);
+        }
+        if let Some(texture) = self.texture(description.get("emissiveTexture"), true) {
+            material = material.with_emission_map(texture);
+        }
+        let normal = description.get("normalTexture");
+        if let Some(texture) = self.texture(normal, false) {
+            material = material.with_normal_map(texture, normal.and_then(|n| n.get("scale")).and_then(Json::as_f64).unwrap_or(1.0));
+        }
+        Box::new(material)
+    }
+
+    // The texture a texture info refers to, only the first set of texture coordinates is read
+    fn texture(&mut self, info: Option<&Json>, gamma_encoded: bool) -> Option<Arc<Texture>> {
+        let index = info?.get("index").and_then(Json::as_usize)?;
+        if info?.get("texCoord").and_then(Json::as_usize).unwrap_or(0) != 0 {
+            println!("Texture {} is read with TEXCOORD_0, other sets of texture coordinates are not supported", index);
+        }
+        if let Some(texture) = self.textures.get(&(index, gamma_encoded)) { return texture.clone() }
+
+        let texture = self.image(index).and_then(|bytes| {
+            Texture::from_memory(&bytes, gamma_encoded).map_err(|err| self.invalid(format!("Texture {}: {}", index, err)))
+        });
+        let texture = match texture {
+            Ok(texture) => Some(Arc::new(texture)),
+            Err(err) => {
+                println!("Skipped texture: {}", err);
+                None
+            }
+        };
+        self.textures.insert((index, gamma_encoded), texture.clone());
+        texture
+    }
+
+    // Encoded bytes of the image of a texture, a data URI, a file next to the glTF file or a buffer view
+    fn image(&self, texture: usize) -> Result<Vec<u8>, ImportError> {
+        let source = self.array("textures", texture)?.get("source").and_then(Json::as_usize)
+            .ok_or_else(|| self.invalid(format!("Texture {} without source", texture)))?;
+        let image = self.array("images", source)?;
+        match (image.get("uri").and_then(Json::as_str), image.get("bufferView").and_then(Json::as_usize)) {
+            (Some(uri), _) if uri.starts_with("data:") => uri.split_once(";base64,").and_then(|(_, data)| decode_base64(data))
+                .ok_or_else(|| self.invalid(format!("Image {} is not valid base64 data", source))),
+            (Some(uri), _) => Ok(fs::read(self.directory.join(decode_uri(uri)))?),
+            (None, Some(view)) => {
+                let view = self.array("bufferViews", view)?;
+                let buffer = view.get("buffer").and_then(Json::as_usize).and_then(|b| self.buffers.get(b));
+                let offset = view.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+                let length = view.get("byteLength").and_then(Json::as_usize).unwrap_or(0);
+                offset.checked_add(length).and_then(|end| buffer?.get(offset..end)).map(<[u8]>::to_vec)
+                    .ok_or_else(|| self.invalid(format!("Image {} exceeds its buffer", source)))
+            },
+            (None, None) => Err(self.invalid(format!("Image {} without uri or bufferView", source)))
+        }
+    }
+
+    // glTF cameras look along -z with y up, the field of view of the renderer is horizontal
+    fn camera(&self, index: usize, transformation: &Transformation) -> Result<Option<PerspectiveCamera>, ImportError> {
+        let perspective = match self.array("cameras", index)?.get("perspective") {
+            Some(perspective) => perspective,
+            None => {
+                println!("Skipped camera {}, only perspective cameras are supported", index);
+                return Ok(None);
+            }
+        };
+        let y_fov = perspective.get("yfov").and_then(Json::as_f64).ok_or_else(|| self.invalid(format!("Camera {} without yfov", index)))?;
+        let settings = settings::get();
+        let aspect = perspective.get("aspectRatio").and_then(Json::as_f64).unwrap_or(settings.screen_width as f64 / settings.screen_height as f64);
+        let fov = 2.0*((y_fov/2.0).tan()*aspect).atan();
+
+        let matrix = transformation.matrix();
+        Ok(Some(PerspectiveCamera::new(matrix*Point::origin(), matrix*Direction::negz(), matrix*Direction::up(), fov.to_degrees())))
+    }
+
+    // Intensities are given in candela, the lights of the renderer by their power
+    fn light(&self, index: usize, transformation: &Transformation) -> Result<Option<Box<dyn Light>>, ImportError> {
+        let light = self.document.get("extensions").and_then(|e| e.get("KHR_lights_punctual")).and_then(|l| l.get("lights")).and_then(|l| l.at(index))
+            .ok_or_else(|| self.invalid(format!("Missing light {}", index)))?;
+        let color = light.get("color").and_then(Json::numbers).filter(|c| c.len() == 3).map_or(Color::gray_scale(1.0), |c| Color::new_rgb(c[0], c[1], c[2]));
+        let intensity = light.get("intensity").and_then(Json::as_f64).unwrap_or(1.0);
+        let matrix = transformation.matrix();
+        let position = matrix*Point::origin();
+
+        match light.get("type").and_then(Json::as_str) {
+            Some("point") => Ok(Some(Box::new(PointLight::new(position, 4.0*PI*intensity, color)))),
+            Some("spot") => {
+                let angle = |name: &str, default: f64| light.get("spot").and_then(|s| s.get(name)).and_then(Json::as_f64).unwrap_or(default);
+                let (inner, outer) = (angle("innerConeAngle", 0.0), angle("outerConeAngle", PI/4.0));
+                let power = intensity*2.0*PI*(1.0 - 0.5*(inner.cos() + outer.cos()));
+                Ok(Some(Box::new(SpotLight::new(position, matrix*Direction::negz(), inner, outer, power, color))))
+            },
+            other => {
+                println!("Skipped light {}, lights of type {} are not supported", index, other.unwrap_or("unknown"));
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len()*3/4);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes().filter(|c| *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+// Relative URIs may contain percent encoded characters such as spaces
+fn decode_uri(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cg_tools::Ray;
+
+    fn encode_base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        bytes.chunks(3).flat_map(|chunk| {
+            let bits = chunk.iter().enumerate().fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8*i));
+            (0..chunk.len() + 1).map(move |i| ALPHABET[(bits >> (18 - 6*i) & 63) as usize] as char)
+        }).collect()
+    }
+
+    // Four vertices of the unit square in the xy plane as one embedded buffer, the rest of the document is given
+    fn parse(name: &str, document: &str) -> Result<GltfScene, ImportError> {
+        let positions = [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [1.0, 1.0, 0.0]];
+        let bytes: Vec<u8> = positions.iter().flatten().flat_map(|c| c.to_le_bytes()).collect();
+        let buffer = format!(r#""buffers": [{{"byteLength": 48, "uri": "data:application/octet-stream;base64,{}"}}],
+            "bufferViews": [{{"buffer": 0, "byteLength": 48}}]"#, encode_base64(&bytes));
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, format!("{{{}, {}}}", buffer, document)).unwrap();
+        let scene = parse_gltf(&path.to_string_lossy());
+        fs::remove_file(path).unwrap();
+        scene
+    }
+
+    fn mesh(mode: usize, count: usize) -> String {
+        format!(r#""accessors": [{{"bufferView": 0, "componentType": 5126, "count": {}, "type": "VEC3"}}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}, "mode": {}}}]}}]"#, count, mode)
+    }
+
+    fn close(a: Point, b: Point) -> bool {
+        (a - b).length() < 1e-9
+    }
+
+    fn png(rgb: [u8; 3]) -> Vec<u8> {
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(2, 2, image::Rgb(rgb)).write_to(&mut bytes, image::ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn textures_are_read_from_every_kind_of_image() {
+        // the base color is a data URI, the emissive image a file next to the document and the metallic-roughness
+        // image a buffer view, which makes the otherwise metallic material a dielectric
+        fs::write(std::env::temp_dir().join("gltf_emissive.png"), png([0, 0, 255])).unwrap();
+        let metallic_roughness = png([0, 255, 0]);
+        let document = format!(r#"{{"materials": [{{"pbrMetallicRoughness": {{"baseColorTexture": {{"index": 0}}, "metallicRoughnessTexture": {{"index": 2}},
+                "roughnessFactor": 0.5}}, "emissiveFactor": [1, 1, 1], "emissiveTexture": {{"index": 1}}}}],
+            "textures": [{{"source": 0}}, {{"source": 1}}, {{"source": 2}}, {{"source": 3}}],
+            "images": [{{"uri": "data:image/png;base64,{}"}}, {{"uri": "gltf_emissive.png"}}, {{"bufferView": 0, "mimeType": "image/png"}},
+                {{"uri": "gltf_missing.png"}}],
+            "bufferViews": [{{"buffer": 0, "byteLength": {}}}]}}"#, encode_base64(&png([255, 0, 0])), metallic_roughness.len());
+        let mut gltf = Gltf{file_path: "gltf_textures.gltf", directory: std::env::temp_dir(), document: Json::parse(&document).unwrap(),
+            buffers: vec![metallic_roughness], meshes: HashMap::new(), textures: HashMap::new()};
+
+        let material = gltf.material(Some(0));
+        let (r, g, b) = material.surface_brdf(Direction::up(), Direction::up(), Normal::up(), Some((0.5, 0.5))).rgb();
+        assert!(r > g && g > 0.0 && g == b, "{:?}", (r, g, b));
+        assert_eq!(material.surface_emission(Some((0.5, 0.5))), Color::new_rgb(0.0, 0.0, 1.0));
+        assert_eq!(material.surface_emission(None), Color::gray_scale(1.0));
+        assert_eq!(gltf.textures.len(), 3);
+        // images which can not be read leave the factors of the material
+        assert!(gltf.texture(Json::parse(r#"{"index": 3}"#).ok().as_ref(), true).is_none());
+        fs::remove_file(std::env::temp_dir().join("gltf_emissive.png")).unwrap();
+    }
+
+    #[test]
+    fn base64_round_trips() {
+        for length in 0..8 {
+            let bytes: Vec<u8> = (0..length).map(|i| (i*97 + 13) as u8).collect();
+            assert_eq!(decode_base64(&encode_base64(&bytes)), Some(bytes));
+        }
+        assert_eq!(decode_base64("AAA="), Some(vec![0, 0]));
+        assert_eq!(decode_base64("A*"), None);
+    }
+
+    #[test]
+    fn node_transformations_compose() {
+        // the child is scaled, then rotated by 90 degrees around z and finally moved by its parent
+        let half = 0.5f64.sqrt();
+        let scene = parse("gltf_nodes.gltf", &format!(r#"{},
+            "nodes": [{{"translation": [1, 0, 0], "children": [1]}}, {{"scale": [2, 2, 2], "rotation": [0, 0, {}, {}], "mesh": 0}}],
+            "scenes": [{{"nodes": [0]}}]"#, mesh(4, 3), half, half)).unwrap();
+        assert_eq!(scene.instances.len(), 1);
+        let matrix = scene.instances[0].transformation().matrix();
+        assert!(close(matrix*Point::new(1.0, 0.0, 0.0), Point::new(1.0, 2.0, 0.0)));
+        assert!(close(matrix*Point::new(0.0, 1.0, 0.0), Point::new(-1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn triangle_strips_keep_their_winding() {
+        let scene = parse("gltf_strip.gltf", &format!(r#"{}, "nodes": [{{"mesh": 0}}]"#, mesh(5, 4))).unwrap();
+        let instance = &scene.instances[0];
+        // both triangles face +z, single sided meshes are only hit from the front
+        for (x, y) in [(0.2, 0.2), (0.8, 0.8)] {
+            let from_front = Ray::new(Point::new(x, y, 1.0), Direction::new(0.0, 0.0, -1.0));
+            assert!(instance.intersect(&from_front).is_some(), "({}, {})", x, y);
+            let from_behind = Ray::new(Point::new(x, y, -1.0), Direction::new(0.0, 0.0, 1.0));
+            assert!(instance.intersect(&from_behind).is_none(), "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn spot_lights_are_placed_by_their_node() {
+        // rotated by -90 degrees around x the light shines down
+        let half = 0.5f64.sqrt();
+        let scene = parse("gltf_spot.gltf", &format!(r#""nodes": [{{"translation": [0, 3, 0], "rotation": [{}, 0, 0, {}],
+                "extensions": {{"KHR_lights_punctual": {{"light": 0}}}}}}],
+            "extensions": {{"KHR_lights_punctual": {{"lights": [
+                {{"type": "spot", "intensity": 10, "spot": {{"innerConeAngle": 0.2, "outerConeAngle": 0.5}}}}]}}}}"#, -half, half)).unwrap();
+        assert_eq!(scene.lights.len(), 1);
+        let light = &scene.lights[0];
+        let (position, _) = light.light_points(settings::get().light_sampling_technique)[0];
+        assert!(close(position, Point::new(0.0, 3.0, 0.0)));
+
+        let radiance = |direction: Direction| Color::from(light.radiance_towards(Point::origin(), direction)).rgb().0;
+        // the intensity in candela is the radiance inside the inner cone
+        assert!((radiance(Direction::new(0.0, -1.0, 0.0)) - 10.0).abs() < 1e-9);
+        assert!((radiance(Direction::new(0.0, -1.0, 0.3)) - 10.0).abs() > 1e-3);
+        assert_eq!(radiance(Direction::new(0.0, 1.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn malformed_accessors_are_rejected() {
+        let document = |count: &str| format!(r#"{}, "nodes": [{{"mesh": 0}}]"#, mesh(4, 0).replace(r#""count": 0"#, &format!(r#""count": {}"#, count)));
+        assert!(parse("gltf_accessor_long.gltf", &document("6")).is_err());
+        assert!(parse("gltf_accessor_overflow.gltf", &document("18446744073709551615")).is_err());
+        let unbounded = document("18446744073709551615").replace(r#""bufferView": 0, "#, "");
+        assert!(parse("gltf_accessor_unbounded.gltf", &unbounded).is_err());
+        assert!(parse("gltf_accessor_type.gltf", &document("3").replace("VEC3", "MAT4")).is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use std::fmt;
+use std::io;
+
+//////////////////
+//ImportError
+//////////////////
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    Parse{file: String, line: usize, message: String},
+    // errors in binary data or the structure of a file, which have no line
+    Invalid{file: String, message: String}
+}
+
+impl ImportError {
+    pub fn parse(file: &str, line: usize, message: String) -> ImportError {
+        ImportError::Parse{file: file.to_string(), line, message}
+    }
+
+    pub fn invalid(file: &str, message: String) -> ImportError {
+        ImportError::Invalid{file: file.to_string(), message}
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Io(err) => write!(f, "{}", err),
+            ImportError::Parse{file, line, message} => write!(f, "{}:{}: {}", file, line, message),
+            ImportError::Invalid{file, message} => write!(f, "{}: {}", file, message)
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<io::Error> for ImportError {
+    fn from(err: io::Error) -> Self {
+        ImportError::Io(err)
+    }
+}
@@ -0,0 +1,246 @@
+//////////////////
+//Json
+//////////////////
+// Minimal JSON document as needed to read glTF files, objects keep the order of their members
+#[derive(Clone, Debug)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    // Errors give the line and a description
+    pub fn parse(text: &str) -> Result<Json, (usize, String)> {
+        let mut parser = Parser{bytes: text.as_bytes(), position: 0, line: 1};
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.position < parser.bytes.len() {
+            return Err(parser.error("Unexpected characters after the document"));
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None
+        }
+    }
+
+    pub fn at(&self, index: usize) -> Option<&Json> {
+        match self {
+            Json::Array(items) => items.get(index),
+            _ => None
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        self.as_f64().filter(|n| *n >= 0.0 && n.fract() == 0.0).map(|n| n as usize)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None
+        }
+    }
+
+    // Anything but an array is treated as an empty one
+    pub fn items(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[]
+        }
+    }
+
+    pub fn numbers(&self) -> Option<Vec<f64>> {
+        match self {
+            Json::Array(items) => items.iter().map(|item| item.as_f64()).collect(),
+            _ => None
+        }
+    }
+}
+
+// Nesting deeper than this is rejected instead of overflowing the stack
+const MAX_DEPTH: usize = 256;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    line: usize
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> (usize, String) {
+        (self.line, message.to_string())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b) = self.bytes.get(self.position) {
+            match b {
+                b'\n' => self.line += 1,
+                b' ' | b'\t' | b'\r' => (),
+                _ => break
+            }
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.position).cloned()
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), (usize, String)> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("Expected '{}'", expected as char)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, (usize, String)> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("Document is nested too deeply"));
+        }
+        match self.peek() {
+            Some(b'{') => {
+                self.position += 1;
+                let mut members = vec![];
+                if self.peek() == Some(b'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return Err(self.error("Expected member name"));
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value(depth + 1)?));
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        Some(b'}') => {
+                            self.position += 1;
+                            return Ok(Json::Object(members));
+                        },
+                        _ => return Err(self.error("Expected ',' or '}'"))
+                    }
+                }
+            },
+            Some(b'[') => {
+                self.position += 1;
+                let mut items = vec![];
+                if self.peek() == Some(b']') {
+                    self.position += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        Some(b']') => {
+                            self.position += 1;
+                            return Ok(Json::Array(items));
+                        },
+                        _ => return Err(self.error("Expected ',' or ']'"))
+                    }
+                }
+            },
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Unexpected end of document"))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, (usize, String)> {
+        if !self.bytes[self.position..].starts_with(word.as_bytes()) {
+            return Err(self.error("Unexpected character"));
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Json, (usize, String)> {
+        let start = self.position;
+        while let Some(b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E') = self.bytes.get(self.position) {
+            self.position += 1;
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.position]).unwrap_or("");
+        text.parse().map(Json::Number).map_err(|_| self.error(&format!("Invalid number: {}", text)))
+    }
+
+    fn string(&mut self) -> Result<String, (usize, String)> {
+        self.position += 1;
+        let mut bytes = vec![];
+        loop {
+            let b = *self.bytes.get(self.position).ok_or_else(|| self.error("Unterminated string"))?;
+            self.position += 1;
+            match b {
+                b'"' => break,
+                b'\n' => return Err(self.error("Line break in string")),
+                b'\\' => {
+                    let escaped = *self.bytes.get(self.position).ok_or_else(|| self.error("Unterminated string"))?;
+                    self.position += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err(self.error("Invalid escape sequence"))
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                },
+                _ => bytes.push(b)
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("String is not valid UTF-8"))
+    }
+
+    // Characters outside the basic plane are written as a surrogate pair
+    fn unicode_escape(&mut self) -> Result<char, (usize, String)> {
+        let first = self.hex()?;
+        let code = if (0xD800..0xDC00).contains(&first) && self.bytes[self.position..].starts_with(b"\\u") {
+            self.position += 2;
+            let second = self.hex()?;
+            0x10000 + ((first - 0xD800) << 10) + (second.wrapping_sub(0xDC00) & 0x3FF)
+        } else {
+            first
+        };
+        Ok(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    fn hex(&mut self) -> Result<u32, (usize, String)> {
+        let digits = self.bytes.get(self.position..self.position + 4).and_then(|d| std::str::from_utf8(d).ok());
+        let value = digits.and_then(|d| u32::from_str_radix(d, 16).ok()).ok_or_else(|| self.error("Invalid unicode escape"))?;
+        self.position += 4;
+        Ok(value)
+    }
+}
@@ -1,13 +1,25 @@
 
 use std::f64::consts::PI;
 
-use crate::math::{Normal, Point};
+use crate::math::{Normal, Point, Direction, Matrix};
 use super::{Object, Rectangle};
 use crate::cg_tools::{Color, Radiance, SamplingTechnique, Transformation};
 
 pub trait Light : Send + Sync{
     fn light_points(&self, sampling_technique: SamplingTechnique) -> Vec<(Point,Option<Normal>)>;
     fn radiance_from_point(&self, point: Point) -> Radiance;
+    fn radiance_towards(&self, point: Point, _: Direction) -> Radiance { self.radiance_from_point(point) }
+    // The parameters the light was created from, None for lights the scene file can not describe
+    fn describe(&self) -> Option<LightDescription> { None }
+}
+
+// Angles of spot lights are given in radians
+#[derive(Copy, Clone, PartialEq)]
+pub enum LightDescription {
+    Point{position: Point, power: f64, color: Color},
+    Spot{position: Point, direction: Direction, inner_angle: f64, outer_angle: f64, power: f64, color: Color},
+    Distant{direction: Direction, irradiance: f64, color: Color},
+    Surface{points: [Point; 4], transformation: Matrix, power: f64, color: Color}
 }
 
 pub struct PointLight {
@@ -32,6 +44,41 @@ impl Light for PointLight {
         let rad = Radiance::gray_scale(factor);
         rad*Radiance::from(self.color)
     }
+
+    fn describe(&self) -> Option<LightDescription> {
+        Some(LightDescription::Point{position: self.position, power: self.power, color: self.color})
+    }
+}
+
+// Light arriving from one direction, placed far away from the origin so its rays are nearly parallel within the scene
+const DISTANT_LIGHT_DISTANCE: f64 = 1e6;
+
+pub struct DistantLight {
+    direction: Direction,
+    irradiance: f64,
+    color: Color
+}
+
+impl DistantLight {
+    pub fn new(direction: Direction, irradiance: f64, color: Color) -> DistantLight{
+        DistantLight{direction, irradiance, color}
+    }
+}
+
+impl Light for DistantLight {
+    fn light_points(&self, _:SamplingTechnique) -> Vec<(Point, Option<Normal>)> {
+        vec![(Point::origin() + DISTANT_LIGHT_DISTANCE**self.direction.invert(), None)]
+    }
+
+    fn radiance_from_point(&self, _: Point) -> Radiance {
+        let factor = self.irradiance*DISTANT_LIGHT_DISTANCE*DISTANT_LIGHT_DISTANCE;
+        let rad = Radiance::gray_scale(factor);
+        rad*Radiance::from(self.color)
+    }
+
+    fn describe(&self) -> Option<LightDescription> {
+        Some(LightDescription::Distant{direction: self.direction, irradiance: self.irradiance, color: self.color})
+    }
 }
 
 pub struct SurfaceLight {
@@ -72,4 +119,78 @@ impl Light for SurfaceLight {
         rad*Radiance::from(self.color)
     }
 
-}
\ No newline at end of file
+    fn describe(&self) -> Option<LightDescription> {
+        Some(LightDescription::Surface{points: self.surface.points(), transformation: self.transformation.matrix(), power: self.power, color: self.color})
+    }
+}
+pub struct SpotLight {
+    position: Point,
+    direction: Direction,
+    cos_inner: f64,
+    cos_outer: f64,
+    power: f64,
+    color: Color
+}
+
+impl SpotLight {
+    pub fn new(position: Point, direction: Direction, inner_angle: f64, outer_angle: f64, power: f64, color: Color) -> SpotLight{
+        SpotLight{position, direction, cos_inner: inner_angle.cos(), cos_outer: outer_angle.cos(), power, color}
+    }
+}
+
+impl Light for SpotLight {
+    fn light_points(&self, _:SamplingTechnique) -> Vec<(Point, Option<Normal>)> {
+        vec![(self.position, None)]
+    }
+
+    fn radiance_from_point(&self, _: Point) -> Radiance {
+        let factor = self.power / (2.0*PI*(1.0 - 0.5*(self.cos_inner + self.cos_outer)));
+        let rad = Radiance::gray_scale(factor);
+        rad*Radiance::from(self.color)
+    }
+
+    fn radiance_towards(&self, point: Point, direction: Direction) -> Radiance {
+        let cos = self.direction.dot(&direction);
+        let falloff = if cos >= self.cos_inner { 1.0 }
+            else if cos <= self.cos_outer { 0.0 }
+            else {
+                let x = (cos - self.cos_outer) / (self.cos_inner - self.cos_outer);
+                x*x*(3.0 - 2.0*x)
+            };
+        self.radiance_from_point(point)*falloff
+    }
+
+    fn describe(&self) -> Option<LightDescription> {
+        Some(LightDescription::Spot{position: self.position, direction: self.direction, inner_angle: self.cos_inner.acos(),
+            outer_angle: self.cos_outer.acos(), power: self.power, color: self.color})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brightness(radiance: Radiance) -> f64 {
+        let (r, g, b) = Color::from(radiance).rgb();
+        r + g + b
+    }
+
+    #[test]
+    fn spot_lights_fall_off_between_their_cones() {
+        let spot = SpotLight::new(Point::new(0.0, 3.0, 0.0), Direction::new(0.0, -1.0, 0.0), 20f64.to_radians(), 40f64.to_radians(), 100.0, Color::gray_scale(1.0));
+        let position = Point::new(0.0, 3.0, 0.0);
+        let towards = |degrees: f64| {
+            let angle = f64::to_radians(degrees);
+            brightness(spot.radiance_towards(position, Direction::new(angle.sin(), -angle.cos(), 0.0)))
+        };
+        let full = brightness(spot.radiance_from_point(position));
+        assert!(full > 0.0);
+        assert_eq!(towards(0.0), full);
+        assert_eq!(towards(19.0), full);
+        assert_eq!(towards(41.0), 0.0);
+        assert_eq!(towards(90.0), 0.0);
+        let falloff: Vec<f64> = [22.0, 26.0, 30.0, 34.0, 38.0].iter().map(|degrees| towards(*degrees)).collect();
+        assert!(falloff.windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(falloff[0] < full && falloff[4] > 0.0);
+    }
+}
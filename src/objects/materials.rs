@@ -1,15 +1,38 @@
 
 use std::f64::consts::PI;
 use std::fmt::Debug;
+use std::sync::Arc;
 
-use crate::math::Direction;
-use crate::cg_tools::Color;
+use crate::math::{Direction, Normal, Vector, EPSILON};
+use crate::cg_tools::{Color, Texture};
 
 //////////////////
 //Material
 //////////////////
 pub trait Material : Send + Sync + Debug{
     fn brdf(&self, incoming: Direction, outgoing: Direction) -> Color;
+    fn scatter(&self, _: Direction, _: Normal) -> Vec<(Direction, Color)> { Vec::new() }
+    fn transmittance(&self, _: Direction, _: Normal) -> Option<Color> { None }
+    fn subsurface(&self) -> Option<&Subsurface> { None }
+    // Materials varying over the surface or depending on the normal override these
+    fn surface_brdf(&self, incoming: Direction, outgoing: Direction, _: Normal, _: Option<(f64, f64)>) -> Color { self.brdf(incoming, outgoing) }
+    fn emission(&self) -> Color { Color::black() }
+    fn surface_emission(&self, _: Option<(f64, f64)>) -> Color { self.emission() }
+    fn bump(&self, _: (f64, f64)) -> Option<(f64, f64)> { None }
+    // Normal in the tangent space of the surface, x along u, y along v and z along the geometric normal
+    fn normal_map(&self, _: (f64, f64)) -> Option<Vector> { None }
+    // The parameters the material was created from, None for materials the scene file can not describe
+    fn describe(&self) -> Option<MaterialDescription> { None }
+}
+
+// Texture maps are not part of the description
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MaterialDescription {
+    Lambertian{color: Color},
+    Phong{diffuse: Color, specular: Color, exponent: f64, emission: Color, dissolve: f64, mirror: bool},
+    ThinDielectric{color: Color, ior: f64, film: Option<ThinFilm>},
+    Subsurface{color: Color, mean_free_path: f64, ior: f64},
+    Transparent
 }
 
 //////////////////
@@ -31,4 +54,503 @@ impl Material for Lambertian {
         let factor = 1.0/(2.0*PI);
         self.color*factor
     }
-}
\ No newline at end of file
+
+    fn describe(&self) -> Option<MaterialDescription> {
+        Some(MaterialDescription::Lambertian{color: self.color})
+    }
+}
+//////////////////
+//Phong
+//////////////////
+// Diffuse plus normalized Blinn-Phong lobe as described by OBJ/MTL files, dissolve lets part of the light pass straight through
+#[derive(Clone, Debug)]
+pub struct Phong {
+    diffuse: Color,
+    specular: Color,
+    exponent: f64,
+    emission: Color,
+    dissolve: f64,
+    mirror: bool,
+    diffuse_map: Option<Arc<Texture>>,
+    bump_map: Option<(Arc<Texture>, f64)>
+}
+
+impl Phong {
+    pub fn new(diffuse: Color, specular: Color, exponent: f64) -> Phong {
+        Phong{diffuse, specular, exponent, emission: Color::black(), dissolve: 1.0, mirror: false, diffuse_map: None, bump_map: None}
+    }
+
+    pub fn with_emission(mut self, emission: Color) -> Phong {
+        self.emission = emission;
+        self
+    }
+
+    pub fn with_dissolve(mut self, dissolve: f64) -> Phong {
+        self.dissolve = dissolve.clamp(0.0, 1.0);
+        self
+    }
+
+    // Mirrors the specular color in the reflected direction
+    pub fn with_mirror(mut self) -> Phong {
+        self.mirror = true;
+        self
+    }
+
+    pub fn with_diffuse_map(mut self, texture: Arc<Texture>) -> Phong {
+        self.diffuse_map = Some(texture);
+        self
+    }
+
+    pub fn with_bump_map(mut self, texture: Arc<Texture>, scale: f64) -> Phong {
+        self.bump_map = Some((texture, scale));
+        self
+    }
+
+    fn diffuse_color(&self, uv: Option<(f64, f64)>) -> Color {
+        match (&self.diffuse_map, uv) {
+            (Some(texture), Some(uv)) => texture.sample(uv),
+            _ => self.diffuse
+        }
+    }
+}
+
+impl Material for Phong {
+    fn brdf(&self, _: Direction, _: Direction) -> Color {
+        self.diffuse*(self.dissolve/(2.0*PI))
+    }
+
+    fn surface_brdf(&self, incoming: Direction, outgoing: Direction, normal: Normal, uv: Option<(f64, f64)>) -> Color {
+        let diffuse = self.diffuse_color(uv)*(1.0/(2.0*PI));
+        let half = Direction::from(*incoming + *outgoing);
+        let cos_half = normal.dot(&half).max(0.0);
+        let specular = self.specular*((self.exponent + 2.0)/(2.0*PI)*cos_half.powf(self.exponent));
+        (diffuse + specular)*self.dissolve
+    }
+
+    fn scatter(&self, outgoing: Direction, normal: Normal) -> Vec<(Direction, Color)> {
+        let mut scattered = Vec::new();
+        if self.mirror {
+            let normal = if normal.dot(&outgoing) < 0.0 { normal.invert() } else { normal };
+            let reflected = Direction::from(2.0*normal.dot(&outgoing)**normal - *outgoing);
+            scattered.push((reflected, self.specular*self.dissolve));
+        }
+        if self.dissolve < 1.0 {
+            scattered.push((outgoing.invert(), Color::gray_scale(1.0 - self.dissolve)));
+        }
+        scattered
+    }
+
+    fn transmittance(&self, _: Direction, _: Normal) -> Option<Color> {
+        if self.dissolve < 1.0 { Some(Color::gray_scale(1.0 - self.dissolve)) } else { None }
+    }
+
+    fn emission(&self) -> Color {
+        self.emission
+    }
+
+    fn bump(&self, uv: (f64, f64)) -> Option<(f64, f64)> {
+        self.bump_map.as_ref().map(|(texture, scale)| {
+            let (du, dv) = texture.gradient(uv);
+            (du*scale, dv*scale)
+        })
+    }
+
+    fn describe(&self) -> Option<MaterialDescription> {
+        Some(MaterialDescription::Phong{diffuse: self.diffuse, specular: self.specular, exponent: self.exponent,
+            emission: self.emission, dissolve: self.dissolve, mirror: self.mirror})
+    }
+}
+
+//////////////////
+//MetallicRoughness
+//////////////////
+// glTF material mapped onto diffuse plus Blinn-Phong, metals reflect with their base color and dielectrics with
+// 4% white. Roughness gives the exponent of a lobe with the same width. Metalness is read from the blue and
+// roughness from the green channel of their texture, both scale the factors.
+#[derive(Clone, Debug)]
+pub struct MetallicRoughness {
+    base_color: Color,
+    metallic: f64,
+    roughness: f64,
+    emission: Color,
+    dissolve: f64,
+    base_color_map: Option<Arc<Texture>>,
+    metallic_roughness_map: Option<Arc<Texture>>,
+    emission_map: Option<Arc<Texture>>,
+    normal_map: Option<(Arc<Texture>, f64)>
+}
+
+impl MetallicRoughness {
+    pub fn new(base_color: Color, metallic: f64, roughness: f64) -> MetallicRoughness {
+        MetallicRoughness{base_color, metallic: metallic.clamp(0.0, 1.0), roughness: roughness.clamp(0.0, 1.0), emission: Color::black(),
+            dissolve: 1.0, base_color_map: None, metallic_roughness_map: None, emission_map: None, normal_map: None}
+    }
+
+    pub fn with_emission(mut self, emission: Color) -> MetallicRoughness {
+        self.emission = emission;
+        self
+    }
+
+    pub fn with_dissolve(mut self, dissolve: f64) -> MetallicRoughness {
+        self.dissolve = dissolve.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_base_color_map(mut self, texture: Arc<Texture>) -> MetallicRoughness {
+        self.base_color_map = Some(texture);
+        self
+    }
+
+    pub fn with_metallic_roughness_map(mut self, texture: Arc<Texture>) -> MetallicRoughness {
+        self.metallic_roughness_map = Some(texture);
+        self
+    }
+
+    pub fn with_emission_map(mut self, texture: Arc<Texture>) -> MetallicRoughness {
+        self.emission_map = Some(texture);
+        self
+    }
+
+    // The scale stretches the x and y components of the stored normals
+    pub fn with_normal_map(mut self, texture: Arc<Texture>, scale: f64) -> MetallicRoughness {
+        self.normal_map = Some((texture, scale));
+        self
+    }
+
+    fn parameters(&self, uv: Option<(f64, f64)>) -> (Color, f64, f64) {
+        let base_color = match (&self.base_color_map, uv) {
+            (Some(texture), Some(uv)) => self.base_color*texture.sample(uv),
+            _ => self.base_color
+        };
+        match (&self.metallic_roughness_map, uv) {
+            (Some(texture), Some(uv)) => {
+                let (_, roughness, metallic) = texture.sample(uv).rgb();
+                (base_color, self.metallic*metallic, self.roughness*roughness)
+            },
+            _ => (base_color, self.metallic, self.roughness)
+        }
+    }
+
+    // Diffuse color, specular color and Blinn-Phong exponent
+    fn lobes(base_color: Color, metallic: f64, roughness: f64) -> (Color, Color, f64) {
+        let specular = Color::gray_scale(0.04*(1.0 - metallic)) + base_color*metallic;
+        let alpha = (roughness*roughness).max(1e-3);
+        let exponent = (2.0/(alpha*alpha) - 2.0).min(1e4);
+        (base_color*(1.0 - metallic), specular, exponent)
+    }
+
+    fn is_mirror(&self) -> bool {
+        self.roughness < 0.1
+    }
+}
+
+impl Material for MetallicRoughness {
+    fn brdf(&self, _: Direction, _: Direction) -> Color {
+        let (diffuse, _, _) = MetallicRoughness::lobes(self.base_color, self.metallic, self.roughness);
+        diffuse*(self.dissolve/(2.0*PI))
+    }
+
+    fn surface_brdf(&self, incoming: Direction, outgoing: Direction, normal: Normal, uv: Option<(f64, f64)>) -> Color {
+        let (base_color, metallic, roughness) = self.parameters(uv);
+        let (diffuse, specular, exponent) = MetallicRoughness::lobes(base_color, metallic, roughness);
+        let half = Direction::from(*incoming + *outgoing);
+        let cos_half = normal.dot(&half).max(0.0);
+        let specular = specular*((exponent + 2.0)/(2.0*PI)*cos_half.powf(exponent));
+        (diffuse*(1.0/(2.0*PI)) + specular)*self.dissolve
+    }
+
+    // textures can not change the mirror, scattering has no texture coordinates
+    fn scatter(&self, outgoing: Direction, normal: Normal) -> Vec<(Direction, Color)> {
+        let mut scattered = Vec::new();
+        if self.is_mirror() {
+            let (_, specular, _) = MetallicRoughness::lobes(self.base_color, self.metallic, self.roughness);
+            let normal = if normal.dot(&outgoing) < 0.0 { normal.invert() } else { normal };
+            let reflected = Direction::from(2.0*normal.dot(&outgoing)**normal - *outgoing);
+            scattered.push((reflected, specular*self.dissolve));
+        }
+        if self.dissolve < 1.0 {
+            scattered.push((outgoing.invert(), Color::gray_scale(1.0 - self.dissolve)));
+        }
+        scattered
+    }
+
+    fn transmittance(&self, _: Direction, _: Normal) -> Option<Color> {
+        if self.dissolve < 1.0 { Some(Color::gray_scale(1.0 - self.dissolve)) } else { None }
+    }
+
+    fn emission(&self) -> Color {
+        self.emission
+    }
+
+    fn surface_emission(&self, uv: Option<(f64, f64)>) -> Color {
+        match (&self.emission_map, uv) {
+            (Some(texture), Some(uv)) => self.emission*texture.sample(uv),
+            _ => self.emission
+        }
+    }
+
+    // stored normals map [0, 1] onto [-1, 1]
+    fn normal_map(&self, uv: (f64, f64)) -> Option<Vector> {
+        self.normal_map.as_ref().map(|(texture, scale)| {
+            let (x, y, z) = texture.sample(uv).rgb();
+            Vector::new((2.0*x - 1.0)*scale, (2.0*y - 1.0)*scale, 2.0*z - 1.0)
+        })
+    }
+}
+
+//////////////////
+//ThinDielectric
+//////////////////
+#[derive(Clone, Debug)]
+pub struct ThinDielectric {
+    ior: f64,
+    color: Color,
+    film: Option<ThinFilm>
+}
+
+impl ThinDielectric{
+    pub fn new(ior: f64, color: Color) -> ThinDielectric {
+        ThinDielectric{ior, color, film: None}
+    }
+
+    pub fn with_film(film: ThinFilm, color: Color) -> ThinDielectric {
+        ThinDielectric{ior: film.ior, color, film: Some(film)}
+    }
+
+    pub fn reflectance(&self, cos_incoming: f64) -> Color {
+        match &self.film {
+            Some(film) => film.reflectance_color(cos_incoming),
+            None => {
+                // incoherent sum over the internal reflections between both faces of the sheet
+                let r = fresnel_dielectric(cos_incoming, self.ior);
+                Color::gray_scale(2.0*r/(1.0 + r))
+            }
+        }
+    }
+}
+
+impl Material for ThinDielectric {
+    fn brdf(&self, _: Direction, _: Direction) -> Color {
+        Color::black()
+    }
+
+    fn scatter(&self, outgoing: Direction, normal: Normal) -> Vec<(Direction, Color)> {
+        let normal = if normal.dot(&outgoing) < 0.0 { normal.invert() } else { normal };
+        let cos = normal.dot(&outgoing).min(1.0);
+        let reflected = Direction::from(2.0*cos**normal - *outgoing);
+
+        let (r,g,b) = self.reflectance(cos).rgb();
+        let transmittance = Color::new_rgb(1.0-r, 1.0-g, 1.0-b)*self.color;
+        vec![(reflected, Color::new_rgb(r,g,b)), (outgoing.invert(), transmittance)]
+    }
+
+    fn transmittance(&self, direction: Direction, normal: Normal) -> Option<Color> {
+        let cos = normal.dot(&direction).abs().min(1.0);
+        let (r,g,b) = self.reflectance(cos).rgb();
+        Some(Color::new_rgb(1.0-r, 1.0-g, 1.0-b)*self.color)
+    }
+
+    fn describe(&self) -> Option<MaterialDescription> {
+        Some(MaterialDescription::ThinDielectric{color: self.color, ior: self.ior, film: self.film})
+    }
+}
+
+//////////////////
+//ThinFilm
+//////////////////
+// Wavelengths (nm) used to approximate the interference spectrum with the rgb primaries
+const RGB_WAVELENGTHS: [f64; 3] = [650.0, 532.0, 450.0];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ThinFilm {
+    thickness: f64,
+    ior: f64
+}
+
+impl ThinFilm {
+    pub fn new(thickness: f64, ior: f64) -> ThinFilm {
+        ThinFilm{thickness, ior}
+    }
+
+    pub fn thickness(&self) -> f64 { self.thickness }
+    pub fn ior(&self) -> f64 { self.ior }
+
+    pub fn reflectance(&self, cos_incoming: f64, wavelength: f64) -> f64 {
+        // Airy summation for a film surrounded by air, averaged over both polarizations
+        let sin_t2 = (1.0 - cos_incoming*cos_incoming) / (self.ior*self.ior);
+        let cos_t = (1.0 - sin_t2).max(0.0).sqrt();
+        let phase = 4.0*PI*self.ior*self.thickness*cos_t / wavelength;
+
+        let rs = (cos_incoming - self.ior*cos_t) / (cos_incoming + self.ior*cos_t);
+        let rp = (self.ior*cos_incoming - cos_t) / (self.ior*cos_incoming + cos_t);
+        let airy = |r: f64| {
+            let r2 = r*r;
+            2.0*r2*(1.0 - phase.cos()) / (1.0 - 2.0*r2*phase.cos() + r2*r2)
+        };
+        (airy(rs) + airy(rp)) / 2.0
+    }
+
+    pub fn reflectance_color(&self, cos_incoming: f64) -> Color {
+        let [r,g,b] = RGB_WAVELENGTHS;
+        Color::new_rgb(self.reflectance(cos_incoming, r), self.reflectance(cos_incoming, g), self.reflectance(cos_incoming, b))
+    }
+}
+
+fn fresnel_dielectric(cos_incoming: f64, ior: f64) -> f64 {
+    let sin_t2 = (1.0 - cos_incoming*cos_incoming) / (ior*ior);
+    if sin_t2 >= 1.0 { return 1.0 }
+    let cos_t = (1.0 - sin_t2).sqrt();
+    let rs = (cos_incoming - ior*cos_t) / (cos_incoming + ior*cos_t);
+    let rp = (ior*cos_incoming - cos_t) / (ior*cos_incoming + cos_t);
+    (rs*rs + rp*rp) / 2.0
+}
+
+//////////////////
+//Subsurface
+//////////////////
+// Normalized diffusion profile (Christensen-Burley), sampled as a mixture of two exponentials
+#[derive(Clone, Debug)]
+pub struct Subsurface {
+    color: Color,
+    mean_free_path: f64,
+    ior: f64
+}
+
+impl Subsurface {
+    pub fn new(color: Color, mean_free_path: f64, ior: f64) -> Subsurface {
+        Subsurface{color, mean_free_path, ior}
+    }
+
+    pub fn color(&self) -> Color { self.color }
+
+    pub fn max_radius(&self) -> f64 {
+        16.0*self.mean_free_path
+    }
+
+    pub fn profile(&self, radius: f64) -> f64 {
+        let d = self.mean_free_path;
+        let radius = radius.max(EPSILON);
+        ((-radius/d).exp() + (-radius/(3.0*d)).exp()) / (8.0*PI*d*radius)
+    }
+
+    pub fn sample_disk(&self) -> (f64, f64) {
+        let scale = if rand::random::<f64>() < 0.25 { self.mean_free_path } else { 3.0*self.mean_free_path };
+        let radius = -scale*(1.0 - rand::random::<f64>()).ln();
+        (radius.min(self.max_radius()), 2.0*PI*rand::random::<f64>())
+    }
+
+    pub fn fresnel(&self, cos_incoming: f64) -> f64 {
+        fresnel_dielectric(cos_incoming, self.ior)
+    }
+}
+
+impl Material for Subsurface {
+    fn brdf(&self, _: Direction, _: Direction) -> Color {
+        Color::black()
+    }
+
+    fn scatter(&self, outgoing: Direction, normal: Normal) -> Vec<(Direction, Color)> {
+        let normal = if normal.dot(&outgoing) < 0.0 { normal.invert() } else { normal };
+        let cos = normal.dot(&outgoing).min(1.0);
+        let reflected = Direction::from(2.0*cos**normal - *outgoing);
+        vec![(reflected, Color::gray_scale(self.fresnel(cos)))]
+    }
+
+    fn subsurface(&self) -> Option<&Subsurface> { Some(self) }
+
+    fn describe(&self) -> Option<MaterialDescription> {
+        Some(MaterialDescription::Subsurface{color: self.color, mean_free_path: self.mean_free_path, ior: self.ior})
+    }
+}
+
+//////////////////
+//Transparent
+//////////////////
+// Index matched boundary, light passes through without any change
+#[derive(Clone, Debug)]
+pub struct Transparent;
+
+impl Material for Transparent {
+    fn brdf(&self, _: Direction, _: Direction) -> Color {
+        Color::black()
+    }
+
+    fn scatter(&self, outgoing: Direction, _: Normal) -> Vec<(Direction, Color)> {
+        vec![(outgoing.invert(), Color::gray_scale(1.0))]
+    }
+
+    fn transmittance(&self, _: Direction, _: Normal) -> Option<Color> {
+        Some(Color::gray_scale(1.0))
+    }
+
+    fn describe(&self) -> Option<MaterialDescription> { Some(MaterialDescription::Transparent) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn sheets_reflect_and_transmit_without_offset() {
+        let sheet = ThinDielectric::new(1.5, Color::gray_scale(1.0));
+        let outgoing = Direction::new(1.0, 1.0, 0.0);
+        let scattered = sheet.scatter(outgoing, Normal::up());
+        assert_eq!(scattered.len(), 2);
+
+        let (reflected, reflectance) = scattered[0];
+        let (transmitted, transmittance) = scattered[1];
+        assert!(close(reflected.x, -outgoing.x) && close(reflected.y, outgoing.y));
+        // the light leaves the sheet in the direction it came from
+        assert_eq!(transmitted, outgoing.invert());
+        let (r, _, _) = reflectance.rgb();
+        let (t, _, _) = transmittance.rgb();
+        assert!(r > 0.0 && close(r + t, 1.0));
+    }
+
+    #[test]
+    fn sheets_reflect_both_faces_at_normal_incidence() {
+        let sheet = ThinDielectric::new(1.5, Color::gray_scale(1.0));
+        // a single face reflects ((1.5 - 1)/(1.5 + 1))^2 = 0.04
+        let (r, g, b) = sheet.reflectance(1.0).rgb();
+        assert!(close(r, 0.08/1.04) && close(g, r) && close(b, r));
+        // the back face is seen from below
+        assert_eq!(sheet.transmittance(Direction::new(0.0, -1.0, 0.0), Normal::up()), sheet.transmittance(Direction::up(), Normal::up()));
+    }
+
+    #[test]
+    fn films_interfere_per_wavelength() {
+        assert!(close(ThinFilm::new(0.0, 1.33).reflectance(1.0, 532.0), 0.0));
+        // a quarter wave film reflects the most, a half wave film nothing
+        let quarter = 532.0/(4.0*1.33);
+        let film = ThinFilm::new(quarter, 1.33);
+        assert!(film.reflectance(1.0, 532.0) > film.reflectance(1.0, 450.0));
+        assert!(close(ThinFilm::new(2.0*quarter, 1.33).reflectance(1.0, 532.0), 0.0));
+
+        let (r, g, b) = ThinFilm::new(380.0, 1.33).reflectance_color(1.0).rgb();
+        assert!(r != g && g != b);
+    }
+
+    #[test]
+    fn subsurface_profile_is_normalized() {
+        let subsurface = Subsurface::new(Color::gray_scale(1.0), 0.1, 1.3);
+        let steps = 100000;
+        let dr = subsurface.max_radius() / steps as f64;
+        let integral: f64 = (0..steps).map(|i| {
+            let r = (i as f64 + 0.5)*dr;
+            2.0*PI*r*subsurface.profile(r)*dr
+        }).sum();
+        // the profile is cut off at the largest sampled radius
+        assert!((integral - 1.0).abs() < 0.01);
+
+        for _ in 0..1000 {
+            let (radius, angle) = subsurface.sample_disk();
+            assert!((0.0..=subsurface.max_radius()).contains(&radius));
+            assert!((0.0..2.0*PI).contains(&angle));
+        }
+        assert!(close(subsurface.fresnel(1.0), (0.3f64/2.3).powi(2)));
+    }
+}
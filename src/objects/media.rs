@@ -0,0 +1,353 @@
+
+use std::f64::consts::PI;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+
+use crate::math::{Direction, Point, Vector};
+use crate::cg_tools::{Color, Ray, Transformation};
+
+//////////////////
+//Medium
+//////////////////
+pub enum MediumSample {
+    Scatter{t: f64, weight: Color},
+    Pass{weight: Color}
+}
+
+pub trait Medium : Send + Sync + Debug {
+    fn sample(&self, ray: &Ray, t_max: f64) -> MediumSample;
+    fn transmittance(&self, ray: &Ray, t_max: f64) -> Color;
+    fn scattering(&self, point: Point) -> Color;
+    fn phase(&self, incoming: Direction, outgoing: Direction) -> f64;
+    // Called when the instance the medium fills is moved, media without a placement ignore it
+    fn set_transformation(&mut self, _: &Transformation) {}
+    // The parameters the medium was created from, None for media the scene file can not describe
+    fn describe(&self) -> Option<MediumDescription<'_>> { None }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MediumDescription<'a> {
+    Homogeneous{absorption: Color, scattering: Color, g: f64},
+    // a grid read from a file, placed by the transformation of the instance it fills
+    Grid{file: &'a str, extinction: f64, albedo: Color, g: f64}
+}
+
+pub fn henyey_greenstein(g: f64, incoming: Direction, outgoing: Direction) -> f64 {
+    let cos_theta = -incoming.dot(&outgoing);
+    let denom = 1.0 + g*g - 2.0*g*cos_theta;
+    (1.0 - g*g) / (4.0*PI*denom*denom.sqrt())
+}
+
+//////////////////
+//HomogeneousMedium
+//////////////////
+#[derive(Clone, Debug)]
+pub struct HomogeneousMedium {
+    absorption: (f64,f64,f64),
+    scattering: (f64,f64,f64),
+    g: f64
+}
+
+impl HomogeneousMedium {
+    pub fn new(absorption: Color, scattering: Color, g: f64) -> HomogeneousMedium {
+        HomogeneousMedium{absorption: absorption.rgb(), scattering: scattering.rgb(), g}
+    }
+
+    fn extinction(&self) -> (f64,f64,f64) {
+        let (a, s) = (self.absorption, self.scattering);
+        (a.0 + s.0, a.1 + s.1, a.2 + s.2)
+    }
+
+    fn transmittance_rgb(&self, distance: f64) -> (f64,f64,f64) {
+        let e = self.extinction();
+        ((-e.0*distance).exp(), (-e.1*distance).exp(), (-e.2*distance).exp())
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    fn sample(&self, _: &Ray, t_max: f64) -> MediumSample {
+        // distances are sampled with the averaged extinction, the channels are reweighted afterwards
+        let e = self.extinction();
+        let sigma = (e.0 + e.1 + e.2) / 3.0;
+        if sigma <= 0.0 { return MediumSample::Pass{weight: Color::gray_scale(1.0)} }
+
+        let t = -(1.0 - rand::random::<f64>()).ln() / sigma;
+        if t < t_max {
+            let tr = self.transmittance_rgb(t);
+            let pdf = sigma*(-sigma*t).exp();
+            let s = self.scattering;
+            MediumSample::Scatter{t, weight: Color::new_rgb(s.0*tr.0/pdf, s.1*tr.1/pdf, s.2*tr.2/pdf)}
+        }
+        else {
+            let tr = self.transmittance_rgb(t_max);
+            let prob = (-sigma*t_max).exp();
+            MediumSample::Pass{weight: Color::new_rgb(tr.0/prob, tr.1/prob, tr.2/prob)}
+        }
+    }
+
+    fn transmittance(&self, _: &Ray, t_max: f64) -> Color {
+        let (r,g,b) = self.transmittance_rgb(t_max);
+        Color::new_rgb(r,g,b)
+    }
+
+    fn scattering(&self, _: Point) -> Color {
+        let (r,g,b) = self.scattering;
+        Color::new_rgb(r,g,b)
+    }
+
+    fn phase(&self, incoming: Direction, outgoing: Direction) -> f64 {
+        henyey_greenstein(self.g, incoming, outgoing)
+    }
+
+    fn describe(&self) -> Option<MediumDescription<'_>> {
+        let ((ar, ag, ab), (sr, sg, sb)) = (self.absorption, self.scattering);
+        Some(MediumDescription::Homogeneous{absorption: Color::new_rgb(ar, ag, ab), scattering: Color::new_rgb(sr, sg, sb), g: self.g})
+    }
+}
+
+//////////////////
+//GridMedium
+//////////////////
+// Densities on a voxel grid spanning the unit cube, placed in the scene with a transformation
+#[derive(Clone, Debug)]
+pub struct GridMedium {
+    resolution: (usize,usize,usize),
+    densities: Vec<f32>,
+    max_density: f64,
+    transformation: Transformation,
+    extinction: f64,
+    albedo: Color,
+    g: f64,
+    source: Option<String>
+}
+
+impl GridMedium {
+    pub fn new(resolution: (usize,usize,usize), densities: Vec<f32>, transformation: Transformation, extinction: f64, albedo: Color, g: f64) -> GridMedium {
+        assert_eq!(densities.len(), resolution.0*resolution.1*resolution.2, "Density count does not match the grid resolution");
+        let max_density = densities.iter().fold(0.0f32, |acc, d| acc.max(*d)) as f64;
+        GridMedium{resolution, densities, max_density, transformation, extinction, albedo, g, source: None}
+    }
+
+    // Raw grid: three little endian u32 dimensions followed by x-major little endian f32 densities
+    pub fn load(file_path: &str, transformation: Transformation, extinction: f64, albedo: Color, g: f64) -> Result<GridMedium, Error> {
+        let mut bytes = Vec::new();
+        File::open(file_path)?.read_to_end(&mut bytes)?;
+        let read_u32 = |i: usize| bytes.get(i*4..i*4+4).map(|b| u32::from_le_bytes([b[0],b[1],b[2],b[3]]));
+
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("Invalid grid file: {}", file_path));
+        let (nx, ny, nz) = match (read_u32(0), read_u32(1), read_u32(2)) {
+            (Some(x), Some(y), Some(z)) => (x as usize, y as usize, z as usize),
+            _ => return Err(invalid())
+        };
+        // the header of a corrupt file must neither overflow the size nor leave an empty grid
+        let size = nx.checked_mul(ny).and_then(|n| n.checked_mul(nz)).and_then(|n| n.checked_mul(4)).and_then(|n| n.checked_add(12));
+        if nx == 0 || ny == 0 || nz == 0 || size != Some(bytes.len()) { return Err(invalid()) }
+
+        let densities = bytes[12..].chunks(4).map(|b| f32::from_le_bytes([b[0],b[1],b[2],b[3]])).collect();
+        let grid = GridMedium::new((nx,ny,nz), densities, transformation, extinction, albedo, g);
+        Ok(GridMedium{source: Some(file_path.to_string()), ..grid})
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f64 {
+        let (nx, ny, _) = self.resolution;
+        self.densities[x + nx*(y + ny*z)] as f64
+    }
+
+    pub fn density(&self, local: Point) -> f64 {
+        let (nx, ny, nz) = self.resolution;
+        let cell = |coord: f64, n: usize| {
+            let c = (coord*n as f64 - 0.5).max(0.0).min((n - 1) as f64);
+            let i = (c as usize).min(n.saturating_sub(2));
+            (i, (i + 1).min(n - 1), c - i as f64)
+        };
+        let (x0, x1, fx) = cell(local.x, nx);
+        let (y0, y1, fy) = cell(local.y, ny);
+        let (z0, z1, fz) = cell(local.z, nz);
+
+        let lerp = |a: f64, b: f64, f: f64| a + (b - a)*f;
+        let c00 = lerp(self.voxel(x0,y0,z0), self.voxel(x1,y0,z0), fx);
+        let c10 = lerp(self.voxel(x0,y1,z0), self.voxel(x1,y1,z0), fx);
+        let c01 = lerp(self.voxel(x0,y0,z1), self.voxel(x1,y0,z1), fx);
+        let c11 = lerp(self.voxel(x0,y1,z1), self.voxel(x1,y1,z1), fx);
+        lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+    }
+
+    fn local_interval(&self, ray: &Ray, t_max: f64) -> Option<(Point, Vector, f64, f64)> {
+        let origin = self.transformation.inverted()*ray.origin();
+        let direction = self.transformation.inverted()**ray.direction();
+        let (mut t0, mut t1) = (0.0, t_max);
+        for (o, d) in [(origin.x, direction.x), (origin.y, direction.y), (origin.z, direction.z)] {
+            let (mut near, mut far) = ((0.0 - o)/d, (1.0 - o)/d);
+            if near > far { std::mem::swap(&mut near, &mut far) }
+            t0 = if near > t0 { near } else { t0 };
+            t1 = if far < t1 { far } else { t1 };
+        }
+        if t0 < t1 { Some((origin, direction, t0, t1)) } else { None }
+    }
+
+    fn majorant(&self) -> f64 {
+        self.max_density*self.extinction
+    }
+}
+
+impl Medium for GridMedium {
+    fn sample(&self, ray: &Ray, t_max: f64) -> MediumSample {
+        // delta tracking
+        let pass = MediumSample::Pass{weight: Color::gray_scale(1.0)};
+        let (origin, direction, t0, t1) = match self.local_interval(ray, t_max) {
+            Some(interval) => interval,
+            None => return pass
+        };
+        if self.majorant() <= 0.0 { return pass }
+
+        let mut t = t0;
+        loop {
+            t -= (1.0 - rand::random::<f64>()).ln() / self.majorant();
+            if t >= t1 { return pass }
+            let density = self.density(origin + t*direction);
+            if rand::random::<f64>() < density / self.max_density {
+                return MediumSample::Scatter{t, weight: self.albedo};
+            }
+        }
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: f64) -> Color {
+        // ratio tracking
+        let (origin, direction, t0, t1) = match self.local_interval(ray, t_max) {
+            Some(interval) => interval,
+            None => return Color::gray_scale(1.0)
+        };
+        if self.majorant() <= 0.0 { return Color::gray_scale(1.0) }
+
+        let mut transmittance = 1.0;
+        let mut t = t0;
+        loop {
+            t -= (1.0 - rand::random::<f64>()).ln() / self.majorant();
+            if t >= t1 { break }
+            transmittance *= 1.0 - self.density(origin + t*direction) / self.max_density;
+        }
+        Color::gray_scale(transmittance)
+    }
+
+    fn scattering(&self, point: Point) -> Color {
+        let local = self.transformation.inverted()*point;
+        if !(0.0..=1.0).contains(&local.x) || !(0.0..=1.0).contains(&local.y) || !(0.0..=1.0).contains(&local.z) {
+            return Color::black();
+        }
+        self.albedo*(self.density(local)*self.extinction)
+    }
+
+    fn phase(&self, incoming: Direction, outgoing: Direction) -> f64 {
+        henyey_greenstein(self.g, incoming, outgoing)
+    }
+
+    fn set_transformation(&mut self, transformation: &Transformation) {
+        self.transformation = transformation.clone();
+    }
+
+    // only grids read from a file can be described
+    fn describe(&self) -> Option<MediumDescription<'_>> {
+        Some(MediumDescription::Grid{file: self.source.as_deref()?, extinction: self.extinction, albedo: self.albedo, g: self.g})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn homogeneous_media_follow_beer_lambert() {
+        let medium = HomogeneousMedium::new(Color::new_rgb(0.1, 0.2, 0.3), Color::gray_scale(0.1), 0.0);
+        let ray = Ray::new(Point::origin(), Direction::new(0.0, 0.0, 1.0));
+        let (r, g, b) = medium.transmittance(&ray, 2.0).rgb();
+        assert!(close(r, (-0.4f64).exp()) && close(g, (-0.6f64).exp()) && close(b, (-0.8f64).exp()));
+
+        // without extinction everything passes unchanged
+        let clear = HomogeneousMedium::new(Color::black(), Color::black(), 0.0);
+        assert!(matches!(clear.sample(&ray, 10.0), MediumSample::Pass{weight} if weight == Color::gray_scale(1.0)));
+    }
+
+    #[test]
+    fn phase_function_is_normalized() {
+        let outgoing = Direction::new(0.0, 0.0, 1.0);
+        assert!(close(henyey_greenstein(0.0, Direction::new(1.0, 0.0, 0.0), outgoing), 1.0/(4.0*PI)));
+        for g in [-0.5, 0.3, 0.8] {
+            // integrated over the angle between both directions
+            let steps = 20000;
+            let integral: f64 = (0..steps).map(|i| {
+                let theta = (i as f64 + 0.5)*PI/steps as f64;
+                let incoming = Direction::new(theta.sin(), 0.0, -theta.cos());
+                2.0*PI*theta.sin()*henyey_greenstein(g, incoming, outgoing)*PI/steps as f64
+            }).sum();
+            assert!((integral - 1.0).abs() < 1e-3);
+        }
+        // forward scattering keeps the light going in its direction
+        assert!(henyey_greenstein(0.8, outgoing.invert(), outgoing) > henyey_greenstein(0.8, outgoing, outgoing));
+    }
+
+    #[test]
+    fn ratio_tracking_matches_the_density() {
+        // a constant density of 0.5 scaled by an extinction of 2 attenuates like a homogeneous medium of 1
+        let grid = GridMedium::new((2, 2, 2), vec![0.5; 8], Transformation::new().scale_all(2.0), 2.0, Color::gray_scale(1.0), 0.0);
+        let through = Ray::new(Point::new(1.0, 1.0, -1.0), Direction::new(0.0, 0.0, 1.0));
+        let samples = 20000;
+        let average = (0..samples).map(|_| grid.transmittance(&through, 10.0).rgb().0).sum::<f64>() / samples as f64;
+        assert!((average - (-2.0f64).exp()).abs() < 0.02);
+
+        let past = Ray::new(Point::new(3.0, 1.0, -1.0), Direction::new(0.0, 0.0, 1.0));
+        assert_eq!(grid.transmittance(&past, 10.0), Color::gray_scale(1.0));
+        assert_eq!(grid.scattering(Point::new(1.0, 1.0, 1.0)), Color::gray_scale(1.0));
+        assert_eq!(grid.scattering(Point::new(1.0, 1.0, 3.0)), Color::black());
+    }
+
+    fn grid_file(name: &str, dimensions: [u32; 3], densities: &[f32]) -> String {
+        let mut bytes: Vec<u8> = dimensions.iter().flat_map(|n| n.to_le_bytes()).collect();
+        bytes.extend(densities.iter().flat_map(|d| d.to_le_bytes()));
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn load(path: &str) -> Result<GridMedium, Error> {
+        let grid = GridMedium::load(path, Transformation::new(), 1.0, Color::gray_scale(1.0), 0.0);
+        std::fs::remove_file(path).unwrap();
+        grid
+    }
+
+    #[test]
+    fn grid_is_loaded_and_interpolated() {
+        let grid = load(&grid_file("grid_valid.raw", [2, 1, 1], &[0.0, 2.0])).unwrap();
+        assert_eq!(grid.resolution, (2, 1, 1));
+        assert_eq!(grid.max_density, 2.0);
+        assert_eq!(grid.density(Point::new(0.1, 0.5, 0.5)), 0.0);
+        assert_eq!(grid.density(Point::new(0.5, 0.5, 0.5)), 1.0);
+        assert_eq!(grid.density(Point::new(0.9, 0.5, 0.5)), 2.0);
+    }
+
+    #[test]
+    fn corrupt_headers_are_rejected() {
+        assert!(load(&grid_file("grid_truncated.raw", [2, 2, 2], &[1.0; 7])).is_err());
+        assert!(load(&grid_file("grid_empty.raw", [0, 4, 4], &[])).is_err());
+        // the byte count of this header overflows 64 bits
+        assert!(load(&grid_file("grid_overflow.raw", [u32::MAX, u32::MAX, u32::MAX], &[1.0])).is_err());
+        assert!(load(&grid_file("grid_short.raw", [1, 1, 1], &[])).is_err());
+    }
+
+    #[test]
+    fn grid_follows_its_instance() {
+        use std::sync::Arc;
+        use crate::objects::{Instance, Volume};
+        let grid = GridMedium::new((2, 2, 2), vec![1.0; 8], Transformation::new(), 1.0, Color::gray_scale(1.0), 0.0);
+        let mut instance = Instance::new(Arc::new(Volume::new())).with_medium(Box::new(grid));
+        instance.set_transformation(Transformation::new().translate(Vector::new(5.0, 0.0, 0.0)));
+
+        let medium = instance.medium().unwrap();
+        assert_eq!(medium.scattering(Point::new(0.5, 0.5, 0.5)), Color::black());
+        assert_eq!(medium.scattering(Point::new(5.5, 0.5, 0.5)), Color::gray_scale(1.0));
+    }
+}
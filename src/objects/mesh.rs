@@ -1,36 +1,312 @@
+use std::time::Instant;
 
-use super::{Face, Material, Object};
-use crate::cg_tools::{BoundingBox, Transformation, Ray};
-use crate::math::{Point};
+use super::{Material, Object, ObjectDescription, Lambertian};
+use super::faces::{intersect_triangle, triangle_normal};
+use crate::cg_tools::{BoundingBox, Color, Transformation, Ray};
+use crate::math::{Point, Vector, Normal};
 use crate::scene::Intersection;
-use crate::acceleration::{self, AccelerationStructure};
+use crate::acceleration::LinearBVH;
+use crate::statistics;
+
+//////////////////
+//IndexedTriangle
+//////////////////
+// Indices into the shared buffers of a mesh
+#[derive(Copy, Clone, Debug)]
+pub struct IndexedTriangle {
+    pub vertices: [u32; 3],
+    pub normals: Option<[u32; 3]>,
+    pub uvs: Option<[u32; 3]>,
+    pub material: u32
+}
 
 //////////////////
 //Mesh
 //////////////////
+// Triangles are stored in the order of the leaves of the mesh's own BVH, which is the bottom level structure
+// shared by every instance of the mesh. Instances are bounded by the transformed box of the mesh.
 pub struct Mesh {
-    faces : Box<dyn AccelerationStructure>,
-    material : Box<dyn Material>
+    vertices: Vec<Point>,
+    normals: Vec<Normal>,
+    uvs: Vec<(f64, f64)>,
+    triangles: Vec<IndexedTriangle>,
+    bvh: LinearBVH,
+    bbox: BoundingBox,
+    materials : Vec<Box<dyn Material>>,
+    colors: Vec<Color>,
+    double_sided: bool,
+    // file and group the mesh was read from and whether its materials were replaced since
+    source: Option<String>,
+    group: Option<String>,
+    material_replaced: bool
 }
 
 impl Mesh{
-    pub fn new(mut faces: Vec<Box<dyn Face>>, material: Box<dyn Material>) -> Mesh{
-        let instances: Vec<_> = faces.drain(..).map(|a| {
-            super::Instance::new(a.as_object())
-        } ).collect();
-        let acc_structure = acceleration::create_acceleration_structure(instances);
-        Mesh{faces: acc_structure, material}
+    // Triangles reference their material by its index in materials
+    pub fn new(vertices: Vec<Point>, normals: Vec<Normal>, uvs: Vec<(f64, f64)>, triangles: Vec<IndexedTriangle>, materials: Vec<Box<dyn Material>>) -> Mesh{
+        let now = Instant::now();
+        let bounds: Vec<BoundingBox> = triangles.iter().map(|t| triangle_bounds(&vertices, t)).collect();
+        let (bvh, order) = LinearBVH::build(&bounds);
+        statistics::acceleration_build(now.elapsed());
+
+        let triangles = order.iter().map(|i| triangles[*i]).collect();
+        Mesh::with_hierarchy(vertices, normals, uvs, triangles, bvh, materials)
+    }
+
+    // The triangles have to be in the order of the leaves of the given hierarchy, e.g. as returned by triangles()
+    pub fn with_hierarchy(vertices: Vec<Point>, normals: Vec<Normal>, uvs: Vec<(f64, f64)>, triangles: Vec<IndexedTriangle>, bvh: LinearBVH, mut materials: Vec<Box<dyn Material>>) -> Mesh{
+        // material() has to return something even for meshes without faces
+        if materials.is_empty() {
+            materials.push(Box::new(Lambertian::new(Color::gray_scale(1.0))));
+        }
+        assert!(triangles.iter().all(|t| (t.material as usize) < materials.len()), "Triangle references a missing material");
+        let bbox = triangles.iter().fold(BoundingBox::empty(), |acc, t| acc.union(&triangle_bounds(&vertices, t)));
+        Mesh{vertices, normals, uvs, triangles, bvh, bbox, materials, colors: Vec::new(), double_sided: false, source: None, group: None, material_replaced: false}
+    }
+
+    // One color per vertex, the interpolated color tints the material of the faces
+    pub fn with_colors(mut self, colors: Vec<Color>) -> Mesh {
+        assert_eq!(colors.len(), self.vertices.len(), "Mesh needs one color per vertex");
+        self.colors = colors;
+        self
+    }
+
+    pub fn with_source(mut self, file_path: &str) -> Mesh {
+        self.source = Some(file_path.to_string());
+        self
+    }
+
+    // The mesh only holds the faces of this group of its source file
+    pub fn with_group(mut self, name: &str) -> Mesh {
+        self.group = Some(name.to_string());
+        self
+    }
+
+    // Replaces the materials of all faces
+    pub fn set_material(&mut self, material: Box<dyn Material>) {
+        self.materials = vec![material];
+        self.triangles.iter_mut().for_each(|t| t.material = 0);
+        self.material_replaced = true;
     }
+
+    // Back faces are culled unless the mesh is double sided
+    pub fn set_double_sided(&mut self, double_sided: bool) {
+        self.double_sided = double_sided;
+    }
+
+    pub fn vertices(&self) -> &[Point] { &self.vertices }
+    pub fn normals(&self) -> &[Normal] { &self.normals }
+    pub fn uvs(&self) -> &[(f64, f64)] { &self.uvs }
+    pub fn triangles(&self) -> &[IndexedTriangle] { &self.triangles }
+    pub fn hierarchy(&self) -> &LinearBVH { &self.bvh }
+
+    fn triangle_vertices(&self, triangle: &IndexedTriangle) -> [Point; 3] {
+        triangle.vertices.map(|i| self.vertices[i as usize])
+    }
+
+    fn intersect_triangle(&self, index: usize, ray: &Ray) -> Option<Intersection<'_>> {
+        let triangle = &self.triangles[index];
+        let vertices = self.triangle_vertices(triangle);
+        let hit = intersect_triangle(&vertices, self.double_sided, ray);
+        statistics::triangle_intersection(hit.is_some());
+        let (t, u, v, det) = hit?;
+
+        let normals = triangle.normals.map(|n| n.map(|i| self.normals[i as usize]));
+        let mut normal = triangle_normal(&vertices, normals.as_ref(), u, v, det);
+        let point = ray.origin() + t * *ray.direction();
+        let material = self.materials[triangle.material as usize].as_ref();
+
+        let w = 1.0 - u - v;
+        let int = match triangle.uvs {
+            None => Intersection::new(t, point, normal, material),
+            Some(uvs) => {
+                let uvs = uvs.map(|i| self.uvs[i as usize]);
+                let uv = (w*uvs[0].0 + u*uvs[1].0 + v*uvs[2].0, w*uvs[0].1 + u*uvs[1].1 + v*uvs[2].1);
+                if let Some(gradient) = material.bump(uv) {
+                    normal = bump_normal(&vertices, &uvs, normal, gradient);
+                }
+                else if let Some(mapped) = material.normal_map(uv) {
+                    normal = mapped_normal(&vertices, &uvs, normal, mapped);
+                }
+                Intersection::new(t, point, normal, material).with_uv(uv)
+            }
+        };
+        if self.colors.is_empty() { return Some(int) }
+        let [a, b, c] = triangle.vertices.map(|i| self.colors[i as usize]);
+        Some(int.with_color(a*w + b*u + c*v))
+    }
+}
+
+// Derivatives of the position along u and v, None if the texture coordinates of the triangle are degenerate
+fn tangents(vertices: &[Point; 3], uvs: &[(f64, f64); 3]) -> Option<(Vector, Vector)> {
+    let (du02, dv02) = (uvs[0].0 - uvs[2].0, uvs[0].1 - uvs[2].1);
+    let (du12, dv12) = (uvs[1].0 - uvs[2].0, uvs[1].1 - uvs[2].1);
+    let det = du02*dv12 - dv02*du12;
+    if det.abs() < 1e-12 { return None }
+
+    let (dp02, dp12) = (vertices[0] - vertices[2], vertices[1] - vertices[2]);
+    Some(((dv12*dp02 - dv02*dp12)*(1.0/det), (du02*dp12 - du12*dp02)*(1.0/det)))
+}
+
+// Offsets the surface along the normal by the height gradient, the tangents follow from the texture coordinates
+fn bump_normal(vertices: &[Point; 3], uvs: &[(f64, f64); 3], normal: Normal, (dh_du, dh_dv): (f64, f64)) -> Normal {
+    let (dp_du, dp_dv) = match tangents(vertices, uvs) {
+        Some(tangents) => tangents,
+        None => return normal
+    };
+    let bumped = Normal::from((dp_du + dh_du**normal).cross(&(dp_dv + dh_dv**normal)));
+    if bumped.dot(&normal) < 0.0 { bumped.invert() } else { bumped }
+}
+
+// Tangent space normal turned into the frame of the surface, the tangent along u is made perpendicular to the
+// interpolated normal and the bitangent keeps the direction of v
+fn mapped_normal(vertices: &[Point; 3], uvs: &[(f64, f64); 3], normal: Normal, mapped: Vector) -> Normal {
+    let (dp_du, dp_dv) = match tangents(vertices, uvs) {
+        Some(tangents) => tangents,
+        None => return normal
+    };
+    let tangent = dp_du - normal.dot(&dp_du)**normal;
+    if tangent.length() < 1e-12 { return normal }
+    let tangent = tangent.normalize();
+    let bitangent = normal.cross(&tangent);
+    let bitangent = if bitangent.dot(&dp_dv) < 0.0 { bitangent.invert() } else { bitangent };
+    Normal::from(mapped.x*tangent + mapped.y**bitangent + mapped.z**normal)
+}
+
+fn triangle_bounds(vertices: &[Point], triangle: &IndexedTriangle) -> BoundingBox {
+    let [a, b, c] = triangle.vertices.map(|i| vertices[i as usize]);
+    BoundingBox::new(a.min(b).min(c), a.max(b).max(c))
 }
 
 impl Object for Mesh{
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
-        self.faces.intersect(ray)
+        self.bvh.closest_hit(ray, |i, t_max| {
+            self.intersect_triangle(i, &ray.with_t_max(t_max)).map(|int| (int.t(), int))
+        })
+    }
+
+    fn occluded(&self, ray: &Ray) -> bool {
+        self.bvh.any_hit(ray, |i| {
+            let hit = intersect_triangle(&self.triangle_vertices(&self.triangles[i]), self.double_sided, ray).is_some();
+            statistics::triangle_intersection(hit);
+            hit
+        })
     }
 
     fn bounding_box(&self, transformation: &Transformation) -> BoundingBox {
-        self.faces.bounding_box(transformation)
+        self.bbox.transformed(transformation)
+    }
+
+    fn material(&self) -> &dyn Material { self.materials[0].as_ref() }
+
+    // only meshes read from a file can be described
+    fn describe(&self) -> Option<ObjectDescription<'_>> {
+        Some(ObjectDescription::Mesh{
+            file: self.source.as_deref()?,
+            group: self.group.as_deref(),
+            material_replaced: self.material_replaced,
+            double_sided: self.double_sided
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::math::{Direction, Vector};
+    use crate::objects::Instance;
+
+    fn triangle(vertices: [u32; 3], normals: Option<[u32; 3]>) -> IndexedTriangle {
+        IndexedTriangle{vertices, normals, uvs: None, material: 0}
+    }
+
+    // Unit square in the xy plane facing -z, made of two triangles sharing a diagonal
+    fn square(normals: Vec<Normal>) -> Mesh {
+        let vertices = vec![Point::new(0.0, 0.0, 0.0), Point::new(0.0, 1.0, 0.0), Point::new(1.0, 1.0, 0.0), Point::new(1.0, 0.0, 0.0)];
+        let smooth = !normals.is_empty();
+        let triangles = vec![
+            triangle([0, 1, 2], smooth.then_some([0, 1, 2])),
+            triangle([0, 2, 3], smooth.then_some([0, 2, 3]))
+        ];
+        Mesh::new(vertices, normals, Vec::new(), triangles, vec![Box::new(Lambertian::new(Color::gray_scale(1.0)))])
+    }
+
+    fn ray_at(x: f64, y: f64) -> Ray {
+        Ray::new(Point::new(x, y, -1.0), Direction::new(0.0, 0.0, 1.0))
     }
 
-    fn material(&self) -> &dyn Material { self.material.as_ref() }
-}
\ No newline at end of file
+    #[test]
+    fn normal_maps_follow_the_texture_coordinates() {
+        // u runs along x and v along y on a triangle facing -z
+        let vertices = [Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0), Point::new(0.0, 1.0, 0.0)];
+        let uvs = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+        let normal = Normal::new(0.0, 0.0, -1.0);
+        let close = |a: Normal, b: Normal| (*a - *b).length() < 1e-9;
+
+        assert!(close(mapped_normal(&vertices, &uvs, normal, Vector::new(0.0, 0.0, 1.0)), normal));
+        assert!(close(mapped_normal(&vertices, &uvs, normal, Vector::new(1.0, 0.0, 1.0)), Normal::new(1.0, 0.0, -1.0)));
+        assert!(close(mapped_normal(&vertices, &uvs, normal, Vector::new(0.0, 1.0, 1.0)), Normal::new(0.0, 1.0, -1.0)));
+        // without usable texture coordinates the normal is kept
+        assert!(close(mapped_normal(&vertices, &[(0.5, 0.5); 3], normal, Vector::new(1.0, 0.0, 1.0)), normal));
+    }
+
+    #[test]
+    fn shared_vertices_are_stored_once() {
+        let mesh = square(Vec::new());
+        assert_eq!(mesh.vertices().len(), 4);
+        assert_eq!(mesh.triangles().len(), 2);
+        assert_eq!(mesh.hierarchy().nodes().len(), 1);
+    }
+
+    #[test]
+    fn both_triangles_are_hit() {
+        let mesh = square(Vec::new());
+        for (x, y) in [(0.2, 0.7), (0.7, 0.2), (0.5, 0.5)] {
+            let int = mesh.intersect(&ray_at(x, y)).expect("hit");
+            assert!((int.t() - 1.0).abs() < 1e-9);
+            assert!((int.point().x - x).abs() < 1e-9 && (int.point().y - y).abs() < 1e-9);
+            assert!(mesh.occluded(&ray_at(x, y)));
+        }
+        assert!(mesh.intersect(&ray_at(1.2, 0.5)).is_none());
+        assert!(!mesh.occluded(&ray_at(-0.2, 0.5)));
+    }
+
+    #[test]
+    fn instances_share_the_mesh() {
+        let mesh = Arc::new(square(Vec::new()));
+        let moved = Instance::transformed(mesh.clone(), Transformation::new().translate(Vector::new(5.0, 0.0, 0.0)));
+        let placed = Instance::new(mesh.clone());
+
+        assert!(placed.intersect(&ray_at(0.5, 0.5)).is_some() && moved.intersect(&ray_at(0.5, 0.5)).is_none());
+        assert!(moved.intersect(&ray_at(5.5, 0.5)).is_some() && placed.intersect(&ray_at(5.5, 0.5)).is_none());
+        assert!((moved.bounding_box().min().x - 5.0).abs() < 1e-9 && (moved.bounding_box().max().x - 6.0).abs() < 1e-9);
+        assert_eq!(Arc::strong_count(&mesh), 3);
+    }
+
+    #[test]
+    fn back_faces_are_culled_unless_double_sided() {
+        let mut mesh = square(Vec::new());
+        let from_behind = Ray::new(Point::new(0.5, 0.5, 1.0), Direction::new(0.0, 0.0, -1.0));
+        assert!(mesh.intersect(&from_behind).is_none());
+        mesh.set_double_sided(true);
+        assert!(mesh.intersect(&from_behind).is_some());
+    }
+
+    #[test]
+    fn meshes_without_materials_get_a_default() {
+        let mesh = Mesh::new(Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        assert_ne!(mesh.material().brdf(Direction::up(), Direction::up()).rgb(), (0.0, 0.0, 0.0));
+        assert!(mesh.intersect(&ray_at(0.5, 0.5)).is_none());
+    }
+
+    #[test]
+    fn vertex_normals_are_interpolated() {
+        let normals = vec![Normal::new(-1.0, 0.0, -1.0), Normal::new(-1.0, 0.0, -1.0), Normal::new(1.0, 0.0, -1.0), Normal::new(1.0, 0.0, -1.0)];
+        let mesh = square(normals);
+        let int = mesh.intersect(&ray_at(0.5, 0.25)).expect("hit");
+        assert!(int.normal().x.abs() < 1e-9 && int.normal().z < 0.0);
+        let int = mesh.intersect(&ray_at(0.9, 0.5)).expect("hit");
+        assert!(int.normal().x > 0.0);
+    }
+}
@@ -0,0 +1,284 @@
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use super::{IndexedTriangle, Mesh};
+use crate::acceleration::{BVHNode, LinearBVH};
+use crate::cg_tools::BoundingBox;
+use crate::math::{Normal, Point};
+use crate::settings;
+
+const MAGIC: &[u8; 4] = b"MSHC";
+const VERSION: u32 = 4;
+
+#[derive(Copy, Clone, Debug)]
+pub enum MeshCaching {
+    Disabled,
+    NextToSource,
+    Directory(&'static str)
+}
+
+// Buffers of a mesh, if there is a hierarchy the triangles are in the order of its leaves
+pub struct MeshData {
+    pub vertices: Vec<Point>,
+    pub normals: Vec<Normal>,
+    pub uvs: Vec<(f64, f64)>,
+    pub triangles: Vec<IndexedTriangle>,
+    pub bvh: Option<LinearBVH>,
+    pub material_libraries: Vec<String>,
+    pub material_names: Vec<String>
+}
+
+pub fn source_hash(bytes: &[u8]) -> u64 {
+    // FNV-1a
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn cache_path(source_path: &str, hash: u64) -> Option<PathBuf> {
+    match settings::get().mesh_caching {
+        MeshCaching::Disabled => None,
+        MeshCaching::NextToSource => Some(PathBuf::from(format!("{}.meshcache", source_path))),
+        MeshCaching::Directory(dir) => Some(Path::new(dir).join(format!("{:016x}.meshcache", hash)))
+    }
+}
+
+pub fn load(source_path: &str, hash: u64) -> Option<MeshData> {
+    let path = cache_path(source_path, hash)?;
+    let bytes = fs::read(&path).ok()?;
+    let data = decode(&bytes, hash);
+    if data.is_none() {
+        println!("Ignoring outdated or invalid mesh cache: {}", path.display());
+    }
+    data
+}
+
+pub fn store(source_path: &str, hash: u64, mesh: &Mesh, material_libraries: &[String], material_names: &[String]) {
+    let path = match cache_path(source_path, hash) {
+        Some(path) => path,
+        None => return
+    };
+    let write = |path: &Path| -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, encode(mesh, material_libraries, material_names, hash))
+    };
+    if let Err(err) = write(&path) {
+        println!("Could not write mesh cache {}: {}", path.display(), err);
+    }
+}
+
+// Materials are not stored, only the names needed to look them up again
+fn encode(mesh: &Mesh, material_libraries: &[String], material_names: &[String], hash: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&hash.to_le_bytes());
+    // computed normals depend on the crease angle
+    out.extend_from_slice(&settings::get().crease_angle.to_le_bytes());
+
+    let write_u32 = |out: &mut Vec<u8>, value: usize| out.extend_from_slice(&(value as u32).to_le_bytes());
+    let write_f64 = |out: &mut Vec<u8>, value: f64| out.extend_from_slice(&value.to_le_bytes());
+    let write_point = |out: &mut Vec<u8>, point: Point| {
+        for c in [point.x, point.y, point.z] { write_f64(out, c); }
+    };
+    let write_indices = |out: &mut Vec<u8>, indices: Option<[u32; 3]>| match indices {
+        None => out.push(0),
+        Some(indices) => {
+            out.push(1);
+            for i in indices { write_u32(out, i as usize); }
+        }
+    };
+
+    let write_strings = |out: &mut Vec<u8>, strings: &[String]| {
+        write_u32(out, strings.len());
+        for string in strings {
+            write_u32(out, string.len());
+            out.extend_from_slice(string.as_bytes());
+        }
+    };
+    write_strings(&mut out, material_libraries);
+    write_strings(&mut out, material_names);
+
+    write_u32(&mut out, mesh.vertices().len());
+    for vertex in mesh.vertices() {
+        write_point(&mut out, *vertex);
+    }
+    write_u32(&mut out, mesh.normals().len());
+    for normal in mesh.normals() {
+        write_point(&mut out, Point::new(normal.x, normal.y, normal.z));
+    }
+    write_u32(&mut out, mesh.uvs().len());
+    for (u, v) in mesh.uvs() {
+        write_f64(&mut out, *u);
+        write_f64(&mut out, *v);
+    }
+    write_u32(&mut out, mesh.triangles().len());
+    for triangle in mesh.triangles() {
+        for i in triangle.vertices { write_u32(&mut out, i as usize); }
+        write_indices(&mut out, triangle.normals);
+        write_indices(&mut out, triangle.uvs);
+        write_u32(&mut out, triangle.material as usize);
+    }
+
+    let nodes = mesh.hierarchy().nodes();
+    write_u32(&mut out, nodes.len());
+    for node in nodes {
+        write_point(&mut out, node.bbox().min());
+        write_point(&mut out, node.bbox().max());
+        if node.is_leaf() {
+            out.push(1);
+            write_u32(&mut out, node.primitives().start);
+            write_u32(&mut out, node.primitives().len());
+        }
+        else {
+            out.push(0);
+            write_u32(&mut out, node.second_child());
+            write_u32(&mut out, node.axis());
+        }
+    }
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.position..self.position + count)?;
+        self.position += count;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> { self.take(1).map(|b| b[0]) }
+    fn u32(&mut self) -> Option<usize> { self.take(4).map(|b| u32::from_le_bytes([b[0],b[1],b[2],b[3]]) as usize) }
+    fn u64(&mut self) -> Option<u64> { self.take(8).map(|b| u64::from_le_bytes([b[0],b[1],b[2],b[3],b[4],b[5],b[6],b[7]])) }
+    fn f64(&mut self) -> Option<f64> { self.u64().map(f64::from_bits) }
+    fn point(&mut self) -> Option<Point> { Some(Point::new(self.f64()?, self.f64()?, self.f64()?)) }
+}
+
+fn decode(bytes: &[u8], hash: u64) -> Option<MeshData> {
+    let mut reader = Reader{bytes, position: 0};
+    if reader.take(4)? != MAGIC || reader.u32()? != VERSION as usize || reader.u64()? != hash || reader.f64()? != settings::get().crease_angle {
+        return None;
+    }
+
+    // counts are checked against the remaining bytes so a corrupt file can not trigger huge allocations
+    let mut strings = || -> Option<Vec<String>> {
+        let count = reader.u32()?;
+        if count*4 > bytes.len() { return None }
+        (0..count).map(|_| {
+            let len = reader.u32()?;
+            String::from_utf8(reader.take(len)?.to_vec()).ok()
+        }).collect()
+    };
+    let material_libraries = strings()?;
+    let material_names = strings()?;
+
+    let vertex_count = reader.u32()?;
+    if vertex_count*24 > bytes.len() { return None }
+    let vertices = (0..vertex_count).map(|_| reader.point()).collect::<Option<Vec<Point>>>()?;
+
+    let normal_count = reader.u32()?;
+    if normal_count*24 > bytes.len() { return None }
+    let normals = (0..normal_count).map(|_| reader.point().map(|p| Normal::new(p.x, p.y, p.z))).collect::<Option<Vec<Normal>>>()?;
+
+    let uv_count = reader.u32()?;
+    if uv_count*16 > bytes.len() { return None }
+    let uvs = (0..uv_count).map(|_| Some((reader.f64()?, reader.f64()?))).collect::<Option<Vec<(f64, f64)>>>()?;
+
+    let triangle_count = reader.u32()?;
+    if triangle_count*18 > bytes.len() { return None }
+    let triangles = (0..triangle_count).map(|_| {
+        let mut indices = |count: usize| -> Option<[u32; 3]> {
+            let mut index = || reader.u32().filter(|i| *i < count).map(|i| i as u32);
+            Some([index()?, index()?, index()?])
+        };
+        let vertices = indices(vertex_count)?;
+        let mut optional_indices = |count: usize| -> Option<Option<[u32; 3]>> {
+            match reader.u8()? {
+                0 => Some(None),
+                _ => {
+                    let mut index = || reader.u32().filter(|i| *i < count).map(|i| i as u32);
+                    Some(Some([index()?, index()?, index()?]))
+                }
+            }
+        };
+        let normals = optional_indices(normal_count)?;
+        let uvs = optional_indices(uv_count)?;
+        let material = reader.u32().filter(|i| *i < material_names.len())? as u32;
+        Some(IndexedTriangle{vertices, normals, uvs, material})
+    }).collect::<Option<Vec<IndexedTriangle>>>()?;
+
+    let node_count = reader.u32()?;
+    if node_count*57 > bytes.len() { return None }
+    let nodes = (0..node_count).map(|_| {
+        let bbox = BoundingBox::new(reader.point()?, reader.point()?);
+        let is_leaf = reader.u8()? == 1;
+        let (first, second) = (reader.u32()?, reader.u32()?);
+        Some(if is_leaf { BVHNode::leaf(bbox, first..first + second) } else { BVHNode::interior(bbox, first, second) })
+    }).collect::<Option<Vec<BVHNode>>>()?;
+    let bvh = LinearBVH::from_nodes(nodes, triangle_count)?;
+
+    if reader.position != bytes.len() { return None }
+    Some(MeshData{vertices, normals, uvs, triangles, bvh: Some(bvh), material_libraries, material_names})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cg_tools::Color;
+    use crate::objects::{Lambertian, Material};
+
+    fn mesh() -> Mesh {
+        let vertices = vec![Point::origin(), Point::new(1.0, 0.0, 0.0), Point::new(0.0, 1.0, 0.0), Point::new(1.0, 1.0, 0.5)];
+        let normals = vec![Normal::new(0.0, 0.0, 1.0)];
+        let uvs = vec![(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+        let triangles = vec![
+            IndexedTriangle{vertices: [0, 1, 2], normals: Some([0, 0, 0]), uvs: Some([0, 1, 2]), material: 0},
+            IndexedTriangle{vertices: [1, 3, 2], normals: None, uvs: None, material: 1}
+        ];
+        let materials: Vec<Box<dyn Material>> = vec![
+            Box::new(Lambertian::new(Color::gray_scale(1.0))), Box::new(Lambertian::new(Color::gray_scale(0.5)))
+        ];
+        Mesh::new(vertices, normals, uvs, triangles, materials)
+    }
+
+    #[test]
+    fn encoded_meshes_are_decoded() {
+        let (libraries, names) = (vec!["scene.mtl".to_string()], vec!["white".to_string(), "gray".to_string()]);
+        let mesh = mesh();
+        let data = decode(&encode(&mesh, &libraries, &names, 42), 42).unwrap();
+
+        assert_eq!(data.vertices, mesh.vertices());
+        assert_eq!(data.normals, mesh.normals());
+        assert_eq!(data.uvs, mesh.uvs());
+        assert_eq!((data.material_libraries, data.material_names), (libraries, names));
+        for (decoded, original) in data.triangles.iter().zip(mesh.triangles()) {
+            assert_eq!((decoded.vertices, decoded.normals, decoded.uvs, decoded.material),
+                (original.vertices, original.normals, original.uvs, original.material));
+        }
+        assert_eq!(data.bvh.unwrap().nodes().len(), mesh.hierarchy().nodes().len());
+    }
+
+    #[test]
+    fn outdated_or_broken_caches_are_rejected() {
+        let names = vec!["white".to_string(), "gray".to_string()];
+        let bytes = encode(&mesh(), &[], &names, 42);
+        assert!(decode(&bytes, 43).is_none());
+        assert!(decode(&bytes[..bytes.len() - 1], 42).is_none());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(decode(&trailing, 42).is_none());
+        // the second triangle refers to a material that is no longer named
+        assert!(decode(&encode(&mesh(), &[], &names[..1], 42), 42).is_none());
+    }
+
+    #[test]
+    fn hash_changes_with_the_source() {
+        assert_eq!(source_hash(b""), 0xcbf29ce484222325);
+        assert_ne!(source_hash(b"v 0 0 0"), source_hash(b"v 0 0 1"));
+    }
+}
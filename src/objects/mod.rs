@@ -1,19 +1,33 @@
 mod faces;
+mod gltf_import;
+mod import_error;
+mod json;
 mod lights;
 mod materials;
+mod media;
 mod mesh;
+mod mesh_cache;
+mod mtl_import;
 mod obj_import;
+mod ply_import;
 mod primitives;
+mod stl_import;
 
-pub use self::faces::{Face, Triangle, Rectangle};
-pub use self::lights::{Light,PointLight,SurfaceLight};
-pub use self::materials::{Material,Lambertian};
-pub use self::mesh::{Mesh};
-pub use self::obj_import::{parse_obj};
+pub use self::faces::{Triangle, Rectangle};
+pub use self::gltf_import::{GltfScene, parse_gltf};
+pub use self::import_error::{ImportError};
+pub use self::lights::{Light,LightDescription,PointLight,DistantLight,SurfaceLight,SpotLight};
+pub use self::materials::{Material,MaterialDescription,Lambertian,Phong,MetallicRoughness,Subsurface,ThinDielectric,ThinFilm,Transparent};
+pub use self::media::{Medium,MediumDescription,MediumSample,HomogeneousMedium,GridMedium};
+pub use self::mesh::{Mesh, IndexedTriangle};
+pub use self::mesh_cache::{MeshCaching};
+pub use self::obj_import::{parse_obj, parse_obj_groups};
+pub use self::ply_import::{parse_ply};
 pub use self::primitives::*;
+pub use self::stl_import::{parse_stl};
 
 use std::sync::Arc;
-use crate::math::Direction;
+use crate::math::{Direction, Normal, Point};
 use crate::cg_tools::{Ray, Transformation, BoundingBox, Color};
 use crate::scene::Intersection;
 use crate::statistics;
@@ -22,31 +36,60 @@ use crate::settings;
 //Object
 pub trait Object : Send + Sync {
     fn intersect(&self, ray: &Ray) -> Option<Intersection>;
+    fn occluded(&self, ray: &Ray) -> bool { self.intersect(ray).is_some() }
     fn bounding_box(&self, transformation: &Transformation) -> BoundingBox;
     fn material(&self) -> &dyn Material;
+    // The parameters the object was created from, None for objects the scene file can not describe
+    fn describe(&self) -> Option<ObjectDescription<'_>> { None }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ObjectDescription<'a> {
+    Sphere,
+    Plane{point: Point, normal: Normal, double_sided: bool},
+    Box{min: Point, max: Point},
+    Triangle{vertices: [Point; 3], normals: Option<[Normal; 3]>, double_sided: bool},
+    Rectangle{points: [Point; 4], double_sided: bool},
+    Volume,
+    // a mesh read from a file, optionally a single group of it, and whether the materials of the file were replaced
+    Mesh{file: &'a str, group: Option<&'a str>, material_replaced: bool, double_sided: bool}
 }
 
 //Instance
 pub struct Instance {
     object: Arc<dyn Object>,
     transformation: Transformation,
-    bbox: BoundingBox
+    bbox: BoundingBox,
+    medium: Option<Box<dyn Medium>>
 }
 
 impl Instance {
     pub fn new(object: Arc<dyn Object>) -> Instance {
         let transformation = Transformation::new();
         let bbox = object.bounding_box(&transformation);
-        Instance{object, transformation, bbox}
+        Instance{object, transformation, bbox, medium: None}
     }
 
     pub fn transformed(object: Arc<dyn Object>, transformation: Transformation) -> Instance {
         let bbox = object.bounding_box(&transformation);
-        Instance{object, transformation, bbox}
+        Instance{object, transformation, bbox, medium: None}
+    }
+
+    pub fn with_medium(mut self, medium: Box<dyn Medium>) -> Instance {
+        self.medium = Some(medium);
+        self
+    }
+
+    fn transform_ray(&self, ray: &Ray) -> Ray {
+        // distances along the object space ray scale with the length of the transformed direction
+        let direction = self.transformation.inverted()**ray.direction();
+        let scale = direction.length();
+        Ray::new(self.transformation.inverted()*ray.origin(), Direction::from(direction))
+            .with_interval(ray.t_min()*scale, ray.t_max()*scale)
     }
 
     pub fn intersect(&self, ray: &Ray) -> Option<Intersection> {
-        let transformed_ray = Ray::new(self.transformation.inverted()*ray.origin(), self.transformation.inverted()*ray.direction() );
+        let transformed_ray = self.transform_ray(ray);
         let intersect = match settings::get().render_mode {
             settings::RenderMode::BoundingBox => {
                 let int = self.bbox.intersect(&ray);
@@ -55,7 +98,7 @@ impl Instance {
                     Some((t, point, normal)) => {Some(Intersection::new(t, point, normal, &BBOX_MATERIAL))},
                 }
             },
-            _ => self.object.intersect(&transformed_ray)
+            _ => statistics::bottom_level(|| self.object.intersect(&transformed_ray))
         };
         match intersect {
             None => {
@@ -64,15 +107,34 @@ impl Instance {
             },
             Some(int) => {
                 statistics::object_intersection(true);
-                Some(int.transform(&self.transformation, &ray))
+                Some(int.transform(&self.transformation, &ray).with_instance(self))
             }
         }
     }
 
+    pub fn occluded(&self, ray: &Ray) -> bool {
+        let occluded = statistics::bottom_level(|| self.object.occluded(&self.transform_ray(ray)));
+        statistics::object_intersection(occluded);
+        occluded
+    }
+
+    pub fn object(&self) -> &dyn Object{
+        self.object.as_ref()
+    }
+
     pub fn transformation(&self) -> &Transformation{
         &self.transformation
     }
 
+    pub fn set_transformation(&mut self, transformation: Transformation) {
+        self.bbox = self.object.bounding_box(&transformation);
+        // a grid medium is placed by the transformation of the instance it fills
+        if let Some(medium) = self.medium.as_mut() {
+            medium.set_transformation(&transformation);
+        }
+        self.transformation = transformation;
+    }
+
     pub fn bounding_box(&self) -> &BoundingBox{
         &self.bbox
     }
@@ -80,6 +142,10 @@ impl Instance {
     pub fn material(&self) -> &dyn Material{
         self.object.material()
     }
+
+    pub fn medium(&self) -> Option<&dyn Medium>{
+        self.medium.as_deref()
+    }
 }
 
 #[derive(Clone, Debug)]
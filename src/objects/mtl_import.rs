@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, BufReader, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::{Material, Phong, ThinDielectric};
+use crate::cg_tools::{Color, Texture};
+
+const DEFAULT_DIFFUSE: f64 = 0.8;
+
+pub type NamedMaterial = (String, Box<dyn Material>);
+
+// Material description as found in the file, converted once the next material starts
+struct MtlEntry {
+    name: String,
+    diffuse: Color,
+    specular: Color,
+    exponent: f64,
+    emission: Color,
+    ior: f64,
+    dissolve: f64,
+    illumination: u32,
+    diffuse_map: Option<String>,
+    bump_map: Option<(String, f64)>
+}
+
+impl MtlEntry {
+    fn new(name: &str) -> MtlEntry {
+        MtlEntry{name: name.to_string(), diffuse: Color::gray_scale(DEFAULT_DIFFUSE), specular: Color::black(), exponent: 0.0, emission: Color::black(),
+            ior: 1.5, dissolve: 1.0, illumination: 2, diffuse_map: None, bump_map: None}
+    }
+
+    fn into_material(self, directory: &Path, textures: &mut HashMap<PathBuf, Option<Arc<Texture>>>) -> Box<dyn Material> {
+        // illumination models with glass enabled, light is reflected and transmitted by the surface
+        if let 4 | 6 | 7 | 9 = self.illumination {
+            return Box::new(ThinDielectric::new(self.ior, Color::gray_scale(1.0)));
+        }
+
+        // a map that can not be loaded falls back to Kd, unless exporters left Kd black because the map carries the color
+        let diffuse_map = self.diffuse_map.map(|file| load_texture(directory, &file, textures));
+        let diffuse = match diffuse_map {
+            Some(None) if self.diffuse.rgb() == (0.0, 0.0, 0.0) => Color::gray_scale(DEFAULT_DIFFUSE),
+            _ => self.diffuse
+        };
+        let mut material = Phong::new(diffuse, self.specular, self.exponent)
+            .with_emission(self.emission)
+            .with_dissolve(self.dissolve);
+        if let 3 | 5 = self.illumination {
+            material = material.with_mirror();
+        }
+        if let Some(texture) = diffuse_map.flatten() {
+            material = material.with_diffuse_map(texture);
+        }
+        if let Some((texture, scale)) = self.bump_map.and_then(|(file, scale)| load_texture(directory, &file, textures).map(|t| (t, scale))) {
+            material = material.with_bump_map(texture, scale);
+        }
+        Box::new(material)
+    }
+}
+
+pub fn parse_mtl(file_path: &str) -> Result<Vec<NamedMaterial>, Error> {
+    let file = File::open(file_path)?;
+    let directory = Path::new(file_path).parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut textures = HashMap::new();
+    let mut materials = Vec::new();
+    let mut current: Option<MtlEntry> = None;
+
+    for line_result in BufReader::new(file).lines() {
+        let line = line_result?;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let (keyword, args) = match tokens.split_first() {
+            Some((keyword, args)) => (*keyword, args),
+            None => continue
+        };
+        let number = |i: usize| args.get(i).and_then(|s| s.parse::<f64>().ok());
+        let color = || match (number(0), number(1), number(2)) {
+            (Some(r), Some(g), Some(b)) => Some(Color::new_rgb(r, g, b)),
+            (Some(v), None, None) => Some(Color::gray_scale(v)),
+            _ => None
+        };
+
+        if keyword == "newmtl" {
+            if let Some(entry) = current.take() {
+                materials.push((entry.name.clone(), entry.into_material(&directory, &mut textures)));
+            }
+            current = Some(MtlEntry::new(&args.join(" ")));
+            continue;
+        }
+        let entry = match current.as_mut() {
+            Some(entry) => entry,
+            None => continue
+        };
+        match keyword {
+            "Kd" => entry.diffuse = color().unwrap_or(entry.diffuse),
+            "Ks" => entry.specular = color().unwrap_or(entry.specular),
+            "Ke" => entry.emission = color().unwrap_or(entry.emission),
+            "Ns" => entry.exponent = number(0).unwrap_or(entry.exponent),
+            "Ni" => entry.ior = number(0).unwrap_or(entry.ior),
+            "d" => entry.dissolve = number(0).unwrap_or(entry.dissolve),
+            "Tr" => entry.dissolve = number(0).map_or(entry.dissolve, |tr| 1.0 - tr),
+            "illum" => entry.illumination = number(0).map_or(entry.illumination, |i| i as u32),
+            "map_Kd" => entry.diffuse_map = Some(texture_file(args).0),
+            "map_Bump" | "map_bump" | "bump" => entry.bump_map = Some(texture_file(args)),
+            _ => ()
+        }
+    }
+    if let Some(entry) = current.take() {
+        materials.push((entry.name.clone(), entry.into_material(&directory, &mut textures)));
+    }
+    Ok(materials)
+}
+
+// Skips the options in front of the file name, returns the name together with the bump multiplier
+fn texture_file(args: &[&str]) -> (String, f64) {
+    let mut bump_scale = 1.0;
+    let mut i = 0;
+    while i < args.len() && args[i].starts_with('-') {
+        let arg_count = match args[i] {
+            "-o" | "-s" | "-t" => 3,
+            "-mm" => 2,
+            _ => 1
+        };
+        if args[i] == "-bm" {
+            bump_scale = args.get(i + 1).and_then(|s| s.parse().ok()).unwrap_or(bump_scale);
+        }
+        i += 1 + arg_count;
+    }
+    (args[i.min(args.len())..].join(" "), bump_scale)
+}
+
+// Paths are often absolute paths from the exporting machine, the bare file name next to the MTL file is tried as well
+fn load_texture(directory: &Path, file: &str, textures: &mut HashMap<PathBuf, Option<Arc<Texture>>>) -> Option<Arc<Texture>> {
+    let file = file.replace('\\', "/");
+    let path = directory.join(&file);
+    let path = if path.exists() { path } else {
+        let file_name = Path::new(&file).file_name().map(|name| directory.join(name));
+        file_name.filter(|path| path.exists()).unwrap_or(path)
+    };
+
+    textures.entry(path.clone()).or_insert_with(|| {
+        match Texture::load(&path.to_string_lossy()) {
+            Ok(texture) => Some(Arc::new(texture)),
+            Err(err) => {
+                println!("Could not load texture: {}", err);
+                None
+            }
+        }
+    }).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::{Direction, Normal};
+    use crate::objects::MaterialDescription;
+
+    fn parse(name: &str, content: &str) -> Vec<NamedMaterial> {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, content).unwrap();
+        let materials = parse_mtl(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        materials
+    }
+
+    fn reflectance(material: &dyn Material) -> (f64, f64, f64) {
+        material.brdf(Direction::up(), Direction::up()).rgb()
+    }
+
+    #[test]
+    fn missing_maps_fall_back_to_the_diffuse_color() {
+        let materials = parse("fallback.mtl", "newmtl textured\nKd 0 0 0\nmap_Kd C:\\textures\\missing.jpg\n\
+            newmtl red\nKd 1 0 0\nmap_Kd missing.jpg\n\
+            newmtl black\nKd 0 0 0\n");
+        let names: Vec<&str> = materials.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["textured", "red", "black"]);
+
+        let (r, g, b) = reflectance(materials[0].1.as_ref());
+        assert!(r > 0.0 && r == g && g == b);
+        let (r, g, b) = reflectance(materials[1].1.as_ref());
+        assert!(r > 0.0 && g == 0.0 && b == 0.0);
+        assert_eq!(reflectance(materials[2].1.as_ref()), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn png_and_jpeg_maps_are_found_next_to_the_file() {
+        // exporters often write absolute paths of the machine the model was made on
+        let red = image::RgbImage::from_pixel(4, 4, image::Rgb([255, 0, 0]));
+        red.save(std::env::temp_dir().join("mtl_red.png")).unwrap();
+        red.save(std::env::temp_dir().join("mtl_red.jpg")).unwrap();
+        let materials = parse("maps.mtl", "newmtl png\nKd 0 0 0\nmap_Kd C:\\Users\\artist\\mtl_red.png\n\
+            newmtl jpeg\nKd 0 0 0\nmap_Kd mtl_red.jpg\n");
+
+        for (_, material) in &materials {
+            let (r, g, b) = material.surface_brdf(Direction::up(), Direction::up(), Normal::new(0.0, 1.0, 0.0), Some((0.5, 0.5))).rgb();
+            assert!(r > 0.1 && g < 0.01 && b < 0.01, "{:?}", (r, g, b));
+        }
+    }
+
+    #[test]
+    fn illumination_models_and_dissolve_are_applied() {
+        let materials = parse("illumination.mtl", "newmtl glass\nillum 7\n\
+            newmtl mirror\nillum 3\nd 0.25\n\
+            newmtl transparency\nTr 0.75\n\
+            newmtl overflow\nd 1.5\n");
+        assert!(matches!(materials[0].1.describe(), Some(MaterialDescription::ThinDielectric{ior, ..}) if ior == 1.5));
+        let dissolve = |i: usize| match materials[i].1.describe() {
+            Some(MaterialDescription::Phong{dissolve, mirror, ..}) => (dissolve, mirror),
+            other => panic!("{:?}", other)
+        };
+        assert_eq!(dissolve(1), (0.25, true));
+        assert_eq!(dissolve(2), (0.25, false));
+        assert_eq!(dissolve(3), (1.0, false));
+    }
+
+    #[test]
+    fn texture_options_are_skipped() {
+        assert_eq!(texture_file(&["-bm", "0.5", "-o", "1", "2", "3", "bump", "map.bmp"]), ("bump map.bmp".to_string(), 0.5));
+        assert_eq!(texture_file(&["-clamp", "on", "color.bmp"]), ("color.bmp".to_string(), 1.0));
+    }
+}
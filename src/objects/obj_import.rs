@@ -1,54 +1,489 @@
-
-use std::fs::File;
-use std::io::{Error, BufReader, BufRead};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
 use std::f64;
 
-use super::{Rectangle, Triangle, Mesh, Face, Lambertian};
-use crate::math::{Point};
+use super::{Mesh, IndexedTriangle, Material, Lambertian, ImportError};
+use super::mesh_cache::{self, MeshData};
+use super::mtl_import::parse_mtl;
+use crate::math::{Point, Vector, Normal};
 use crate::cg_tools::{Color};
+use crate::settings;
+
+pub fn parse_obj(file_path: &str) -> Result<Mesh, ImportError> {
+    let bytes = fs::read(file_path)?;
+    let hash = mesh_cache::source_hash(&bytes);
+
+    let (data, cached) = match mesh_cache::load(file_path, hash) {
+        Some(data) => {
+            println!("Loaded mesh cache for: {}", file_path);
+            (data, true)
+        },
+        None => (read_obj(file_path, &bytes)?.data, false)
+    };
+
+    println!("Imported mesh: {}", file_path);
+    println!("Amount of faces: {}", data.triangles.len());
+    let MeshData{vertices, normals, uvs, triangles, bvh, material_libraries, material_names} = data;
+    let materials = load_materials(file_path, &material_libraries, &material_names);
+    let mesh = match bvh {
+        Some(bvh) => Mesh::with_hierarchy(vertices, normals, uvs, triangles, bvh, materials),
+        None => Mesh::new(vertices, normals, uvs, triangles, materials)
+    };
+    if !cached {
+        mesh_cache::store(file_path, hash, &mesh, &material_libraries, &material_names);
+    }
+    Ok( mesh.with_source(file_path) )
+}
+
+// One mesh per object or group of the file, faces in front of the first o or g statement form the unnamed group.
+// The split meshes are not cached.
+pub fn parse_obj_groups(file_path: &str) -> Result<Vec<(String, Mesh)>, ImportError> {
+    let bytes = fs::read(file_path)?;
+    let ObjData{data, group_names, triangle_groups} = read_obj(file_path, &bytes)?;
+    println!("Imported mesh: {}", file_path);
+    println!("Amount of faces: {}", data.triangles.len());
+
+    let meshes = group_names.into_iter().enumerate().filter_map(|(group, name)| {
+        let triangles: Vec<&IndexedTriangle> = data.triangles.iter().zip(&triangle_groups)
+            .filter(|(_, g)| **g as usize == group).map(|(t, _)| t).collect();
+        if triangles.is_empty() { return None }
+
+        // only the entries of the shared buffers used by the group are copied
+        let mut vertices = Compacted::default();
+        let mut normals = Compacted::default();
+        let mut uvs = Compacted::default();
+        let mut materials = Compacted::default();
+        let triangles = triangles.into_iter().map(|t| IndexedTriangle{
+            vertices: t.vertices.map(|i| vertices.index(i)),
+            normals: t.normals.map(|n| n.map(|i| normals.index(i))),
+            uvs: t.uvs.map(|uv| uv.map(|i| uvs.index(i))),
+            material: materials.index(t.material)
+        }).collect();
+        let material_names: Vec<String> = materials.order.iter().map(|i| data.material_names[*i as usize].clone()).collect();
+
+        let mesh = Mesh::new(
+            vertices.order.iter().map(|i| data.vertices[*i as usize]).collect(),
+            normals.order.iter().map(|i| data.normals[*i as usize]).collect(),
+            uvs.order.iter().map(|i| data.uvs[*i as usize]).collect(),
+            triangles,
+            load_materials(file_path, &data.material_libraries, &material_names)
+        ).with_source(file_path).with_group(&name);
+        Some((name, mesh))
+    }).collect();
+    Ok(meshes)
+}
+
+// Maps indices of a shared buffer to indices of a compact copy in order of first use
+#[derive(Default)]
+struct Compacted {
+    indices: HashMap<u32, u32>,
+    order: Vec<u32>
+}
+
+impl Compacted {
+    fn index(&mut self, old: u32) -> u32 {
+        let order = &mut self.order;
+        *self.indices.entry(old).or_insert_with(|| {
+            order.push(old);
+            (order.len() - 1) as u32
+        })
+    }
+}
+
+// Looks up the used materials in the libraries next to the OBJ file, faces without material are light gray
+fn load_materials(file_path: &str, libraries: &[String], names: &[String]) -> Vec<Box<dyn Material>> {
+    let directory = Path::new(file_path).parent().unwrap_or(Path::new(""));
+    let mut library_materials: HashMap<String, Box<dyn Material>> = HashMap::new();
+    for library in libraries {
+        let library_path = directory.join(library);
+        match parse_mtl(&library_path.to_string_lossy()) {
+            Ok(materials) => library_materials.extend(materials),
+            Err(err) => println!("Could not read material library {}: {}", library_path.display(), err)
+        }
+    }
 
-pub fn parse_obj(file_path: &str) -> Result<Mesh,Error> {
-    let file = File::open(file_path)?;
-    let mut vertices: Vec<Vec<String>> = vec![];
-    let mut faces: Vec<Vec<String>> = vec![];
-
-    for line_result in BufReader::new(file).lines() {
-        let line = line_result?;
-        let mut chars = line.chars();
-        match chars.next() {
-            Some('v') => match chars.next() {
-                Some(' ') => vertices.push(line.split(char::is_whitespace).skip(1).map(|s: &str| s.to_string()).collect() ),
-                _ => ()
+    names.iter().map(|name| {
+        library_materials.remove(name).unwrap_or_else(|| {
+            if !name.is_empty() {
+                println!("Material not found: {}", name);
             }
-            Some('f') => faces.push(line.split(char::is_whitespace).skip(1).map(|s: &str| s.to_string()).collect() ),
+            Box::new(Lambertian::new(Color::gray_scale(1.0)))
+        })
+    }).collect()
+}
+
+struct ObjData {
+    data: MeshData,
+    group_names: Vec<String>,
+    triangle_groups: Vec<u32>
+}
+
+// Position, normal and texture coordinate index of a polygon corner
+type Corner = (usize, Option<usize>, Option<usize>);
+
+fn read_obj(file_path: &str, bytes: &[u8]) -> Result<ObjData, ImportError> {
+    let text = std::str::from_utf8(bytes).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let mut vertices: Vec<Point> = vec![];
+    let mut normals: Vec<Normal> = vec![];
+    let mut uvs: Vec<(f64, f64)> = vec![];
+    let mut triangles: Vec<[usize; 3]> = vec![];
+    let mut triangle_normals: Vec<Option<[usize; 3]>> = vec![];
+    let mut triangle_uvs: Vec<Option<[u32; 3]>> = vec![];
+    let mut triangle_materials: Vec<u32> = vec![];
+    let mut triangle_groups: Vec<u32> = vec![];
+    let mut material_libraries: Vec<String> = vec![];
+    // the unnamed material and group are used until the first usemtl and o or g statement
+    let mut material_names: Vec<String> = vec![String::new()];
+    let mut material = 0;
+    let mut group_names: Vec<String> = vec![String::new()];
+    let mut group = 0;
+
+    for (line_number, line) in logical_lines(text) {
+        let error = |message: String| ImportError::parse(file_path, line_number, message);
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue
+        };
+        let args: Vec<&str> = tokens.collect();
+        let coordinates = |count: usize| -> Result<Vec<f64>, ImportError> {
+            if args.len() < count {
+                return Err(error(format!("Expected {} coordinates after {}", count, keyword)));
+            }
+            args.iter().map(|s| s.parse().map_err(|_| error(format!("Invalid number: {}", s)))).collect()
+        };
+
+        match keyword {
+            "v" => {
+                let v = coordinates(3)?;
+                vertices.push(Point::new(v[0], v[1], v[2]));
+            },
+            "vn" => {
+                let n = coordinates(3)?;
+                normals.push(Normal::new(n[0], n[1], n[2]));
+            },
+            "vt" => {
+                let uv = coordinates(1)?;
+                uvs.push((uv[0], uv.get(1).cloned().unwrap_or(0.0)));
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return Err(error(format!("Face with {} vertices", args.len())));
+                }
+                // corners are v, v/vt, v/vt/vn or v//vn
+                let corners = args.iter().map(|corner| {
+                    let mut parts = corner.split('/');
+                    let vertex = resolve_index(parts.next().unwrap_or(""), vertices.len()).map_err(&error)?;
+                    let uv = parts.next().filter(|s| !s.is_empty()).map(|s| resolve_index(s, uvs.len())).transpose().map_err(&error)?;
+                    let normal = parts.next().filter(|s| !s.is_empty()).map(|s| resolve_index(s, normals.len())).transpose().map_err(&error)?;
+                    Ok((vertex, normal, uv))
+                }).collect::<Result<Vec<Corner>, ImportError>>()?;
+
+                let polygon: Vec<Point> = corners.iter().map(|c| vertices[c.0]).collect();
+                for triangle in triangulate(&polygon) {
+                    let c = triangle.map(|i| corners[i]);
+                    triangles.push([c[0].0, c[1].0, c[2].0]);
+                    triangle_normals.push(match (c[0].1, c[1].1, c[2].1) {
+                        (Some(a), Some(b), Some(c)) => Some([a, b, c]),
+                        _ => None
+                    });
+                    triangle_uvs.push(match (c[0].2, c[1].2, c[2].2) {
+                        (Some(a), Some(b), Some(c)) => Some([a as u32, b as u32, c as u32]),
+                        _ => None
+                    });
+                    triangle_materials.push(material);
+                    triangle_groups.push(group);
+                }
+            },
+            "mtllib" => material_libraries.extend(args.iter().map(|s| s.to_string())),
+            "usemtl" => material = name_index(&mut material_names, args.join(" ")),
+            "o" | "g" => group = name_index(&mut group_names, args.join(" ")),
             _ => ()
         }
     }
 
-    let vertices : Vec<Point> = vertices.iter().map(|vec| {
-        let v: Vec<f64> = vec.iter().map(|s| s.parse().expect("Unable to convert String to f64") ).collect();
-        Point::new(v[0], v[1], v[2])
+    let triangle_normals = complete_normals(&vertices, &triangles, triangle_normals, &mut normals, settings::get().crease_angle);
+    let triangles = triangles.iter().zip(triangle_normals).zip(triangle_uvs).zip(triangle_materials).map(|(((t, n), uv), material)| IndexedTriangle{
+        vertices: t.map(|i| i as u32),
+        normals: Some(n.map(|i| i as u32)),
+        uvs: uv,
+        material
     }).collect();
+    let data = MeshData{vertices, normals, uvs, triangles, bvh: None, material_libraries, material_names};
+    Ok(ObjData{data, group_names, triangle_groups})
+}
 
-    let material = Box::new(Lambertian::new(Color::gray_scale(1.0)));
-    let mat = material.clone();
-    let faces : Vec<Box<dyn Face>> = faces.iter().filter_map(move |vec|{
-        let v: Vec<Point> = vec.iter().map(|s| s.split('/').next().unwrap() )
-            .map(|s| s.parse().expect("Unable to convert String to usize") )
-            .map(|index: usize| vertices.get(index-1).unwrap() ).cloned().collect();
-        match v.len() {
-            3 => Some(Box::new(Triangle::new([v[0],v[1],v[2]], false,mat.clone())) as Box<dyn Face>),
-            4 => Some(Box::new(Rectangle::new([v[0],v[1],v[2],v[3]], false,mat.clone())) as Box<dyn Face>),
-            x if x < 3 => None,
-            x => {
-                println!("Faces with {} vertices are not supported.", x);
-                None
+// Joins lines ending with a backslash and strips comments, statements keep the number of their first line
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut lines = vec![];
+    let mut pending: Option<(usize, String)> = None;
+    for (i, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line
+        };
+        let (start, mut joined) = pending.take().unwrap_or((i + 1, String::new()));
+        match line.trim_end().strip_suffix('\\') {
+            Some(part) => {
+                joined.push_str(part);
+                joined.push(' ');
+                pending = Some((start, joined));
+            },
+            None => {
+                joined.push_str(line);
+                lines.push((start, joined));
             }
         }
+    }
+    lines.extend(pending);
+    lines
+}
+
+// Indices start at one, negative indices count back from the last element read so far
+fn resolve_index(s: &str, count: usize) -> Result<usize, String> {
+    let index: i64 = s.parse().map_err(|_| format!("Invalid index: {}", s))?;
+    let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!("Index {} out of range, {} elements defined", index, count));
+    }
+    Ok(resolved as usize)
+}
+
+fn name_index(names: &mut Vec<String>, name: String) -> u32 {
+    match names.iter().position(|n| *n == name) {
+        Some(index) => index as u32,
+        None => {
+            names.push(name);
+            (names.len() - 1) as u32
+        }
+    }
+}
+
+// Ear clipping in the plane of the polygon, handles convex and concave polygons without holes.
+// Returns the triangles as indices into the polygon.
+pub(super) fn triangulate(polygon: &[Point]) -> Vec<[usize; 3]> {
+    if polygon.len() == 3 { return vec![[0, 1, 2]] }
+
+    // Newell's method also gives a sensible normal for slightly non planar polygons
+    let normal = (0..polygon.len()).fold(Vector::new(0.0, 0.0, 0.0), |acc, i| {
+        let (a, b) = (polygon[i] - Point::origin(), polygon[(i + 1) % polygon.len()] - Point::origin());
+        acc + a.cross(&b)
+    });
+    let axis = (0..3).max_by(|a, b| normal.component(*a).abs().partial_cmp(&normal.component(*b).abs()).unwrap_or(std::cmp::Ordering::Equal)).unwrap();
+    // dropping the dominant axis, the polygon is counter clockwise in the remaining two
+    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+    let sign = if normal.component(axis) < 0.0 { -1.0 } else { 1.0 };
+    let points: Vec<(f64, f64)> = polygon.iter().map(|p| (p.component(u), sign*p.component(v))).collect();
+    let cross = |a: (f64, f64), b: (f64, f64), c: (f64, f64)| (b.0 - a.0)*(c.1 - a.1) - (b.1 - a.1)*(c.0 - a.0);
+
+    let mut remaining: Vec<usize> = (0..polygon.len()).collect();
+    let mut triangles = vec![];
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let corners = |i: usize| (remaining[(i + n - 1) % n], remaining[i], remaining[(i + 1) % n]);
+        let ear = (0..n).find(|i| {
+            let (a, b, c) = corners(*i);
+            let (pa, pb, pc) = (points[a], points[b], points[c]);
+            cross(pa, pb, pc) > 0.0 && remaining.iter().all(|j| {
+                let p = points[*j];
+                *j == a || *j == b || *j == c || cross(pa, pb, p) < 0.0 || cross(pb, pc, p) < 0.0 || cross(pc, pa, p) < 0.0
+            })
+        });
+        // degenerate polygons may have no ear left, the rest is split as a fan
+        let i = match ear {
+            Some(i) => i,
+            None => break
+        };
+        let (a, b, c) = corners(i);
+        triangles.push([a, b, c]);
+        remaining.remove(i);
+    }
+    for fan in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[fan], remaining[fan + 1]]);
+    }
+    triangles
+}
+
+// Computes angle weighted vertex normals for the triangles without normals, faces whose normals differ
+// by more than the crease angle are not averaged so hard edges stay sharp.
+pub(super) fn complete_normals(vertices: &[Point], triangles: &[[usize; 3]], triangle_normals: Vec<Option<[usize; 3]>>, normals: &mut Vec<Normal>, crease_angle: f64) -> Vec<[usize; 3]> {
+    if triangle_normals.iter().all(|n| n.is_some()) {
+        return triangle_normals.into_iter().map(|n| n.unwrap()).collect();
+    }
+
+    let face_normals: Vec<Option<Vector>> = triangles.iter().map(|t| {
+        let normal = (vertices[t[1]] - vertices[t[0]]).cross(&(vertices[t[2]] - vertices[t[0]]));
+        if normal.length() > 0.0 { Some(normal.normalize()) } else { None }
+    }).collect();
+    let corner_angles: Vec<[f64; 3]> = triangles.iter().map(|t| {
+        [0, 1, 2].map(|k| {
+            let (a, b) = (vertices[t[(k + 1) % 3]] - vertices[t[k]], vertices[t[(k + 2) % 3]] - vertices[t[k]]);
+            (a.dot(&b) / (a.length()*b.length())).clamp(-1.0, 1.0).acos()
+        })
     }).collect();
 
-    println!("Imported mesh: {}", file_path);
-    println!("Amount of faces: {}", faces.len());
-    let mesh = Mesh::new(faces, material);
-    Ok( mesh )
-}
\ No newline at end of file
+    let mut incident: Vec<Vec<(usize, usize)>> = vec![Vec::new(); vertices.len()];
+    for (i, t) in triangles.iter().enumerate() {
+        for k in 0..3 { incident[t[k]].push((i, k)); }
+    }
+
+    // equal normals at a vertex share one entry
+    let mut vertex_normals: Vec<Vec<(Vector, usize)>> = vec![Vec::new(); vertices.len()];
+    let cos_crease = crease_angle.cos();
+    triangle_normals.into_iter().enumerate().map(|(i, corner_normals)| {
+        if let Some(corner_normals) = corner_normals { return corner_normals }
+        [0, 1, 2].map(|k| {
+            let vertex = triangles[i][k];
+            let normal = match face_normals[i] {
+                None => Vector::up(),
+                Some(face_normal) => {
+                    let sum = incident[vertex].iter().fold(Vector::new(0.0, 0.0, 0.0), |acc, (j, corner)| {
+                        match face_normals[*j] {
+                            Some(other) if other.dot(&face_normal) >= cos_crease => acc + corner_angles[*j][*corner]*other,
+                            _ => acc
+                        }
+                    });
+                    if sum.length() > 0.0 { sum.normalize() } else { face_normal }
+                }
+            };
+            match vertex_normals[vertex].iter().find(|(n, _)| *n == normal) {
+                Some((_, index)) => *index,
+                None => {
+                    normals.push(Normal::from(normal));
+                    vertex_normals[vertex].push((normal, normals.len() - 1));
+                    normals.len() - 1
+                }
+            }
+        })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::{Object, ObjectDescription};
+
+    fn read(text: &str) -> Result<ObjData, ImportError> {
+        read_obj("test.obj", text.as_bytes())
+    }
+
+    #[test]
+    fn normals_are_averaged_within_the_crease_angle() {
+        // two triangles folded by a right angle along their shared edge from vertex 0 to 1
+        let vertices = [Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0), Point::new(0.0, 1.0, 0.0), Point::new(0.0, 0.0, -1.0)];
+        let triangles = [[0, 1, 2], [1, 0, 3]];
+
+        let mut normals = Vec::new();
+        let sharp = complete_normals(&vertices, &triangles, vec![None, None], &mut normals, 60f64.to_radians());
+        assert_eq!(normals.len(), 6);
+        assert_ne!(sharp[0][0], sharp[1][1]);
+
+        let mut normals = Vec::new();
+        let smooth = complete_normals(&vertices, &triangles, vec![None, None], &mut normals, 100f64.to_radians());
+        assert_eq!(normals.len(), 4);
+        assert_eq!((smooth[0][0], smooth[0][1]), (smooth[1][1], smooth[1][0]));
+        let shared = normals[smooth[0][0]];
+        assert!(shared.x.abs() < 1e-9 && (shared.y + 0.5f64.sqrt()).abs() < 1e-9 && (shared.z - 0.5f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn given_normals_are_kept() {
+        let vertices = [Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0), Point::new(0.0, 1.0, 0.0), Point::new(2.0, 0.0, 0.0)];
+        let mut normals = vec![Normal::new(0.0, 0.0, 1.0)];
+        // the second triangle is degenerate and has no face normal
+        let indices = complete_normals(&vertices, &[[0, 1, 2], [0, 1, 3]], vec![Some([0, 0, 0]), None], &mut normals, 1.0);
+        assert_eq!(indices[0], [0, 0, 0]);
+        assert!(indices[1].iter().all(|i| normals[*i] == Normal::up()));
+    }
+
+    #[test]
+    fn indices_count_from_one_or_back_from_the_end() {
+        assert_eq!(resolve_index("1", 3), Ok(0));
+        assert_eq!(resolve_index("3", 3), Ok(2));
+        assert_eq!(resolve_index("-1", 3), Ok(2));
+        assert_eq!(resolve_index("-3", 3), Ok(0));
+        assert!(resolve_index("0", 3).is_err());
+        assert!(resolve_index("4", 3).is_err());
+        assert!(resolve_index("-4", 3).is_err());
+        assert!(resolve_index("1.5", 3).is_err());
+        assert!(resolve_index("1", 0).is_err());
+    }
+
+    #[test]
+    fn continued_lines_are_joined_and_comments_stripped() {
+        let lines = logical_lines("v 1 2 3 # first\nf 1 \\\n  2 \\\n  3\n# only a comment\nvn 0 1 0");
+        let lines: Vec<(usize, &str)> = lines.iter().map(|(n, line)| (*n, line.trim())).collect();
+        assert_eq!(lines[0], (1, "v 1 2 3"));
+        assert_eq!(lines[1].0, 2);
+        assert_eq!(lines[1].1.split_whitespace().collect::<Vec<&str>>(), ["f", "1", "2", "3"]);
+        assert_eq!(lines[2], (5, ""));
+        assert_eq!(lines[3], (6, "vn 0 1 0"));
+        // a continuation at the end of the file keeps what was read
+        assert_eq!(logical_lines("f 1 2 \\")[0].0, 1);
+    }
+
+    #[test]
+    fn concave_polygons_are_triangulated_inside() {
+        // an L shape, a fan from the first corner would cover the notch
+        let polygon = [(2.0, 2.0), (0.0, 2.0), (0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (2.0, 1.0)].map(|(x, y)| Point::new(x, y, 0.0));
+        let triangles = triangulate(&polygon);
+        assert_eq!(triangles.len(), 4);
+        let area = |t: &[usize; 3]| {
+            let (a, b, c) = (polygon[t[0]], polygon[t[1]], polygon[t[2]]);
+            0.5*(b - a).cross(&(c - a)).z
+        };
+        // every triangle keeps the winding of the polygon and together they cover its area
+        assert!(triangles.iter().all(|t| area(t) > 0.0));
+        assert!((triangles.iter().map(area).sum::<f64>() - 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn faces_keep_their_group_and_material() {
+        let obj = read("v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\n\
+            f 1 2 3\ng wheels\nusemtl rubber\nf 2 4 3\no body\nusemtl paint\nf 1 2 4\ng wheels\nf -3 -2 -1\n").unwrap();
+        assert_eq!(obj.group_names, ["", "wheels", "body"]);
+        assert_eq!(obj.triangle_groups, [0, 1, 2, 1]);
+        assert_eq!(obj.data.material_names, ["", "rubber", "paint"]);
+        let materials: Vec<u32> = obj.data.triangles.iter().map(|t| t.material).collect();
+        assert_eq!(materials, [0, 1, 2, 2]);
+        assert_eq!(obj.data.triangles[3].vertices, [1, 2, 3]);
+    }
+
+    #[test]
+    fn errors_report_their_line() {
+        let line = |text: &str| match read(text) {
+            Err(ImportError::Parse{line, ..}) => Some(line),
+            _ => None
+        };
+        assert_eq!(line("v 0 0 0\nv 1 0 0\nf 1 2 3"), Some(3));
+        assert_eq!(line("v 0 0\n"), Some(1));
+        assert_eq!(line("v 0 0 0\n\nf 1 \\\n1\n"), Some(3));
+        assert_eq!(line("v 0 0 0\nvt 0.5\nf 1/1 1/1 1/2\n"), Some(3));
+    }
+
+    #[test]
+    fn groups_become_separate_meshes() {
+        let path = std::env::temp_dir().join("groups.obj");
+        std::fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 5 5 5\nv 6 5 5\nv 5 6 5\ng first\nf 1 2 3\ng empty\ng second\nf 4 5 6\n").unwrap();
+        let file_path = path.to_string_lossy().into_owned();
+        let groups = parse_obj_groups(&file_path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let names: Vec<&str> = groups.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["first", "second"]);
+        let (_, second) = &groups[1];
+        // only the vertices used by the group are kept
+        assert_eq!(second.vertices(), [Point::new(5.0, 5.0, 5.0), Point::new(6.0, 5.0, 5.0), Point::new(5.0, 6.0, 5.0)]);
+        assert_eq!(second.triangles()[0].vertices, [0, 1, 2]);
+        assert_eq!(second.describe(), Some(ObjectDescription::Mesh{file: &file_path, group: Some("second"), material_replaced: false, double_sided: false}));
+    }
+
+    #[test]
+    fn compacted_indices_follow_first_use() {
+        let mut compacted = Compacted::default();
+        assert_eq!([7, 3, 7, 5].map(|i| compacted.index(i)), [0, 1, 0, 2]);
+        assert_eq!(compacted.order, [7, 3, 5]);
+    }
+}
@@ -0,0 +1,388 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::str::Lines;
+
+use super::{Mesh, IndexedTriangle, Lambertian, ImportError};
+use super::obj_import::{complete_normals, triangulate};
+use crate::math::{Point, Normal};
+use crate::cg_tools::Color;
+use crate::settings;
+
+#[derive(Copy, Clone)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian
+}
+
+#[derive(Copy, Clone)]
+enum Scalar { I8, U8, I16, U16, I32, U32, F32, F64 }
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8
+        }
+    }
+
+    // Integer colors use the whole range of their type
+    fn color_scale(self) -> f64 {
+        match self {
+            Scalar::I8 => i8::MAX as f64,
+            Scalar::U8 => u8::MAX as f64,
+            Scalar::I16 => i16::MAX as f64,
+            Scalar::U16 => u16::MAX as f64,
+            Scalar::I32 => i32::MAX as f64,
+            Scalar::U32 => u32::MAX as f64,
+            Scalar::F32 | Scalar::F64 => 1.0
+        }
+    }
+}
+
+// List properties store their length with the count type in front of the items
+struct Property {
+    name: String,
+    scalar: Scalar,
+    list: Option<Scalar>
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+    line: usize
+}
+
+impl Element {
+    fn property(&self, names: &[&str]) -> Option<usize> {
+        self.properties.iter().position(|p| names.contains(&p.name.as_str()))
+    }
+}
+
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+    lines: usize,
+    length: usize
+}
+
+pub fn parse_ply(file_path: &str) -> Result<Mesh, ImportError> {
+    let bytes = fs::read(file_path)?;
+    let header = read_header(file_path, &bytes)?;
+    let data = &bytes[header.length..];
+    let body = match header.format {
+        Format::Ascii => {
+            let text = std::str::from_utf8(data).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            Body::Ascii{lines: text.lines(), tokens: Vec::new(), next: 0, line: header.lines}
+        },
+        Format::BinaryLittleEndian => Body::Binary{data, position: 0, big_endian: false},
+        Format::BinaryBigEndian => Body::Binary{data, position: 0, big_endian: true}
+    };
+    let mut reader = BodyReader{file_path, body};
+
+    let mut vertices: Vec<Point> = vec![];
+    let mut normals: Vec<Normal> = vec![];
+    let mut uvs: Vec<(f64, f64)> = vec![];
+    let mut colors: Vec<Color> = vec![];
+    let mut triangles: Vec<[usize; 3]> = vec![];
+    let mut values: Vec<f64> = vec![];
+    let mut ranges: Vec<Range<usize>> = vec![];
+    let gamma = settings::get().gamma;
+
+    for element in &header.elements {
+        let missing = |what: &str| ImportError::parse(file_path, element.line, format!("Element {} without {}", element.name, what));
+        match element.name.as_str() {
+            "vertex" => {
+                let position = match [element.property(&["x"]), element.property(&["y"]), element.property(&["z"])] {
+                    [Some(x), Some(y), Some(z)] => [x, y, z],
+                    _ => return Err(missing("x, y and z"))
+                };
+                let normal = [element.property(&["nx"]), element.property(&["ny"]), element.property(&["nz"])];
+                let color = [element.property(&["red", "r", "diffuse_red"]), element.property(&["green", "g", "diffuse_green"]), element.property(&["blue", "b", "diffuse_blue"])];
+                let uv = [element.property(&["u", "s", "texture_u", "texture_s"]), element.property(&["v", "t", "texture_v", "texture_t"])];
+
+                for _ in 0..element.count {
+                    read_instance(&mut reader, element, &mut values, &mut ranges)?;
+                    let value = |i: usize| values.get(ranges[i].start).cloned().unwrap_or(0.0);
+                    vertices.push(Point::new(value(position[0]), value(position[1]), value(position[2])));
+                    if let [Some(x), Some(y), Some(z)] = normal {
+                        normals.push(Normal::new(value(x), value(y), value(z)));
+                    }
+                    // stored colors are gamma encoded
+                    if let [Some(r), Some(g), Some(b)] = color {
+                        let decode = |i: usize| (value(i) / element.properties[i].scalar.color_scale()).max(0.0).powf(gamma);
+                        colors.push(Color::new_rgb(decode(r), decode(g), decode(b)));
+                    }
+                    if let [Some(u), Some(v)] = uv {
+                        uvs.push((value(u), value(v)));
+                    }
+                }
+            },
+            "face" => {
+                let indices = element.property(&["vertex_indices", "vertex_index"]).ok_or_else(|| missing("vertex_indices"))?;
+                for _ in 0..element.count {
+                    read_instance(&mut reader, element, &mut values, &mut ranges)?;
+                    let face = &values[ranges[indices].clone()];
+                    if face.len() < 3 {
+                        return Err(reader.error(format!("Face with {} vertices", face.len())));
+                    }
+                    if let Some(index) = face.iter().find(|i| **i < 0.0 || **i as usize >= vertices.len()) {
+                        return Err(reader.error(format!("Vertex index {} out of range, {} vertices defined", index, vertices.len())));
+                    }
+                    let polygon: Vec<Point> = face.iter().map(|i| vertices[*i as usize]).collect();
+                    triangles.extend(triangulate(&polygon).into_iter().map(|t| t.map(|k| face[k] as usize)));
+                }
+            },
+            // other elements are read to get to the following data
+            _ => for _ in 0..element.count {
+                read_instance(&mut reader, element, &mut values, &mut ranges)?;
+            }
+        }
+    }
+
+    // normals and texture coordinates are per vertex, so they share the vertex indices
+    let triangle_normals = triangles.iter().map(|t| if normals.is_empty() { None } else { Some(*t) }).collect();
+    let triangle_normals = complete_normals(&vertices, &triangles, triangle_normals, &mut normals, settings::get().crease_angle);
+    let has_uvs = !uvs.is_empty();
+    let triangles = triangles.iter().zip(triangle_normals).map(|(t, n)| IndexedTriangle{
+        vertices: t.map(|i| i as u32),
+        normals: Some(n.map(|i| i as u32)),
+        uvs: if has_uvs { Some(t.map(|i| i as u32)) } else { None },
+        material: 0
+    }).collect();
+
+    println!("Imported mesh: {}", file_path);
+    let mesh = Mesh::new(vertices, normals, uvs, triangles, vec![Box::new(Lambertian::new(Color::gray_scale(1.0)))]).with_source(file_path);
+    println!("Amount of faces: {}", mesh.triangles().len());
+    Ok( if colors.is_empty() { mesh } else { mesh.with_colors(colors) } )
+}
+
+fn read_header(file_path: &str, bytes: &[u8]) -> Result<Header, ImportError> {
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    let mut position = 0;
+    let mut line = 0;
+    loop {
+        let end = bytes[position..].iter().position(|b| *b == b'\n').map(|i| position + i)
+            .ok_or_else(|| ImportError::parse(file_path, line + 1, "Header without end_header".to_string()))?;
+        let text = String::from_utf8_lossy(&bytes[position..end]).trim_end().to_string();
+        position = end + 1;
+        line += 1;
+
+        let error = |message: String| ImportError::parse(file_path, line, message);
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+        if line == 1 {
+            if text != "ply" { return Err(error("Not a PLY file".to_string())) }
+            continue;
+        }
+        match tokens.first().cloned() {
+            Some("format") => format = Some(match tokens.get(1).cloned() {
+                Some("ascii") => Format::Ascii,
+                Some("binary_little_endian") => Format::BinaryLittleEndian,
+                Some("binary_big_endian") => Format::BinaryBigEndian,
+                _ => return Err(error(format!("Unknown format: {}", text)))
+            }),
+            Some("element") => {
+                let name = tokens.get(1).ok_or_else(|| error("Element without name".to_string()))?;
+                let count = tokens.get(2).and_then(|c| c.parse().ok()).ok_or_else(|| error(format!("Invalid element count: {}", text)))?;
+                elements.push(Element{name: name.to_string(), count, properties: vec![], line});
+            },
+            Some("property") => {
+                let scalar = |i: usize| tokens.get(i).and_then(|name| Scalar::parse(name)).ok_or_else(|| error(format!("Unknown property type: {}", text)));
+                let (list, scalar, name) = if tokens.get(1) == Some(&"list") {
+                    (Some(scalar(2)?), scalar(3)?, tokens.get(4))
+                } else {
+                    (None, scalar(1)?, tokens.get(2))
+                };
+                let name = name.ok_or_else(|| error("Property without name".to_string()))?.to_string();
+                elements.last_mut().ok_or_else(|| error("Property outside of an element".to_string()))?
+                    .properties.push(Property{name, scalar, list});
+            },
+            Some("end_header") => break,
+            _ => ()
+        }
+    }
+    let format = format.ok_or_else(|| ImportError::parse(file_path, line, "Header without format".to_string()))?;
+    Ok(Header{format, elements, lines: line, length: position})
+}
+
+// Reads one instance of the element, the values of the i-th property are values[ranges[i]]
+fn read_instance(reader: &mut BodyReader, element: &Element, values: &mut Vec<f64>, ranges: &mut Vec<Range<usize>>) -> Result<(), ImportError> {
+    values.clear();
+    ranges.clear();
+    for property in &element.properties {
+        let start = values.len();
+        match property.list {
+            None => values.push(reader.read(property.scalar)?),
+            Some(count_scalar) => {
+                let count = reader.read(count_scalar)?;
+                for _ in 0..count as usize {
+                    values.push(reader.read(property.scalar)?);
+                }
+            }
+        }
+        ranges.push(start..values.len());
+    }
+    Ok(())
+}
+
+enum Body<'a> {
+    Ascii{lines: Lines<'a>, tokens: Vec<&'a str>, next: usize, line: usize},
+    Binary{data: &'a [u8], position: usize, big_endian: bool}
+}
+
+struct BodyReader<'a> {
+    file_path: &'a str,
+    body: Body<'a>
+}
+
+impl<'a> BodyReader<'a> {
+    fn read(&mut self, scalar: Scalar) -> Result<f64, ImportError> {
+        match &mut self.body {
+            Body::Ascii{lines, tokens, next, line} => {
+                while *next >= tokens.len() {
+                    *tokens = match lines.next() {
+                        Some(text) => text.split_whitespace().collect(),
+                        None => return Err(ImportError::parse(self.file_path, *line, "File ends before all elements are read".to_string()))
+                    };
+                    *next = 0;
+                    *line += 1;
+                }
+                let token = tokens[*next];
+                *next += 1;
+                token.parse().map_err(|_| ImportError::parse(self.file_path, *line, format!("Invalid number: {}", token)))
+            },
+            Body::Binary{data, position, big_endian} => {
+                let size = scalar.size();
+                let bytes = data.get(*position..*position + size)
+                    .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "File ends before all elements are read"))?;
+                *position += size;
+                let mut buffer = [0u8; 8];
+                buffer[..size].copy_from_slice(bytes);
+                if *big_endian { buffer[..size].reverse() }
+                let [b0, b1, b2, b3, ..] = buffer;
+                Ok(match scalar {
+                    Scalar::I8 => b0 as i8 as f64,
+                    Scalar::U8 => b0 as f64,
+                    Scalar::I16 => i16::from_le_bytes([b0, b1]) as f64,
+                    Scalar::U16 => u16::from_le_bytes([b0, b1]) as f64,
+                    Scalar::I32 => i32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    Scalar::U32 => u32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    Scalar::F32 => f32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    Scalar::F64 => f64::from_le_bytes(buffer)
+                })
+            }
+        }
+    }
+
+    // Binary data has no lines, its errors give the byte offset instead
+    fn error(&self, message: String) -> ImportError {
+        match &self.body {
+            Body::Ascii{line, ..} => ImportError::parse(self.file_path, *line, message),
+            Body::Binary{position, ..} => ImportError::invalid(self.file_path, format!("{} after {} bytes of data", message, position))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cg_tools::Ray;
+    use crate::math::Direction;
+    use crate::objects::Object;
+
+    const HEADER: &str = "ply\nformat {}\ncomment unit square facing -z\n\
+        element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+        property uchar red\nproperty uchar green\nproperty uchar blue\n\
+        element face 1\nproperty list uchar int vertex_indices\nend_header\n";
+
+    fn parse(name: &str, bytes: &[u8]) -> Result<Mesh, ImportError> {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, bytes).unwrap();
+        let mesh = parse_ply(&path.to_string_lossy());
+        fs::remove_file(path).unwrap();
+        mesh
+    }
+
+    fn square(format: &str, big_endian: bool) -> Vec<u8> {
+        let mut bytes = HEADER.replace("{}", format).into_bytes();
+        let corners = [(0.0f32, 0.0f32), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)];
+        for (x, y) in corners {
+            for c in [x, y, 0.0] {
+                bytes.extend_from_slice(&if big_endian { c.to_be_bytes() } else { c.to_le_bytes() });
+            }
+            bytes.extend_from_slice(&[255, 0, 0]);
+        }
+        bytes.push(4);
+        for i in 0..4i32 {
+            bytes.extend_from_slice(&if big_endian { i.to_be_bytes() } else { i.to_le_bytes() });
+        }
+        bytes
+    }
+
+    fn hit(mesh: &Mesh, x: f64, y: f64) -> Option<Color> {
+        let int = mesh.intersect(&Ray::new(Point::new(x, y, -1.0), Direction::new(0.0, 0.0, 1.0)))?;
+        int.color()
+    }
+
+    #[test]
+    fn ascii_and_binary_files_give_the_same_mesh() {
+        let ascii = HEADER.replace("{}", "ascii 1.0") + "0 0 0 255 0 0\n0 1 0 255 0 0\n1 1 0 255 0 0\n1 0 0 255 0 0\n4 0 1 2 3\n";
+        let meshes = [
+            parse("square_ascii.ply", ascii.as_bytes()).unwrap(),
+            parse("square_le.ply", &square("binary_little_endian 1.0", false)).unwrap(),
+            parse("square_be.ply", &square("binary_big_endian 1.0", true)).unwrap()
+        ];
+        for mesh in &meshes {
+            assert_eq!(mesh.vertices(), meshes[0].vertices());
+            assert_eq!(mesh.triangles().len(), 2);
+            // full intensity stays the same under the gamma curve
+            assert_eq!(hit(mesh, 0.25, 0.5).map(|c| c.rgb()), Some((1.0, 0.0, 0.0)));
+            assert_eq!(hit(mesh, 0.75, 0.5).map(|c| c.rgb()), Some((1.0, 0.0, 0.0)));
+        }
+    }
+
+    #[test]
+    fn unknown_elements_are_skipped() {
+        let text = "ply\nformat ascii 1.0\nelement material 2\nproperty list uchar float values\n\
+            element vertex 3\nproperty double x\nproperty double y\nproperty double z\nproperty float nx\nproperty float ny\nproperty float nz\n\
+            element face 1\nproperty list uchar uint vertex_index\nend_header\n\
+            2 0.5 0.5\n0\n0 0 0 0 0 -1\n0 1 0 0 0 -1\n1 0 0 0 0 -1\n3 0 1 2\n";
+        let mesh = parse("skipped.ply", text.as_bytes()).unwrap();
+        assert_eq!(mesh.vertices().len(), 3);
+        assert_eq!(mesh.normals(), [Normal::new(0.0, 0.0, -1.0); 3]);
+        let int = mesh.intersect(&Ray::new(Point::new(0.25, 0.25, -1.0), Direction::new(0.0, 0.0, 1.0))).unwrap();
+        assert!(int.color().is_none());
+    }
+
+    #[test]
+    fn errors_report_their_line() {
+        let error = |name: &str, text: String| parse(name, text.as_bytes()).err().map(|err| err.to_string()).unwrap_or_default();
+        let file = |name: &str| std::env::temp_dir().join(name).to_string_lossy().to_string();
+
+        let out_of_range = HEADER.replace("{}", "ascii 1.0") + "0 0 0 0 0 0\n0 1 0 0 0 0\n1 1 0 0 0 0\n1 0 0 0 0 0\n3 0 1 4\n";
+        assert_eq!(error("out_of_range.ply", out_of_range), format!("{}:18: Vertex index 4 out of range, 4 vertices defined", file("out_of_range.ply")));
+        let truncated = HEADER.replace("{}", "ascii 1.0") + "0 0 0 0 0 0\n";
+        assert_eq!(error("truncated.ply", truncated), format!("{}:14: File ends before all elements are read", file("truncated.ply")));
+        assert_eq!(error("no_format.ply", "ply\nelement vertex 0\nend_header\n".to_string()), format!("{}:3: Header without format", file("no_format.ply")));
+        assert!(parse("short.ply", &square("binary_little_endian 1.0", false)[..HEADER.len() + 20]).is_err());
+    }
+}
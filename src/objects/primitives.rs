@@ -2,7 +2,7 @@
 use crate::math::{Point, Vector, Normal, EPSILON};
 use crate::cg_tools::{Ray,Transformation,BoundingBox};
 use crate::scene::Intersection;
-use crate::objects::{Object,Material};
+use crate::objects::{Object,ObjectDescription,Material,Transparent};
 
 //////////////////
 //Sphere
@@ -27,25 +27,12 @@ impl Object for Sphere {
         let c = origin.dot(&origin) - 1.0;
         let d = b*b - 4.0*a*c;
 
-        let t;
         if d < 0.0 { return None; }
-        else if d > 0.0 {
-            let t1 = (-b + d.sqrt())/(2.0*a);
-            let t2 = (-b - d.sqrt())/(2.0*a);
-            t = if t1 <= 0.0 {
-                t2
-                } else {
-                    if t2 <= 0.0 || t1 < t2 {
-                        t1
-                    }
-                else { t2 }
-            };
-        }
-        else {
-            t = -b/(2.0*a);
-        }
+        let t1 = (-b - d.sqrt())/(2.0*a);
+        let t2 = (-b + d.sqrt())/(2.0*a);
+        let t = if ray.in_interval(t1) { t1 } else { t2 };
 
-        if t > 0.0 {
+        if ray.in_interval(t) {
             let point = origin + t**direction;
             let normal = Normal::from(point - Point::origin());
             Some( Intersection::new(t, point, normal, self.material()) )
@@ -69,6 +56,8 @@ impl Object for Sphere {
     fn material(&self) -> &dyn Material {
         self.material.as_ref()
     }
+
+    fn describe(&self) -> Option<ObjectDescription<'_>> { Some(ObjectDescription::Sphere) }
 }
 
 //////////////////
@@ -102,7 +91,7 @@ impl Object for Plane {
         else {
             let normal = if nom < 0.0 { self.normal.invert() } else { self.normal };
             let t = -denom/nom;
-            if t < 0.0 { return None }
+            if !ray.in_interval(t) { return None }
             let point = origin + t**direction;
             let int = Intersection::new(t, point, normal, self.material());
             Some(int)
@@ -115,6 +104,10 @@ impl Object for Plane {
     }
 
     fn material(&self) -> &dyn Material { self.material.as_ref() }
+
+    fn describe(&self) -> Option<ObjectDescription<'_>> {
+        Some(ObjectDescription::Plane{point: self.point, normal: self.normal, double_sided: self.double_sided})
+    }
 }
 
 //////////////////
@@ -127,6 +120,10 @@ pub struct BoxObject {
 }
 
 impl BoxObject{
+    pub fn new(min: Point, max: Point, material: Box<dyn Material>) -> BoxObject{
+        BoxObject{bounds: BoundingBox::new(min, max), material}
+    }
+
     pub fn new_from_origin(corner_point: Point, material: Box<dyn Material>) -> BoxObject{
         let bounds = BoundingBox::new_from_origin(corner_point);
         BoxObject{bounds, material}
@@ -146,4 +143,57 @@ impl Object for BoxObject {
     }
 
     fn material(&self) -> &dyn Material { self.material.as_ref() }
-}
\ No newline at end of file
+
+    fn describe(&self) -> Option<ObjectDescription<'_>> {
+        Some(ObjectDescription::Box{min: self.bounds.min(), max: self.bounds.max()})
+    }
+}
+//////////////////
+//Volume
+//////////////////
+// Boundary of the unit cube, only used to enter and leave the medium of its instance
+#[derive(Debug)]
+pub struct Volume {
+    bounds: BoundingBox,
+    material: Transparent
+}
+
+impl Volume {
+    pub fn new() -> Volume {
+        Volume{bounds: BoundingBox::new_from_origin(Point::new(1.,1.,1.)), material: Transparent}
+    }
+}
+
+impl Object for Volume {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let (origin, direction) = (ray.origin(), ray.direction());
+        let (min, max) = (self.bounds.min(), self.bounds.max());
+        let slabs = [(origin.x, direction.x, min.x, max.x), (origin.y, direction.y, min.y, max.y), (origin.z, direction.z, min.z, max.z)];
+
+        let (mut t_near, mut t_far) = (f64::MIN, f64::MAX);
+        let (mut axis_near, mut axis_far) = (0, 0);
+        for (axis, (o, d, lo, hi)) in slabs.iter().enumerate() {
+            let (mut t0, mut t1) = ((lo - o)/d, (hi - o)/d);
+            if t0 > t1 { std::mem::swap(&mut t0, &mut t1) }
+            if t0 > t_near { t_near = t0; axis_near = axis; }
+            if t1 < t_far { t_far = t1; axis_far = axis; }
+        }
+        if t_near > t_far { return None }
+
+        let t_min = ray.t_min().max(EPSILON);
+        let (t, axis) = if t_near > t_min { (t_near, axis_near) } else if t_far > t_min { (t_far, axis_far) } else { return None };
+        if t >= ray.t_max() { return None }
+        let mut normal = [0.0; 3];
+        normal[axis] = 1.0;
+        let point = origin + t**direction;
+        Some(Intersection::new(t, point, Normal::new(normal[0], normal[1], normal[2]), &self.material))
+    }
+
+    fn bounding_box(&self, transformation: &Transformation) -> BoundingBox {
+        self.bounds.transformed(transformation)
+    }
+
+    fn material(&self) -> &dyn Material { &self.material }
+
+    fn describe(&self) -> Option<ObjectDescription<'_>> { Some(ObjectDescription::Volume) }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use super::{Mesh, IndexedTriangle, Lambertian, ImportError};
+use super::obj_import::{complete_normals, triangulate};
+use crate::cg_tools::{BoundingBox, Color};
+use crate::math::Point;
+use crate::settings;
+
+// Vertices closer than this fraction of the mesh's diagonal are welded together
+const WELD_TOLERANCE: f64 = 1e-6;
+
+pub fn parse_stl(file_path: &str) -> Result<Mesh, ImportError> {
+    let bytes = fs::read(file_path)?;
+    let facets = if is_binary(&bytes) { read_binary(&bytes)? } else { read_ascii(file_path, &bytes)? };
+
+    // STL files repeat the corners for every facet, shared vertices are needed for smooth normals
+    let (vertices, triangles) = weld(&facets);
+    let triangle_normals = vec![None; triangles.len()];
+    let mut normals = vec![];
+    let triangle_normals = complete_normals(&vertices, &triangles, triangle_normals, &mut normals, settings::get().crease_angle);
+    let triangles = triangles.iter().zip(triangle_normals).map(|(t, n)| IndexedTriangle{
+        vertices: t.map(|i| i as u32),
+        normals: Some(n.map(|i| i as u32)),
+        uvs: None,
+        material: 0
+    }).collect();
+
+    println!("Imported mesh: {}", file_path);
+    println!("Amount of faces: {}", facets.len());
+    Ok( Mesh::new(vertices, normals, vec![], triangles, vec![Box::new(Lambertian::new(Color::gray_scale(1.0)))]).with_source(file_path) )
+}
+
+// Binary files may start with "solid" as well, the size given by the facet count decides
+fn is_binary(bytes: &[u8]) -> bool {
+    if bytes.len() >= 84 {
+        let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+        if bytes.len() == 84 + 50*count { return true }
+    }
+    !bytes.trim_ascii_start().starts_with(b"solid")
+}
+
+// 80 byte header, facet count and per facet the normal, three corners and two attribute bytes
+fn read_binary(bytes: &[u8]) -> Result<Vec<[Point; 3]>, ImportError> {
+    let count = bytes.get(80..84).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "File ends inside the STL header"))?;
+    if bytes.len() < 84 + 50*count {
+        return Err(Error::new(ErrorKind::UnexpectedEof, format!("File ends before all {} facets are read", count)).into());
+    }
+    let float = |offset: usize| f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as f64;
+    Ok((0..count).map(|i| {
+        let facet = 84 + 50*i;
+        [0, 1, 2].map(|k| {
+            let corner = facet + 12 + 12*k;
+            Point::new(float(corner), float(corner + 4), float(corner + 8))
+        })
+    }).collect())
+}
+
+fn read_ascii(file_path: &str, bytes: &[u8]) -> Result<Vec<[Point; 3]>, ImportError> {
+    let text = std::str::from_utf8(bytes).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let mut facets = vec![];
+    let mut corners: Vec<Point> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let error = |message: String| ImportError::parse(file_path, i + 1, message);
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.first().cloned() {
+            Some("facet") => corners.clear(),
+            Some("vertex") => {
+                if tokens.len() < 4 {
+                    return Err(error("Expected 3 coordinates after vertex".to_string()));
+                }
+                let c: Vec<f64> = tokens[1..4].iter().map(|s| s.parse().map_err(|_| error(format!("Invalid number: {}", s))))
+                    .collect::<Result<_, _>>()?;
+                corners.push(Point::new(c[0], c[1], c[2]));
+            },
+            // some exporters write polygons instead of triangles
+            Some("endfacet") => {
+                if corners.len() < 3 {
+                    return Err(error(format!("Facet with {} vertices", corners.len())));
+                }
+                facets.extend(triangulate(&corners).into_iter().map(|t| t.map(|k| corners[k])));
+            },
+            _ => ()
+        }
+    }
+    Ok(facets)
+}
+
+// Corners are sorted into cells of the tolerance size, close corners can only be in neighboring cells
+fn weld(facets: &[[Point; 3]]) -> (Vec<Point>, Vec<[usize; 3]>) {
+    let bbox = facets.iter().flatten().fold(BoundingBox::empty(), |acc, p| acc.union(&BoundingBox::new(*p, *p)));
+    let tolerance = ((bbox.max() - bbox.min()).length()*WELD_TOLERANCE).max(f64::MIN_POSITIVE);
+    let cell = |p: &Point| [0, 1, 2].map(|axis| (p.component(axis)/tolerance).floor() as i64);
+
+    let mut vertices: Vec<Point> = vec![];
+    let mut cells: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+    let mut index = |p: &Point| -> usize {
+        let [x, y, z] = cell(p);
+        for neighbor in (0..27).map(|n| [x + n%3 - 1, y + n/3%3 - 1, z + n/9 - 1]) {
+            let found = cells.get(&neighbor).and_then(|indices| indices.iter().find(|i| (vertices[**i] - *p).length() <= tolerance));
+            if let Some(i) = found { return *i }
+        }
+        vertices.push(*p);
+        cells.entry([x, y, z]).or_default().push(vertices.len() - 1);
+        vertices.len() - 1
+    };
+
+    // facets collapsing to a line or point after welding are dropped
+    let triangles = facets.iter().map(|facet| facet.map(|p| index(&p)))
+        .filter(|[a, b, c]| a != b && b != c && a != c).collect();
+    (vertices, triangles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(name: &str, bytes: &[u8]) -> Result<Mesh, ImportError> {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, bytes).unwrap();
+        let mesh = parse_stl(&path.to_string_lossy());
+        fs::remove_file(path).unwrap();
+        mesh
+    }
+
+    fn binary(header: &[u8], facets: &[[[f32; 3]; 3]]) -> Vec<u8> {
+        let mut bytes = header.to_vec();
+        bytes.resize(80, b' ');
+        bytes.extend_from_slice(&(facets.len() as u32).to_le_bytes());
+        for facet in facets {
+            bytes.extend_from_slice(&[0; 12]);
+            facet.iter().flatten().for_each(|c| bytes.extend_from_slice(&c.to_le_bytes()));
+            bytes.extend_from_slice(&[0; 2]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn close_corners_are_welded() {
+        let p = |x: f64, y: f64| Point::new(x, y, 0.0);
+        let facets = [
+            [p(0.0, 0.0), p(0.0, 1.0), p(1.0, 1.0)],
+            [p(1e-9, 0.0), p(1.0, 1.0 + 1e-9), p(1.0, 0.0)],
+            // collapses to a line once welded
+            [p(0.0, 0.0), p(1e-9, 0.0), p(1.0, 0.0)]
+        ];
+        let (vertices, triangles) = weld(&facets);
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(triangles, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn binary_files_may_start_with_solid() {
+        let facets = [[[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [1.0, 1.0, 0.0]], [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [1.0, 0.0, 0.0]]];
+        let bytes = binary(b"solid exported by a tool that ignores the format", &facets);
+        assert!(is_binary(&bytes));
+        let mesh = parse("solid_binary.stl", &bytes).unwrap();
+        assert_eq!((mesh.vertices().len(), mesh.triangles().len()), (4, 2));
+
+        assert!(parse("truncated.stl", &bytes[..bytes.len() - 1]).is_err());
+        assert!(!is_binary(b"  solid ascii\nendsolid ascii\n"));
+    }
+
+    #[test]
+    fn ascii_polygons_are_triangulated() {
+        let text = "solid quad\nfacet normal 0 0 -1\nouter loop\nvertex 0 0 0\nvertex 0 1 0\nvertex 1 1 0\nvertex 1 0 0\nendloop\nendfacet\nendsolid quad\n";
+        let mesh = parse("quad.stl", text.as_bytes()).unwrap();
+        assert_eq!((mesh.vertices().len(), mesh.triangles().len()), (4, 2));
+        // every corner of a flat surface gets its face normal
+        assert_eq!(mesh.normals().len(), 4);
+        assert!(mesh.normals().iter().all(|n| n.z.abs() == 1.0));
+    }
+
+    #[test]
+    fn errors_report_their_line() {
+        let file = |name: &str| std::env::temp_dir().join(name).to_string_lossy().to_string();
+        let text = "solid broken\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 0 one 0\n";
+        assert_eq!(parse("broken.stl", text.as_bytes()).err().unwrap().to_string(), format!("{}:5: Invalid number: one", file("broken.stl")));
+        let text = "solid line\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 0 1 0\nendloop\nendfacet\n";
+        assert_eq!(parse("line.stl", text.as_bytes()).err().unwrap().to_string(), format!("{}:7: Facet with 2 vertices", file("line.stl")));
+    }
+}
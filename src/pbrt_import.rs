@@ -0,0 +1,610 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::settings::{self, Settings};
+use crate::math::{Point, Vector, Direction, Normal, Matrix};
+use crate::cg_tools::{Color, Transformation};
+use crate::camera::PerspectiveCamera;
+use crate::scene::Scene;
+use crate::objects::{Instance, Light, PointLight, SpotLight, DistantLight, SurfaceLight, Rectangle, Sphere, Mesh, IndexedTriangle,
+    Material, Lambertian, Phong, ThinDielectric, ImportError, parse_ply};
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// Reads the subset of PBRT-v3 scene files describing cameras, spheres, triangle meshes, matte, mirror and glass
+// materials and point, spot, distant and area lights. Unsupported directives are skipped with a message.
+// The returned settings carry the resolution of the film and the samples per pixel.
+pub fn parse_pbrt(file_path: &str) -> Result<(Scene, Settings), ImportError> {
+    let mut builder = Builder{
+        instances: vec![],
+        lights: vec![],
+        camera: None,
+        settings: settings::get().clone(),
+        state: GraphicsState{transformation: Transformation::new(), material: PbrtMaterial::Matte(Color::gray_scale(0.5)), area_light: None, reverse_orientation: false},
+        attributes: vec![],
+        transformations: vec![]
+    };
+    read_file(file_path, &mut builder, 0)?;
+
+    let (camera_transformation, fov) = builder.camera.clone().ok_or_else(|| ImportError::invalid(file_path, "Scene without camera".to_string()))?;
+    let camera = builder.create_camera(camera_transformation, fov);
+    println!("Imported scene: {}", file_path);
+    println!("Amount of instances: {}", builder.instances.len());
+    Ok((Scene::new(builder.instances, builder.lights, camera), builder.settings))
+}
+
+fn read_file(file_path: &str, builder: &mut Builder, depth: usize) -> Result<(), ImportError> {
+    let text = fs::read_to_string(file_path)?;
+    let directory = Path::new(file_path).parent().unwrap_or(Path::new(""));
+    let mut parser = Parser{file_path, tokens: tokenize(file_path, &text)?, position: 0};
+
+    while let Some(token) = parser.next() {
+        let directive = match token {
+            Token::Word(word) => word,
+            _ => return Err(parser.error("Expected a directive".to_string()))
+        };
+        match directive.as_str() {
+            "Identity" => builder.state.transformation = Transformation::new(),
+            "Translate" => {
+                let v = parser.numbers(3)?;
+                builder.apply(Transformation::new().translate(Vector::new(v[0], v[1], v[2])));
+            },
+            "Scale" => {
+                let v = parser.numbers(3)?;
+                builder.apply(Transformation::new().scale(v[0], v[1], v[2]));
+            },
+            "Rotate" => {
+                let v = parser.numbers(4)?;
+                builder.apply(Transformation::new().rotate_axis(Vector::new(v[1], v[2], v[3]), v[0].to_radians()));
+            },
+            "LookAt" => {
+                let v = parser.numbers(9)?;
+                let look_at = look_at(Point::new(v[0], v[1], v[2]), Point::new(v[3], v[4], v[5]), Vector::new(v[6], v[7], v[8]))
+                    .ok_or_else(|| parser.error("Degenerate LookAt".to_string()))?;
+                builder.apply(look_at);
+            },
+            "Transform" | "ConcatTransform" => {
+                let m = parser.numbers(16)?;
+                let transformation = matrix_transformation(&m).ok_or_else(|| parser.error("Matrix can not be inverted".to_string()))?;
+                if directive == "Transform" {
+                    builder.state.transformation = transformation;
+                } else {
+                    builder.apply(transformation);
+                }
+            },
+            "ReverseOrientation" => builder.state.reverse_orientation = !builder.state.reverse_orientation,
+            "AttributeBegin" => builder.attributes.push(builder.state.clone()),
+            "AttributeEnd" => builder.state = builder.attributes.pop().ok_or_else(|| parser.error("AttributeEnd without AttributeBegin".to_string()))?,
+            "TransformBegin" => builder.transformations.push(builder.state.transformation.clone()),
+            "TransformEnd" => builder.state.transformation = builder.transformations.pop().ok_or_else(|| parser.error("TransformEnd without TransformBegin".to_string()))?,
+            "WorldBegin" => builder.state.transformation = Transformation::new(),
+            "WorldEnd" => (),
+            "Camera" => {
+                let kind = parser.string()?;
+                let params = parser.params()?;
+                if kind != "perspective" {
+                    println!("Camera {} is not supported, a perspective camera is used", kind);
+                }
+                builder.camera = Some((builder.state.transformation.clone(), params.number("fov", 90.0)));
+            },
+            "Film" => {
+                parser.string()?;
+                let params = parser.params()?;
+                builder.settings.screen_width = params.number("xresolution", 1280.0) as u32;
+                builder.settings.screen_height = params.number("yresolution", 720.0) as u32;
+            },
+            "Sampler" => {
+                parser.string()?;
+                let params = parser.params()?;
+                builder.settings.aa_multi_sample = (params.number("pixelsamples", 16.0).sqrt().round() as u32).max(1);
+            },
+            "Material" => {
+                let kind = parser.string()?;
+                let params = parser.params()?;
+                builder.state.material = PbrtMaterial::new(&kind, &params);
+            },
+            "AreaLightSource" => {
+                let kind = parser.string()?;
+                let params = parser.params()?;
+                if kind != "diffuse" {
+                    println!("Area light {} is not supported, a diffuse light is used", kind);
+                }
+                builder.state.area_light = Some(params.color("L", Color::gray_scale(1.0))*params.color("scale", Color::gray_scale(1.0)));
+            },
+            "LightSource" => {
+                let kind = parser.string()?;
+                let params = parser.params()?;
+                builder.light(&kind, &params);
+            },
+            "Shape" => {
+                let kind = parser.string()?;
+                let params = parser.params()?;
+                builder.shape(&kind, &params, directory, |message| parser.error(message))?;
+            },
+            "Include" => {
+                let name = parser.string()?;
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(parser.error("Includes are nested too deeply".to_string()));
+                }
+                read_file(&directory.join(name).to_string_lossy(), builder, depth + 1)?;
+            },
+            _ => {
+                println!("Skipped unsupported directive: {}", directive);
+                parser.skip_arguments();
+            }
+        }
+    }
+    Ok(())
+}
+
+// The camera space of PBRT is left handed, the scene is mirrored along x to keep the image the same
+fn mirror() -> Transformation {
+    Transformation::new().scale(-1.0, 1.0, 1.0)
+}
+
+// Transformation from world to camera space
+fn look_at(eye: Point, look: Point, up: Vector) -> Option<Transformation> {
+    let direction = (look - eye).normalize();
+    let right = up.normalize().cross(&direction);
+    if !right.length().is_normal() { return None }
+    let right = right.normalize();
+    let up = direction.cross(&right);
+    let camera_to_world = Matrix::from([
+        [right.x, up.x, direction.x, eye.x],
+        [right.y, up.y, direction.y, eye.y],
+        [right.z, up.z, direction.z, eye.z],
+        [0.0, 0.0, 0.0, 1.0]
+    ]);
+    Transformation::from_matrix(camera_to_world).map(|t| t.inverse())
+}
+
+// Matrices are written column by column
+fn matrix_transformation(m: &[f64]) -> Option<Transformation> {
+    Transformation::from_matrix(Matrix::from([
+        [m[0], m[4], m[8], m[12]],
+        [m[1], m[5], m[9], m[13]],
+        [m[2], m[6], m[10], m[14]],
+        [0.0, 0.0, 0.0, 1.0]
+    ]))
+}
+
+//////////////////
+//Builder
+//////////////////
+#[derive(Clone)]
+enum PbrtMaterial {
+    Matte(Color),
+    Mirror(Color),
+    Glass(f64, Color)
+}
+
+impl PbrtMaterial {
+    fn new(kind: &str, params: &Params) -> PbrtMaterial {
+        match kind {
+            "matte" => PbrtMaterial::Matte(params.color("Kd", Color::gray_scale(0.5))),
+            "mirror" => PbrtMaterial::Mirror(params.color("Kr", Color::gray_scale(0.9))),
+            "glass" => PbrtMaterial::Glass(params.numbers("index").or(params.numbers("eta")).and_then(|n| n.first().cloned()).unwrap_or(1.5), params.color("Kt", Color::gray_scale(1.0))),
+            _ => {
+                println!("Material {} is not supported, a matte material is used", kind);
+                PbrtMaterial::Matte(params.color("Kd", Color::gray_scale(0.5)))
+            }
+        }
+    }
+
+    // Glass is approximated by a thin sheet and mirrors by a very sharp specular lobe
+    fn create(&self) -> Box<dyn Material> {
+        match self {
+            PbrtMaterial::Matte(kd) => Box::new(Lambertian::new(*kd)),
+            PbrtMaterial::Mirror(kr) => Box::new(Phong::new(Color::black(), *kr, 1e4).with_mirror()),
+            PbrtMaterial::Glass(ior, kt) => Box::new(ThinDielectric::new(*ior, *kt))
+        }
+    }
+}
+
+#[derive(Clone)]
+struct GraphicsState {
+    transformation: Transformation,
+    material: PbrtMaterial,
+    area_light: Option<Color>,
+    reverse_orientation: bool
+}
+
+struct Builder {
+    instances: Vec<Instance>,
+    lights: Vec<Box<dyn Light>>,
+    camera: Option<(Transformation, f64)>,
+    settings: Settings,
+    state: GraphicsState,
+    attributes: Vec<GraphicsState>,
+    transformations: Vec<Transformation>
+}
+
+impl Builder {
+    // PBRT composes transformations from the right, they apply before the current ones
+    fn apply(&mut self, transformation: Transformation) {
+        self.state.transformation = transformation.then(&self.state.transformation);
+    }
+
+    fn world(&self) -> Transformation {
+        self.state.transformation.clone().then(&mirror())
+    }
+
+    // PBRT cameras look along z, their field of view belongs to the shorter side of the image
+    fn create_camera(&self, world_to_camera: Transformation, fov: f64) -> PerspectiveCamera {
+        let matrix = world_to_camera.inverse().then(&mirror()).matrix();
+        let (width, height) = (self.settings.screen_width as f64, self.settings.screen_height as f64);
+        let fov = if width > height { 2.0*((fov.to_radians()/2.0).tan()*width/height).atan().to_degrees() } else { fov };
+        PerspectiveCamera::new(matrix*Point::origin(), matrix*Direction::posz(), matrix*Direction::up(), fov)
+    }
+
+    fn light(&mut self, kind: &str, params: &Params) {
+        let matrix = self.world().matrix();
+        let point = |name: &str, default: Point| params.numbers(name).filter(|p| p.len() == 3).map_or(default, |p| Point::new(p[0], p[1], p[2]));
+        let (from, to) = (point("from", Point::origin()), point("to", Point::new(0.0, 0.0, 1.0)));
+        let scale = params.color("scale", Color::gray_scale(1.0));
+
+        match kind {
+            "point" => {
+                let intensity = params.color("I", Color::gray_scale(1.0))*scale;
+                self.lights.push(Box::new(PointLight::new(matrix*from, 4.0*PI, intensity)));
+            },
+            "spot" => {
+                let intensity = params.color("I", Color::gray_scale(1.0))*scale;
+                let outer = params.number("coneangle", 30.0).to_radians();
+                let inner = outer - params.number("conedelta", 5.0).to_radians();
+                let power = 2.0*PI*(1.0 - 0.5*(inner.cos() + outer.cos()));
+                self.lights.push(Box::new(SpotLight::new(matrix*from, Direction::from(matrix*(to - from)), inner, outer, power, intensity)));
+            },
+            "distant" => {
+                let radiance = params.color("L", Color::gray_scale(1.0))*scale;
+                self.lights.push(Box::new(DistantLight::new(Direction::from(matrix*(to - from)), 1.0, radiance)));
+            },
+            _ => println!("Light source {} is not supported", kind)
+        }
+    }
+
+    fn shape<E>(&mut self, kind: &str, params: &Params, directory: &Path, error: E) -> Result<(), ImportError> where E: Fn(String) -> ImportError {
+        let world = self.world();
+        match kind {
+            "sphere" => {
+                let radius = params.number("radius", 1.0);
+                match self.state.area_light {
+                    // emitting spheres become point lights with the power of the sphere
+                    Some(radiance) => {
+                        let radius = (world.matrix()*Vector::new(radius, 0.0, 0.0)).length();
+                        self.lights.push(Box::new(PointLight::new(world.matrix()*Point::origin(), 4.0*PI*PI*radius*radius, radiance)));
+                    },
+                    None => {
+                        let sphere = Arc::new(Sphere::new(self.state.material.create()));
+                        self.instances.push(Instance::transformed(sphere, Transformation::new().scale_all(radius).then(&world)));
+                    }
+                }
+            },
+            "trianglemesh" => {
+                let points = params.numbers("P").filter(|p| !p.is_empty() && p.len() % 3 == 0).ok_or_else(|| error("Triangle mesh without points P".to_string()))?;
+                let vertices: Vec<Point> = points.chunks(3).map(|p| Point::new(p[0], p[1], p[2])).collect();
+                let indices = match params.numbers("indices") {
+                    Some(indices) => indices.iter().map(|i| *i as usize).collect(),
+                    None if vertices.len() == 3 => vec![0, 1, 2],
+                    None => return Err(error("Triangle mesh without indices".to_string()))
+                };
+                if indices.len() % 3 != 0 || indices.iter().any(|i| *i >= vertices.len()) {
+                    return Err(error("Triangle mesh indices out of range".to_string()));
+                }
+                let triangles: Vec<[usize; 3]> = indices.chunks(3).map(|t| [t[0], t[1], t[2]]).collect();
+
+                if let Some(radiance) = self.state.area_light {
+                    match quad_light(&vertices, &triangles, self.state.reverse_orientation, &world, radiance) {
+                        Some(light) => self.lights.push(light),
+                        None => println!("Area lights are only supported on spheres and quads, the shape is skipped")
+                    }
+                    return Ok(());
+                }
+
+                let normals: Vec<Normal> = params.numbers("N").filter(|n| n.len() == points.len())
+                    .map_or(vec![], |n| n.chunks(3).map(|n| Normal::new(n[0], n[1], n[2])).collect());
+                let uvs: Vec<(f64, f64)> = params.numbers("uv").or(params.numbers("st")).filter(|uv| uv.len() == 2*vertices.len())
+                    .map_or(vec![], |uv| uv.chunks(2).map(|uv| (uv[0], uv[1])).collect());
+                let mesh = Mesh::new(vertices, normals.clone(), uvs.clone(), triangles.iter().map(|t| IndexedTriangle{
+                    vertices: t.map(|i| i as u32),
+                    normals: if normals.is_empty() { None } else { Some(t.map(|i| i as u32)) },
+                    uvs: if uvs.is_empty() { None } else { Some(t.map(|i| i as u32)) },
+                    material: 0
+                }).collect(), vec![self.state.material.create()]);
+                self.add_mesh(mesh, world);
+            },
+            "plymesh" => {
+                let file_name = params.string("filename").ok_or_else(|| error("PLY mesh without filename".to_string()))?;
+                if self.state.area_light.is_some() {
+                    println!("Area lights are only supported on spheres and quads, the shape is skipped");
+                    return Ok(());
+                }
+                let mut mesh = parse_ply(&directory.join(file_name).to_string_lossy())?;
+                mesh.set_material(self.state.material.create());
+                self.add_mesh(mesh, world);
+            },
+            _ => println!("Shape {} is not supported", kind)
+        }
+        Ok(())
+    }
+
+    // Shapes are visible from both sides in PBRT
+    fn add_mesh(&mut self, mut mesh: Mesh, transformation: Transformation) {
+        mesh.set_double_sided(true);
+        self.instances.push(Instance::transformed(Arc::new(mesh), transformation));
+    }
+}
+
+// Quads become surface lights, the unit square of the light is mapped onto the parallelogram.
+// Light leaves on the side of the first triangle's normal.
+fn quad_light(vertices: &[Point], triangles: &[[usize; 3]], reverse_orientation: bool, world: &Transformation, radiance: Color) -> Option<Box<dyn Light>> {
+    if vertices.len() != 4 || triangles.len() != 2 { return None }
+    let p0 = vertices[0];
+    let size = (1..4).map(|i| (vertices[i] - p0).length()).fold(0.0, f64::max);
+    // the corner opposite of the first one is the sum of the two edges
+    let (u, v) = (1..4).find_map(|opposite| {
+        let others: Vec<usize> = (1..4).filter(|i| *i != opposite).collect();
+        let (u, v) = (vertices[others[0]] - p0, vertices[others[1]] - p0);
+        if (vertices[opposite] - (p0 + u + v)).length() <= 1e-6*size { Some((u, v)) } else { None }
+    })?;
+
+    let [a, b, c] = triangles[0].map(|i| vertices[i]);
+    let normal = (b - a).cross(&(c - a));
+    if !normal.length().is_normal() { return None }
+    let normal = if reverse_orientation { normal.normalize().invert() } else { normal.normalize() };
+    let transformation = Transformation::from_matrix(Matrix::from([
+        [u.x, normal.x, v.x, p0.x],
+        [u.y, normal.y, v.y, p0.y],
+        [u.z, normal.z, v.z, p0.z],
+        [0.0, 0.0, 0.0, 1.0]
+    ]))?.then(world);
+
+    let matrix = world.matrix();
+    let area = (matrix*u).cross(&(matrix*v)).length();
+    let surface = Rectangle::unit_square(false, Box::new(Lambertian::new(Color::black())));
+    Some(Box::new(SurfaceLight::transformed(surface, transformation, 2.0*PI*area, radiance)))
+}
+
+//////////////////
+//Parser
+//////////////////
+#[derive(Clone, Debug)]
+enum Token {
+    Word(String),
+    Str(String),
+    Number(f64),
+    Open,
+    Close
+}
+
+fn tokenize(file_path: &str, text: &str) -> Result<Vec<(Token, usize)>, ImportError> {
+    let bytes = text.as_bytes();
+    let mut tokens = vec![];
+    let (mut i, mut line) = (0, 1);
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\n' => {
+                line += 1;
+                i += 1;
+            },
+            b' ' | b'\t' | b'\r' => i += 1,
+            b'#' => while i < bytes.len() && bytes[i] != b'\n' { i += 1 },
+            b'[' => {
+                tokens.push((Token::Open, line));
+                i += 1;
+            },
+            b']' => {
+                tokens.push((Token::Close, line));
+                i += 1;
+            },
+            b'"' => {
+                let start = i + 1;
+                let end = bytes[start..].iter().position(|b| *b == b'"' || *b == b'\n').map(|p| start + p);
+                match end {
+                    Some(end) if bytes[end] == b'"' => {
+                        tokens.push((Token::Str(text[start..end].to_string()), line));
+                        i = end + 1;
+                    },
+                    _ => return Err(ImportError::parse(file_path, line, "Unterminated string".to_string()))
+                }
+            },
+            _ => {
+                let start = i;
+                while i < bytes.len() && !matches!(bytes[i], b' ' | b'\t' | b'\r' | b'\n' | b'[' | b']' | b'"' | b'#') { i += 1 }
+                let word = &text[start..i];
+                tokens.push((word.parse().map_or(Token::Word(word.to_string()), Token::Number), line));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Parameters are written as "type name" followed by a value or a list of values in brackets
+struct Param {
+    name: String,
+    kind: String,
+    numbers: Vec<f64>,
+    strings: Vec<String>
+}
+
+struct Params(Vec<Param>);
+
+impl Params {
+    fn find(&self, name: &str) -> Option<&Param> {
+        self.0.iter().find(|p| p.name == name)
+    }
+
+    fn numbers(&self, name: &str) -> Option<&[f64]> {
+        self.find(name).filter(|p| !p.numbers.is_empty()).map(|p| &p.numbers[..])
+    }
+
+    fn number(&self, name: &str, default: f64) -> f64 {
+        self.numbers(name).and_then(|n| n.first().cloned()).unwrap_or(default)
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        self.find(name).and_then(|p| p.strings.first()).map(|s| s.as_str())
+    }
+
+    // Only rgb colors are supported, spectra and textures fall back to the default
+    fn color(&self, name: &str, default: Color) -> Color {
+        match self.find(name) {
+            Some(p) if (p.kind == "rgb" || p.kind == "color") && p.numbers.len() == 3 => Color::new_rgb(p.numbers[0], p.numbers[1], p.numbers[2]),
+            Some(p) if p.kind == "float" && p.numbers.len() == 1 => Color::gray_scale(p.numbers[0]),
+            Some(p) => {
+                println!("Parameter {} {} is not supported, the default is used", p.kind, p.name);
+                default
+            },
+            None => default
+        }
+    }
+}
+
+struct Parser<'a> {
+    file_path: &'a str,
+    tokens: Vec<(Token, usize)>,
+    position: usize
+}
+
+impl<'a> Parser<'a> {
+    // Errors refer to the line of the last read token
+    fn error(&self, message: String) -> ImportError {
+        let line = self.tokens.get(self.position.max(1) - 1).map_or(1, |(_, line)| *line);
+        ImportError::parse(self.file_path, line, message)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        if token.is_some() { self.position += 1 }
+        token
+    }
+
+    fn string(&mut self) -> Result<String, ImportError> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(s),
+            _ => Err(self.error("Expected a string".to_string()))
+        }
+    }
+
+    // The numbers may be enclosed in brackets
+    fn numbers(&mut self, count: usize) -> Result<Vec<f64>, ImportError> {
+        let bracketed = matches!(self.peek(), Some(Token::Open));
+        if bracketed { self.position += 1 }
+        let mut numbers = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self.next() {
+                Some(Token::Number(n)) => numbers.push(n),
+                _ => return Err(self.error(format!("Expected {} numbers", count)))
+            }
+        }
+        if bracketed && !matches!(self.next(), Some(Token::Close)) {
+            return Err(self.error("Expected ']'".to_string()));
+        }
+        Ok(numbers)
+    }
+
+    fn params(&mut self) -> Result<Params, ImportError> {
+        let mut params = vec![];
+        while let Some(Token::Str(declaration)) = self.peek().cloned() {
+            self.position += 1;
+            let mut parts = declaration.split_whitespace();
+            let (kind, name) = match (parts.next(), parts.next()) {
+                (Some(kind), Some(name)) => (kind.to_string(), name.to_string()),
+                _ => return Err(self.error(format!("Invalid parameter declaration: {}", declaration)))
+            };
+
+            let mut values = vec![];
+            match self.next() {
+                Some(Token::Open) => loop {
+                    match self.next() {
+                        Some(Token::Close) => break,
+                        Some(Token::Open) | None => return Err(self.error(format!("Unterminated values of {}", name))),
+                        Some(value) => values.push(value)
+                    }
+                },
+                Some(Token::Close) | None => return Err(self.error(format!("Missing value of {}", name))),
+                Some(value) => values.push(value)
+            }
+            let numbers = values.iter().filter_map(|v| if let Token::Number(n) = v { Some(*n) } else { None }).collect();
+            let strings = values.into_iter().filter_map(|v| match v {
+                Token::Str(s) | Token::Word(s) => Some(s),
+                _ => None
+            }).collect();
+            params.push(Param{name, kind, numbers, strings});
+        }
+        Ok(Params(params))
+    }
+
+    // Everything up to the next directive
+    fn skip_arguments(&mut self) {
+        while let Some(token) = self.peek() {
+            if let Token::Word(_) = token { break }
+            self.position += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Direction;
+
+    fn parse(name: &str, text: &str) -> Result<(Scene, Settings), ImportError> {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, text).unwrap();
+        let scene = parse_pbrt(&path.to_string_lossy());
+        fs::remove_file(path).unwrap();
+        scene
+    }
+
+    fn reflectance(instance: &Instance) -> (f64, f64, f64) {
+        instance.material().brdf(Direction::up(), Direction::up()).rgb()
+    }
+
+    #[test]
+    fn objects_keep_their_side_of_the_image() {
+        // PBRT shows +x on the right for this camera, the renderer has to as well after mirroring
+        let (scene, _) = parse("pbrt_look_at.pbrt", "LookAt 0 0 -5  0 0 0  0 1 0\nCamera \"perspective\" \"float fov\" 45\n\
+            WorldBegin\nTranslate 2 1 0\nShape \"sphere\" \"float radius\" 0.5\nWorldEnd\n").unwrap();
+        let camera = scene.camera();
+        assert!((camera.position() - Point::new(0.0, 0.0, -5.0)).length() < 1e-9);
+        let right = camera.direction().cross(&camera.up());
+        let to_sphere = scene.instances()[0].bounding_box().centroid() - camera.position();
+        assert!(to_sphere.dot(&right) > 1.0);
+        assert!(to_sphere.dot(&camera.up()) > 0.5);
+        assert!(to_sphere.dot(&camera.direction()) > 4.0);
+    }
+
+    #[test]
+    fn attributes_are_restored() {
+        let (scene, _) = parse("pbrt_attributes.pbrt", "LookAt 0 0 -5  0 0 0  0 1 0\nCamera \"perspective\"\nWorldBegin\n\
+            AttributeBegin\n  Translate 5 0 0\n  Material \"matte\" \"rgb Kd\" [1 0 0]\n  Shape \"sphere\"\nAttributeEnd\n\
+            TransformBegin\n  Translate 0 3 0\nTransformEnd\nShape \"sphere\"\nWorldEnd\n").unwrap();
+        let instances = scene.instances();
+        let centers: Vec<Point> = instances.iter().map(|inst| inst.bounding_box().centroid()).collect();
+        assert!((centers[0] - Point::new(-5.0, 0.0, 0.0)).length() < 1e-9);
+        assert!((centers[1] - Point::origin()).length() < 1e-9);
+
+        let (r, g, _) = reflectance(instances[0]);
+        assert!(r > 0.0 && g == 0.0);
+        let (r, g, b) = reflectance(instances[1]);
+        assert!(r > 0.0 && r == g && g == b);
+
+        let unbalanced = parse("pbrt_unbalanced.pbrt", "Camera \"perspective\"\nWorldBegin\nAttributeEnd\n");
+        assert!(matches!(unbalanced, Err(ImportError::Parse{line: 3, ..})));
+    }
+
+    #[test]
+    fn film_resolution_defaults_to_pbrt() {
+        let (_, settings) = parse("pbrt_film.pbrt", "Film \"image\"\nSampler \"halton\" \"integer pixelsamples\" 16\nCamera \"perspective\"\nWorldBegin\nWorldEnd\n").unwrap();
+        assert_eq!((settings.screen_width, settings.screen_height, settings.aa_multi_sample), (1280, 720, 4));
+        let (_, settings) = parse("pbrt_film_width.pbrt", "Film \"image\" \"integer xresolution\" 400\nCamera \"perspective\"\nWorldBegin\nWorldEnd\n").unwrap();
+        assert_eq!((settings.screen_width, settings.screen_height), (400, 720));
+    }
+}
@@ -17,8 +17,9 @@ use crate::settings;
 use crate::thread_pool::{ThreadPool};
 use crate::camera::{Pixel, PerspectiveCamera};
 use crate::scene::{Scene, Intersection};
+use crate::objects::Instance;
 use crate::math::{Point};
-use crate::cg_tools::{Color, Radiance};
+use crate::cg_tools::{Color, Radiance, Ray};
 
 pub fn render(scene: Scene){
     let scene = Arc::new(scene);
@@ -47,12 +48,16 @@ fn init_threads(chunks: &mut dyn Iterator<Item=(u32,u32)>, thread_pool: &ThreadP
             let h = h*chunk_height;
             let till_h = if height-h < chunk_height {h+(height-h)} else {h+chunk_height};
 
-            for y in h..till_h{
-                let w = w*chunk_width;
-                let till_w = if width-w < chunk_width {w+(width-w)} else {w+chunk_width};
+            let w = w*chunk_width;
+            let till_w = if width-w < chunk_width {w+(width-w)} else {w+chunk_width};
 
-                for x in w..till_w{
-                    pixels.push(calucate_pixel(Pixel{x: x as i32,y: y as i32}, &scene));
+            // camera rays of neighbouring pixels are traced together in square packets
+            let packet_size = settings.packet_size.max(1);
+            for packet_y in (h..till_h).step_by(packet_size as usize){
+                for packet_x in (w..till_w).step_by(packet_size as usize){
+                    let packet: Vec<Pixel> = iproduct!(packet_y..till_h.min(packet_y+packet_size), packet_x..till_w.min(packet_x+packet_size))
+                        .map(|(y,x)| Pixel{x: x as i32,y: y as i32}).collect();
+                    pixels.extend(calculate_packet(packet, &scene));
                 }
             }
             sender_clone.send(ChunkFinished::Chunk(pixels)).unwrap();
@@ -75,17 +80,29 @@ pub enum RenderMode {
     BoundingBox
 }
 
-fn calucate_pixel(pixel: Pixel, scene: &Scene) -> (Pixel, Color) {
+fn calculate_packet(mut pixels: Vec<Pixel>, scene: &Scene) -> Vec<(Pixel, Color)> {
+    if pixels.len() == 1 {
+        let pixel = pixels.remove(0);
+        let rays = scene.camera().rays_for_pixel(&pixel);
+        let intersections = rays.iter().map(|ray| scene.intersect(ray)).collect();
+        return vec![calucate_pixel(pixel, &rays, intersections, scene)];
+    }
+
+    let pixel_rays: Vec<Vec<Ray>> = pixels.iter().map(|pixel| scene.camera().rays_for_pixel(pixel)).collect();
+    let rays: Vec<Ray> = pixel_rays.concat();
+    let mut intersections = scene.intersect_packet(&rays).into_iter();
+    pixels.into_iter().zip(pixel_rays.iter()).map(|(pixel, rays)| {
+        let pixel_intersections = intersections.by_ref().take(rays.len()).collect();
+        calucate_pixel(pixel, rays, pixel_intersections, scene)
+    }).collect()
+}
+
+fn calucate_pixel(pixel: Pixel, rays: &[Ray], intersections: Vec<Option<Intersection>>, scene: &Scene) -> (Pixel, Color) {
     let settings = settings::get();
     let mut intersect = None;
-    let rad = scene.camera().rays_for_pixel(&pixel).iter().map(|ray|{
-        (scene.intersect(ray), ray.direction())
-    }).map(|(int,dir)|{
+    let rad = rays.iter().zip(intersections).map(|(ray,int)|{
         intersect = int.clone();
-        match int {
-            None => Radiance::zero(),
-            Some(i) => scene.receive_radiance(i, dir.invert())
-        }
+        scene.receive_radiance(ray, int)
     }).fold(Radiance::zero(), |acc,rad|{
         acc + rad
     }) * (1.0/(settings.aa_multi_sample.pow(2)) as f64);
@@ -141,48 +158,10 @@ fn run_program_loop(scene: Arc<Scene>){
     // let mut canvas = window.into_canvas().build().unwrap();
 
     canvas.present();
-
-    //START
-    let now = Instant::now();
-
-    let (sender, receiver) = mpsc::channel();
-    let mut thread_pool = ThreadPool::new(settings.amt_threads);
-    let mut chunks = iproduct!(0..(settings.screen_height/settings.chunk_height)+1, 0..(settings.screen_width/settings.chunk_width)+1).peekable();
-    init_threads(&mut chunks, &mut thread_pool,&scene, &sender);
-    thread_pool.finish_jobs();
+    render_image(&scene, &mut canvas);
 
     let mut quit = false;
-    let mut finished_jobs = 0;
     while !quit {
-        if finished_jobs < settings.amt_threads {
-            while let Ok(chunk_finished) = receiver.recv() {
-                match chunk_finished {
-                    ChunkFinished::Done => {
-                        finished_jobs += 1;
-                        if finished_jobs == settings.amt_threads {
-                            let elapsed = now.elapsed();
-                            println!("Done.");
-                            println!("Elapsed time {}.{}s", elapsed.as_secs(), elapsed.subsec_millis());
-                            super::statistics::print_statistics();
-                            quit = true;
-                            break;
-                        }
-                    },
-                    ChunkFinished::Chunk(pixels) => {
-                        for (pixel,color) in pixels{
-                            let (r,g,b) = color.rgb();
-                            let r = (r*255.0) as u8;
-                            let g = (g*255.0) as u8;
-                            let b = (b*255.0) as u8;
-                            canvas.set_draw_color(sdl2::pixels::Color::RGB(r,g,b));
-                            canvas.draw_point(sdl2::rect::Point::new(pixel.x,pixel.y)).unwrap();
-                        }
-                        canvas.present();
-                    }
-                }
-            }
-        }
-
         let mut event_pump = sdl_context.event_pump().unwrap();
         let mut it = event_pump.poll_iter();
         while let Some(e) = it.next() {
@@ -195,4 +174,63 @@ fn run_program_loop(scene: Arc<Scene>){
 
     std::fs::create_dir_all("img").unwrap();
     canvas.surface().save_bmp("img/result_img.bmp").unwrap();
-}
\ No newline at end of file
+}
+
+// Renders frames one after another, between two frames the callback moves the instances by their index in the
+// list the scene was created from and the acceleration structure is refitted instead of rebuilt where possible.
+pub fn render_animation<F>(mut scene: Scene, frames: usize, mut animate: F) where F: FnMut(usize, usize, &mut Instance) {
+    let settings = settings::get();
+    let surface = Surface::new(settings.screen_width as u32, settings.screen_height as u32, PixelFormatEnum::RGB888).unwrap();
+    let mut canvas = surface.into_canvas().unwrap();
+    std::fs::create_dir_all("img").unwrap();
+
+    for frame in 0..frames {
+        if frame > 0 {
+            scene.update_instances(|index, inst| animate(frame, index, inst));
+        }
+        let shared = Arc::new(scene);
+        render_image(&shared, &mut canvas);
+        canvas.surface().save_bmp(format!("img/frame_{:04}.bmp", frame)).unwrap();
+        // the workers dropped their references once they reported being done
+        scene = Arc::try_unwrap(shared).unwrap_or_else(|_| panic!("Scene is still in use after rendering a frame"));
+    }
+}
+
+fn render_image(scene: &Arc<Scene>, canvas: &mut SurfaceCanvas){
+    let settings = settings::get();
+    //START
+    let now = Instant::now();
+
+    let (sender, receiver) = mpsc::channel();
+    let thread_pool = ThreadPool::new(settings.amt_threads);
+    let mut chunks = iproduct!(0..(settings.screen_height/settings.chunk_height)+1, 0..(settings.screen_width/settings.chunk_width)+1).peekable();
+    init_threads(&mut chunks, &thread_pool, scene, &sender);
+    thread_pool.finish_jobs();
+
+    let mut finished_jobs = 0;
+    while let Ok(chunk_finished) = receiver.recv() {
+        match chunk_finished {
+            ChunkFinished::Done => {
+                finished_jobs += 1;
+                if finished_jobs == settings.amt_threads {
+                    let elapsed = now.elapsed();
+                    println!("Done.");
+                    println!("Elapsed time {}.{}s", elapsed.as_secs(), elapsed.subsec_millis());
+                    super::statistics::print_statistics();
+                    break;
+                }
+            },
+            ChunkFinished::Chunk(pixels) => {
+                for (pixel,color) in pixels{
+                    let (r,g,b) = color.rgb();
+                    let r = (r*255.0) as u8;
+                    let g = (g*255.0) as u8;
+                    let b = (b*255.0) as u8;
+                    canvas.set_draw_color(sdl2::pixels::Color::RGB(r,g,b));
+                    canvas.draw_point(sdl2::rect::Point::new(pixel.x,pixel.y)).unwrap();
+                }
+                canvas.present();
+            }
+        }
+    }
+}
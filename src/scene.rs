@@ -1,56 +1,204 @@
 
 use crate::settings;
 use crate::math::{Point, Direction, Normal, EPSILON};
-use crate::cg_tools::{Ray,Transformation,Radiance};
-use crate::objects::{Instance, Light, Material};
+use crate::cg_tools::{Ray,Transformation,Radiance,Color};
+use crate::objects::{Instance, Light, Material, Lambertian, Subsurface, Medium, MediumSample};
 use crate::acceleration::{self, AccelerationStructure};
 use crate::camera::PerspectiveCamera;
 
 pub struct Scene {
     acc_structure: Box<dyn AccelerationStructure>,
     lights : Vec<Box<dyn Light>>,
-    camera: PerspectiveCamera
+    camera: PerspectiveCamera,
+    medium: Option<Box<dyn Medium>>
 }
 
+const MAX_SHADOW_CROSSINGS: u32 = 16;
+
 impl Scene {
     pub fn new(instances : Vec<Instance>, lights : Vec<Box<dyn Light>>, camera: PerspectiveCamera) -> Scene {
         let acc_structure = acceleration::create_acceleration_structure(instances);
-        Scene {acc_structure, lights, camera}
+        Scene {acc_structure, lights, camera, medium: None}
+    }
+
+    pub fn with_medium(mut self, medium: Box<dyn Medium>) -> Scene {
+        self.medium = Some(medium);
+        self
     }
 
     pub fn camera(&self) -> &PerspectiveCamera {
         &self.camera
     }
 
+    pub fn medium(&self) -> Option<&dyn Medium> {
+        self.medium.as_deref()
+    }
+
+    pub fn instances(&self) -> Vec<&Instance> {
+        self.acc_structure.instances()
+    }
+
+    pub fn lights(&self) -> &[Box<dyn Light>] {
+        &self.lights
+    }
+
+    // Animates the scene, the callback gets every instance with its index in the list the scene was created from
+    pub fn update_instances<F>(&mut self, mut update: F) where F: FnMut(usize, &mut Instance) {
+        self.acc_structure.update(&mut update);
+    }
+
     pub fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         self.acc_structure.intersect(ray)
     }
 
+    pub fn intersect_packet(&self, rays: &[Ray]) -> Vec<Option<Intersection<'_>>> {
+        self.acc_structure.intersect_packet(rays)
+    }
+
     pub fn visible(&self, from: Point, to: Point) -> bool {
         self.acc_structure.visible(from, to)
     }
 
-    pub fn receive_radiance(&self, intersection: Intersection, outgoing: Direction) -> Radiance{
+    pub fn receive_radiance(&self, ray: &Ray, intersection: Option<Intersection>) -> Radiance{
+        self.trace(ray, intersection, self.medium(), 0)
+    }
+
+    fn trace(&self, ray: &Ray, intersection: Option<Intersection>, medium: Option<&dyn Medium>, depth: u32) -> Radiance{
+        let outgoing = ray.direction().invert();
+        let mut weight = Radiance::gray_scale(1.0);
+        let mut in_scattered = Radiance::zero();
+        if let Some(medium) = medium {
+            let t_max = intersection.map_or(f64::INFINITY, |int| int.t());
+            match settings::get().volume_sampling {
+                VolumeSampling::FreePath => match medium.sample(ray, t_max) {
+                    MediumSample::Scatter{t, weight} => {
+                        let point = ray.origin() + t**ray.direction();
+                        let rad = self.direct_lighting(point, None, Some(medium), |incoming| Color::gray_scale(medium.phase(incoming, outgoing)));
+                        return rad*Radiance::from(weight);
+                    },
+                    MediumSample::Pass{weight: w} => weight = Radiance::from(w)
+                },
+                VolumeSampling::Equiangular => {
+                    in_scattered = self.equiangular_scattering(ray, t_max, medium);
+                    if intersection.is_some() {
+                        weight = Radiance::from(medium.transmittance(ray, t_max));
+                    }
+                }
+            }
+        }
+
+        match intersection {
+            None => in_scattered,
+            Some(int) => in_scattered + weight*self.surface_radiance(int, outgoing, medium, depth)
+        }
+    }
+
+    fn equiangular_scattering(&self, ray: &Ray, t_max: f64, medium: &dyn Medium) -> Radiance{
+        let (origin, direction) = (ray.origin(), ray.direction());
+        let mut radiance = Radiance::zero();
+        for light in &self.lights {
+            let mut rad = Radiance::zero();
+            let light_points = light.light_points(settings::get().light_sampling_technique);
+            let amount = light_points.len();
+            for (light_point, opt_normal) in light_points{
+                // sample proportional to the inverse squared distance to the light point
+                let delta = (light_point - origin).dot(&direction);
+                let distance = (origin + delta**direction - light_point).length();
+                if distance < EPSILON { continue }
+                let theta_a = (-delta/distance).atan();
+                let theta_b = ((t_max - delta)/distance).atan();
+                let t = delta + distance*(theta_a + rand::random::<f64>()*(theta_b - theta_a)).tan();
+                let pdf = distance / ((theta_b - theta_a)*(distance*distance + (t - delta)*(t - delta)));
+
+                let point = origin + t**direction;
+                let incoming = Direction::from(light_point - point);
+                let light_normal = match opt_normal { Some(n) => n, None => Normal::from(*incoming.invert()) };
+                let transmittance = match self.transmittance(point, light_point + EPSILON**light_normal, Some(medium)) {
+                    Some(transmittance) => transmittance*medium.transmittance(ray, t),
+                    None => continue
+                };
+
+                let r = (light_point - point).length();
+                let cos_light = light_normal.dot(&incoming.invert()).max(0.0);
+                let phase = medium.phase(incoming, direction.invert());
+                let factor = phase*cos_light/(r*r*pdf);
+                let rad_from_light = light.radiance_towards(light_point, incoming.invert());
+                rad = rad + factor*(rad_from_light*Radiance::from(medium.scattering(point)*transmittance));
+            }
+
+            radiance = radiance + rad*(1.0/amount as f64);
+        }
+        radiance
+    }
+
+    fn surface_radiance(&self, intersection: Intersection, outgoing: Direction, medium: Option<&dyn Medium>, depth: u32) -> Radiance{
+        let mut radiance = self.direct_radiance(&intersection, outgoing, medium);
+        if depth >= settings::get().max_depth { return radiance }
+
+        for (direction, weight) in intersection.material().scatter(outgoing, intersection.normal()) {
+            let transmitted = direction.dot(&intersection.normal()).signum() != outgoing.dot(&intersection.normal()).signum();
+            let next_medium = if transmitted { self.crossed_medium(&intersection, medium) } else { medium };
+            let ray = Ray::new(offset_point(&intersection, direction), direction);
+            let int = self.intersect(&ray);
+            if int.is_none() && next_medium.is_none() { continue }
+
+            let rad = self.trace(&ray, int, next_medium, depth + 1);
+            radiance = radiance + rad*Radiance::from(weight);
+        }
+        radiance
+    }
+
+    fn crossed_medium<'a>(&'a self, intersection: &Intersection<'a>, current: Option<&'a dyn Medium>) -> Option<&'a dyn Medium> {
+        let interior = match intersection.instance().and_then(|instance| instance.medium()) {
+            Some(interior) => interior,
+            None => return current
+        };
+        match current {
+            Some(current) if std::ptr::addr_eq(current, interior) => self.medium(),
+            _ => Some(interior)
+        }
+    }
+
+    fn direct_radiance(&self, intersection: &Intersection, outgoing: Direction, medium: Option<&dyn Medium>) -> Radiance{
+        let material = intersection.material();
+        let (normal, uv, color) = (intersection.normal(), intersection.uv(), intersection.color());
+        let radiance = self.direct_lighting(intersection.point(), Some(normal), medium, |incoming| {
+            let brdf = material.surface_brdf(incoming, outgoing, normal, uv);
+            match color {
+                Some(color) => brdf*color,
+                None => brdf
+            }
+        });
+        let radiance = radiance + Radiance::from(material.surface_emission(uv));
+        match material.subsurface() {
+            Some(subsurface) => radiance + self.subsurface_radiance(intersection, subsurface, outgoing, medium),
+            None => radiance
+        }
+    }
+
+    fn direct_lighting<F>(&self, point: Point, normal: Option<Normal>, medium: Option<&dyn Medium>, brdf: F) -> Radiance where F: Fn(Direction) -> Color {
         let mut radiance = Radiance::zero();
         for light in &self.lights {
             let mut rad = Radiance::zero();
             let light_points = light.light_points(settings::get().light_sampling_technique);
             let amount = light_points.len();
             for (light_point, opt_normal) in light_points{
-                let incoming = Direction::from(light_point - intersection.point());
+                let incoming = Direction::from(light_point - point);
                 let light_normal = match opt_normal { Some(n) => n, None => Normal::from(*incoming.invert()) };
 
-                let visible = self.visible(light_point + EPSILON**light_normal, intersection.point() + EPSILON**intersection.normal());
-                if !visible { continue }
+                let receiver = match normal { Some(n) => point + EPSILON**n, None => point };
+                let transmittance = match self.transmittance(receiver, light_point + EPSILON**light_normal, medium) {
+                    Some(transmittance) => transmittance,
+                    None => continue
+                };
 
-                let r = (light_point - intersection.point()).length();
-                let cos_point = intersection.normal().dot(&incoming).max(0.0);
+                let r = (light_point - point).length();
+                let cos_point = normal.map_or(1.0, |n| n.dot(&incoming).max(0.0));
                 let cos_light = light_normal.dot(&incoming.invert()).max(0.0);
 
                 let factor = (cos_point*cos_light)/(r*r);
-                let rad_from_light = light.radiance_from_point(light_point);
-                let brdf = intersection.material().brdf(incoming, outgoing);
-                rad = rad + factor*(rad_from_light*Radiance::from(brdf));
+                let rad_from_light = light.radiance_towards(light_point, incoming.invert());
+                rad = rad + factor*(rad_from_light*Radiance::from(brdf(incoming)*transmittance));
             }
 
             radiance = radiance + rad*(1.0/amount as f64);
@@ -58,6 +206,77 @@ impl Scene {
         radiance
     }
 
+    fn transmittance(&self, from: Point, to: Point, medium: Option<&dyn Medium>) -> Option<Color> {
+        let medium_transmittance = |medium: Option<&dyn Medium>, ray: &Ray, distance: f64| {
+            medium.map_or(Color::gray_scale(1.0), |m| m.transmittance(ray, distance))
+        };
+        if self.visible(to, from) {
+            let ray = Ray::new(from, Direction::from(to - from));
+            return Some(medium_transmittance(medium, &ray, (to - from).length()));
+        }
+
+        // the segment is occluded, walk it to see if every blocker lets light through
+        let (mut origin, mut medium) = (from, medium);
+        let mut transmittance = Color::gray_scale(1.0);
+        for _ in 0..MAX_SHADOW_CROSSINGS {
+            let distance = (to - origin).length();
+            let ray = Ray::new(origin, Direction::from(to - origin)).with_interval(0.0, distance);
+            let intersection = match self.intersect(&ray) {
+                Some(int) => int,
+                None => return Some(transmittance*medium_transmittance(medium, &ray, distance))
+            };
+
+            let crossing = intersection.material().transmittance(ray.direction(), intersection.normal())?;
+            transmittance = transmittance*crossing*medium_transmittance(medium, &ray, intersection.t());
+            medium = self.crossed_medium(&intersection, medium);
+            origin = offset_point(&intersection, ray.direction());
+        }
+        None
+    }
+
+    fn subsurface_radiance(&self, intersection: &Intersection, subsurface: &Subsurface, outgoing: Direction, medium: Option<&dyn Medium>) -> Radiance{
+        let instance = match intersection.instance() {
+            Some(instance) => instance,
+            None => return Radiance::zero()
+        };
+        let normal = intersection.normal();
+        let (tangent, bitangent) = normal.orthonormal_basis();
+        let probe_height = subsurface.max_radius();
+        let diffuse = Lambertian::new(Color::gray_scale(1.0));
+
+        let amount = settings::get().subsurface_samples;
+        let mut radiance = Radiance::zero();
+        for _ in 0..amount {
+            let (radius, angle) = subsurface.sample_disk();
+            let offset = radius*angle.cos()**tangent + radius*angle.sin()**bitangent;
+            let probe = Ray::new(intersection.point() + offset + probe_height**normal, Direction::from(*normal.invert()))
+                .with_interval(0.0, 2.0*probe_height);
+            let exit = match instance.intersect(&probe) {
+                Some(exit) => exit,
+                None => continue
+            };
+
+            let distance = (exit.point() - intersection.point()).length();
+            let weight = subsurface.profile(distance) / subsurface.profile(radius);
+            let irradiance = self.direct_lighting(exit.point(), Some(exit.normal()), medium, |incoming| diffuse.brdf(incoming, outgoing));
+            radiance = radiance + weight*irradiance;
+        }
+
+        let transmittance = 1.0 - subsurface.fresnel(normal.dot(&outgoing).abs());
+        radiance*(transmittance/amount as f64)*Radiance::from(subsurface.color())
+    }
+
+}
+
+fn offset_point(intersection: &Intersection, direction: Direction) -> Point {
+    let side = if direction.dot(&intersection.normal()) < 0.0 { -EPSILON } else { EPSILON };
+    intersection.point() + side**intersection.normal()
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum VolumeSampling {
+    FreePath,
+    Equiangular
 }
 
 #[derive(Copy, Clone)]
@@ -65,18 +284,24 @@ pub struct Intersection<'a>{
     t : f64,
     point : Point,
     normal : Normal,
-    material: &'a dyn Material
+    material: &'a dyn Material,
+    instance: Option<&'a Instance>,
+    uv: Option<(f64, f64)>,
+    color: Option<Color>
 }
 
 impl<'a> Intersection<'a>{
     pub fn new(t : f64, point : Point, normal : Normal, material: &dyn Material) -> Intersection{
-        Intersection{t, point, normal, material}
+        Intersection{t, point, normal, material, instance: None, uv: None, color: None}
     }
 
     pub fn t(&self) -> f64 { self.t }
     pub fn point(&self) -> Point { self.point }
     pub fn normal(&self) -> Normal { self.normal }
     pub fn material(&self) -> &'a dyn Material { self.material }
+    pub fn instance(&self) -> Option<&'a Instance> { self.instance }
+    pub fn uv(&self) -> Option<(f64, f64)> { self.uv }
+    pub fn color(&self) -> Option<Color> { self.color }
 
     pub fn transform(mut self, transformation: &Transformation, ray: &Ray) -> Intersection<'a> {
         self.point = transformation.matrix()*self.point;
@@ -85,6 +310,21 @@ impl<'a> Intersection<'a>{
         self
     }
 
+    pub fn with_uv(mut self, uv: (f64, f64)) -> Intersection<'a> {
+        self.uv = Some(uv);
+        self
+    }
+
+    pub fn with_color(mut self, color: Color) -> Intersection<'a> {
+        self.color = Some(color);
+        self
+    }
+
+    pub fn with_instance(mut self, instance: &'a Instance) -> Intersection<'a> {
+        self.instance = Some(instance);
+        self
+    }
+
     pub fn closest_intersection(first: Option<Intersection<'a>>, second: Option<Intersection<'a>>) -> Option<Intersection<'a>>{
         if let Some(int1) = first {
             if let Some(int2) = second {
@@ -94,4 +334,87 @@ impl<'a> Intersection<'a>{
             else { Some(int1) }
         } else { second }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::objects::{Sphere, Plane, PointLight, SpotLight, Transparent, HomogeneousMedium};
+    use crate::math::Vector;
+
+    fn camera() -> PerspectiveCamera {
+        PerspectiveCamera::new(Point::new(0.0, 0.0, -5.0), Direction::new(0.0, 0.0, 1.0), Direction::up(), 60.0)
+    }
+
+    fn brightness(radiance: Radiance) -> f64 {
+        let (r, g, b) = Color::from(radiance).rgb();
+        r + g + b
+    }
+
+    #[test]
+    fn updated_instances_move_their_hits() {
+        let sphere = Arc::new(Sphere::new(Box::new(Lambertian::new(Color::gray_scale(1.0)))));
+        let mut scene = Scene::new(vec![Instance::new(sphere)], vec![], camera());
+        let ray = Ray::new(Point::new(0.0, 0.0, -5.0), Direction::new(0.0, 0.0, 1.0));
+        assert!((scene.intersect(&ray).unwrap().t() - 4.0).abs() < 1e-9);
+
+        scene.update_instances(|index, inst| {
+            assert_eq!(index, 0);
+            inst.set_transformation(inst.transformation().clone().translate(Vector::new(0.0, 0.0, 2.0)));
+        });
+        assert!((scene.intersect(&ray).unwrap().t() - 6.0).abs() < 1e-9);
+        assert!(scene.visible(Point::new(0.0, 0.0, -5.0), Point::new(0.0, 0.0, 0.5)));
+    }
+
+    #[test]
+    fn subsurface_light_reaches_unlit_points() {
+        let sphere = Arc::new(Sphere::new(Box::new(Subsurface::new(Color::gray_scale(1.0), 0.2, 1.3))));
+        let light: Box<dyn Light> = Box::new(PointLight::new(Point::new(0.0, 5.0, 0.0), 100.0, Color::gray_scale(1.0)));
+        let scene = Scene::new(vec![Instance::new(sphere)], vec![light], camera());
+
+        // the front of the sphere faces away from the light and the material reflects no light directly
+        let ray = Ray::new(Point::new(0.0, 0.0, -5.0), Direction::new(0.0, 0.0, 1.0));
+        let int = scene.intersect(&ray).unwrap();
+        assert!(int.instance().is_some());
+        // single estimates can miss every exit point, a few of them together can not
+        assert!((0..20).map(|_| brightness(scene.receive_radiance(&ray, Some(int)))).sum::<f64>() > 0.0);
+
+        // without the instance there is no surface to probe for exit points
+        let detached = Intersection::new(int.t(), int.point(), int.normal(), int.material());
+        assert_eq!(scene.receive_radiance(&ray, Some(detached)), Radiance::zero());
+    }
+
+    #[test]
+    fn absorbing_interiors_dim_the_light_passing_through() {
+        let floor = || Instance::new(Arc::new(Plane::new(Point::new(0.0, -2.0, 0.0), Normal::up(), false, Box::new(Lambertian::new(Color::gray_scale(1.0))))));
+        let light = || -> Vec<Box<dyn Light>> { vec![Box::new(PointLight::new(Point::new(0.5, 5.0, 0.0), 100.0, Color::gray_scale(1.0)))] };
+        let absorbing = Box::new(HomogeneousMedium::new(Color::gray_scale(0.5), Color::black(), 0.0));
+        let sphere = Instance::new(Arc::new(Sphere::new(Box::new(Transparent)))).with_medium(absorbing);
+
+        let open = Scene::new(vec![floor()], light(), camera());
+        let shadowed = Scene::new(vec![floor(), sphere], light(), camera());
+        let ray = Ray::new(Point::new(0.5, -1.5, 0.0), Direction::new(0.0, -1.0, 0.0));
+        let unblocked = brightness(open.receive_radiance(&ray, open.intersect(&ray)));
+        let dimmed = brightness(shadowed.receive_radiance(&ray, shadowed.intersect(&ray)));
+
+        // the shadow ray crosses the unit sphere along a chord of length sqrt(3)
+        assert!(unblocked > 0.0);
+        assert!((dimmed/unblocked - (-0.5*3f64.sqrt()).exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fog_is_lit_inside_the_spot_cone_only() {
+        let spot: Box<dyn Light> = Box::new(SpotLight::new(Point::new(0.0, 3.0, 0.0), Direction::new(0.0, -1.0, 0.0),
+            20f64.to_radians(), 30f64.to_radians(), 100.0, Color::gray_scale(1.0)));
+        let fog = Box::new(HomogeneousMedium::new(Color::black(), Color::gray_scale(0.1), 0.0));
+        let scene = Scene::new(vec![], vec![spot], camera()).with_medium(fog);
+
+        // the ray through the cone is lit wherever its sample falls under the light
+        let shaft = Ray::new(Point::new(-10.0, 0.0, 0.0), Direction::new(1.0, 0.0, 0.0));
+        let lit: f64 = (0..200).map(|_| brightness(scene.receive_radiance(&shaft, None))).sum();
+        assert!(lit > 0.0);
+        // at this height the cone reaches less than two units from its axis
+        let beside = Ray::new(Point::new(-10.0, 0.0, 4.0), Direction::new(1.0, 0.0, 0.0));
+        assert!((0..200).all(|_| brightness(scene.receive_radiance(&beside, None)) == 0.0));
+    }
+}
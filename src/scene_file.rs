@@ -0,0 +1,1037 @@
+use std::fs;
+use std::io;
+use std::sync::Arc;
+
+use crate::settings::{self, Settings, RenderMode, ColorModel, AccelerationStructureKind, VolumeSampling, MeshCaching, SamplingTechnique};
+use crate::math::{Point, Vector, Direction, Normal, Matrix, RotationAxis};
+use crate::cg_tools::{Color, Transformation};
+use crate::camera::PerspectiveCamera;
+use crate::scene::Scene;
+use crate::objects::{Instance, Object, ObjectDescription, Sphere, Plane, BoxObject, Triangle, Rectangle, Light, LightDescription, PointLight, SpotLight,
+    DistantLight, SurfaceLight, Material, MaterialDescription, Lambertian, Phong, ThinDielectric, ThinFilm, Subsurface, Transparent,
+    Volume, Medium, MediumDescription, HomogeneousMedium, GridMedium, ImportError, parse_obj, parse_obj_groups, parse_ply, parse_stl};
+
+// Native scene description. Every line holds one statement, a keyword followed by its arguments. Comments start
+// with # and paths containing spaces are quoted. Blocks are opened by settings, camera, object or light and closed by end:
+//
+//   settings
+//       screen_width 800
+//       acceleration_structure bvh
+//   end
+//   camera
+//       position 0 0.5 -3
+//       direction 0 0 1
+//       fov 60
+//   end
+//   object sphere
+//       material lambertian color 1 0 0
+//       scale 2 1 1
+//       rotate z 45
+//       translate 2 0 4
+//   end
+//   light point
+//       position -2 2 0
+//       power 600
+//       color xyz 1 1 1
+//   end
+//   object volume
+//       medium grid file vol/smoke.raw extinction 8 albedo 0.9 0.9 0.9 g 0.2
+//       scale 2
+//   end
+//   medium homogeneous absorption 0.01 0.01 0.01 scattering 0.05 0.05 0.05 g 0.3
+//
+// Meshes are read from OBJ, PLY or STL files given by file, group selects a single object or group of an OBJ file.
+// A medium statement outside of the blocks fills the space around the objects, inside an object block it fills
+// the interior of the object. Grid media span the unit cube of their object, usually an invisible volume.
+// Transformations apply in the order they are written, angles are given in degrees and file paths are relative
+// to the working directory. The settings replace the global settings at the end of their block, so they apply
+// to the meshes read afterwards and to the acceleration structure of the scene. That structure only indexes the
+// objects, every mesh searches its triangles with its own BVH.
+pub fn parse_scene(file_path: &str) -> Result<Scene, ImportError> {
+    let text = fs::read_to_string(file_path)?;
+    let mut statements = tokenize(file_path, &text)?.into_iter();
+    let (mut instances, mut lights, mut camera, mut medium) = (vec![], vec![], None, None);
+
+    while let Some(mut header) = statements.next() {
+        let keyword = header.keyword.clone();
+        if keyword == "medium" {
+            if medium.is_some() {
+                return Err(header.error("The scene already has a medium".to_string()));
+            }
+            medium = Some(read_medium(&mut header, None)?);
+            continue;
+        }
+        let mut block = Block{header_line: header.line, file_path, statements: vec![]};
+        loop {
+            match statements.next() {
+                Some(statement) if statement.keyword == "end" => {
+                    statement.finish()?;
+                    break;
+                },
+                Some(statement) => block.statements.push(Some(statement)),
+                None => return Err(header.error(format!("Block {} is not closed with end", keyword)))
+            }
+        }
+
+        match keyword.as_str() {
+            "settings" => {
+                header.finish()?;
+                read_settings(block)?;
+            },
+            "camera" => {
+                header.finish()?;
+                camera = Some(read_camera(block)?);
+            },
+            "object" => {
+                let kind = header.word()?;
+                header.finish()?;
+                instances.push(read_object(&kind, block)?);
+            },
+            "light" => {
+                let kind = header.word()?;
+                header.finish()?;
+                lights.push(read_light(&kind, block)?);
+            },
+            _ => return Err(header.error(format!("Unknown block {}", keyword)))
+        }
+    }
+
+    let camera = camera.ok_or_else(|| ImportError::invalid(file_path, "Scene without camera".to_string()))?;
+    println!("Imported scene: {}", file_path);
+    println!("Amount of instances: {}", instances.len());
+    let scene = Scene::new(instances, lights, camera);
+    Ok(match medium {
+        Some(medium) => scene.with_medium(medium),
+        None => scene
+    })
+}
+
+fn read_settings(block: Block) -> Result<(), ImportError> {
+    let mut settings = settings::get().clone();
+    for mut statement in block.statements.into_iter().flatten() {
+        match statement.keyword.as_str() {
+            "screen_width" => settings.screen_width = statement.count()? as u32,
+            "screen_height" => settings.screen_height = statement.count()? as u32,
+            "chunk_width" => settings.chunk_width = statement.count()? as u32,
+            "chunk_height" => settings.chunk_height = statement.count()? as u32,
+            "render_mode" => settings.render_mode = match statement.word()?.as_str() {
+                "default" => RenderMode::Default,
+                "normals" => RenderMode::Normals,
+                "distance" => RenderMode::Distance(statement.number()?),
+                "bounding_box" => RenderMode::BoundingBox,
+                mode => return Err(statement.error(format!("Unknown render mode {}", mode)))
+            },
+            "gamma" => settings.gamma = statement.number()?,
+            "color_model" => settings.color_model = match statement.word()?.as_str() {
+                "rgb" => ColorModel::RGB,
+                "xyz" => ColorModel::XYZ,
+                model => return Err(statement.error(format!("Unknown color model {}", model)))
+            },
+            "acceleration_structure" => settings.acceleration_structure = match statement.word()?.as_str() {
+                "brute_force" => AccelerationStructureKind::BruteForce,
+                "bvh" => AccelerationStructureKind::BVH,
+                "bvh4" => AccelerationStructureKind::BVH4,
+                "bvh8" => AccelerationStructureKind::BVH8,
+                "kd_tree" => AccelerationStructureKind::KdTree,
+                "grid" => AccelerationStructureKind::Grid{hierarchical: false},
+                "hierarchical_grid" => AccelerationStructureKind::Grid{hierarchical: true},
+                kind => return Err(statement.error(format!("Unknown acceleration structure {}", kind)))
+            },
+            "amt_threads" => settings.amt_threads = statement.count()?,
+            "aa_multi_sample" => settings.aa_multi_sample = statement.count()? as u32,
+            "packet_size" => settings.packet_size = statement.count()? as u32,
+            "max_depth" => settings.max_depth = statement.count()? as u32,
+            "subsurface_samples" => settings.subsurface_samples = statement.count()? as u32,
+            "volume_sampling" => settings.volume_sampling = match statement.word()?.as_str() {
+                "free_path" => VolumeSampling::FreePath,
+                "equiangular" => VolumeSampling::Equiangular,
+                sampling => return Err(statement.error(format!("Unknown volume sampling {}", sampling)))
+            },
+            "mesh_caching" => settings.mesh_caching = match statement.word()?.as_str() {
+                "disabled" => MeshCaching::Disabled,
+                "next_to_source" => MeshCaching::NextToSource,
+                // the settings live for the whole program, so the directory can be leaked
+                "directory" => MeshCaching::Directory(Box::leak(statement.word()?.into_boxed_str())),
+                caching => return Err(statement.error(format!("Unknown mesh caching {}", caching)))
+            },
+            "crease_angle" => settings.crease_angle = statement.number()?.to_radians(),
+            "light_sampling_technique" => settings.light_sampling_technique = match statement.word()?.as_str() {
+                "grid" => SamplingTechnique::Grid(statement.count()? as i32),
+                "random" => SamplingTechnique::Random{multi_sample: statement.count()? as i32, seed: statement.number()?},
+                "stratified" => SamplingTechnique::Stratified{multi_sample: statement.count()? as i32, seed: statement.number()?},
+                technique => return Err(statement.error(format!("Unknown sampling technique {}", technique)))
+            },
+            keyword => return Err(statement.error(format!("Unknown setting {}", keyword)))
+        }
+        statement.finish()?;
+    }
+    settings::set(settings);
+    Ok(())
+}
+
+fn read_camera(mut block: Block) -> Result<PerspectiveCamera, ImportError> {
+    let position = block.point("position", Point::origin())?;
+    let direction = Direction::from(block.vector("direction", Vector::new(0.0, 0.0, 1.0))?);
+    let up = Direction::from(block.vector("up", Vector::up())?);
+    let fov = block.number("fov", 60.0)?;
+    block.finish("camera")?;
+    Ok(PerspectiveCamera::new(position, direction, up, fov))
+}
+
+fn read_object(kind: &str, mut block: Block) -> Result<Instance, ImportError> {
+    let transformation = block.transformation()?;
+    let double_sided = block.flag("double_sided")?;
+    let object: Arc<dyn Object> = match kind {
+        "sphere" => Arc::new(Sphere::new(block.material()?)),
+        "plane" => {
+            let point = block.point("point", Point::origin())?;
+            let normal = Normal::from(block.vector("normal", Vector::up())?);
+            Arc::new(Plane::new(point, normal, double_sided, block.material()?))
+        },
+        "box" => {
+            let min = block.point("min", Point::origin())?;
+            let max = block.point("max", Point::new(1.0, 1.0, 1.0))?;
+            Arc::new(BoxObject::new(min, max, block.material()?))
+        },
+        "triangle" => {
+            let mut statement = block.require("vertices")?;
+            let vertices = statement.points(3)?;
+            statement.finish()?;
+            let triangle = Triangle::new([vertices[0], vertices[1], vertices[2]], double_sided, block.material()?);
+            match block.take("normals") {
+                Some(mut statement) => {
+                    let normals = statement.points(3)?;
+                    statement.finish()?;
+                    let normal = |i: usize| Normal::from(normals[i] - Point::origin());
+                    Arc::new(triangle.with_normals([normal(0), normal(1), normal(2)]))
+                },
+                None => Arc::new(triangle)
+            }
+        },
+        "rectangle" => Arc::new(match block.take("points") {
+            Some(mut statement) => {
+                let points = statement.points(4)?;
+                statement.finish()?;
+                Rectangle::new([points[0], points[1], points[2], points[3]], double_sided, block.material()?)
+            },
+            None => Rectangle::unit_square(double_sided, block.material()?)
+        }),
+        "mesh" => {
+            let mut statement = block.require("file")?;
+            let file = statement.word()?;
+            statement.finish()?;
+            let group = match block.take("group") {
+                Some(mut group) => {
+                    let name = group.word()?;
+                    group.finish()?;
+                    Some((group, name))
+                },
+                None => None
+            };
+            let mut mesh = match (file.rsplit('.').next().map(|extension| extension.to_lowercase()).as_deref(), group) {
+                (Some("obj"), None) => parse_obj(&file)?,
+                // only the faces of one object or group of the file
+                (Some("obj"), Some((group, name))) => parse_obj_groups(&file)?.into_iter()
+                    .find(|(group_name, _)| *group_name == name).map(|(_, mesh)| mesh)
+                    .ok_or_else(|| group.error(format!("No group {} in {}", name, file)))?,
+                (_, Some((group, _))) => return Err(group.error("Groups can only be selected from OBJ files".to_string())),
+                (Some("ply"), None) => parse_ply(&file)?,
+                (Some("stl"), None) => parse_stl(&file)?,
+                _ => return Err(statement.error(format!("Unsupported mesh format: {}", file)))
+            };
+            // the materials of the file are kept unless the object gives one
+            if block.has("material") {
+                mesh.set_material(block.material()?);
+            }
+            mesh.set_double_sided(double_sided);
+            Arc::new(mesh)
+        },
+        "volume" => Arc::new(Volume::new()),
+        _ => return Err(block.error(format!("Unknown object {}", kind)))
+    };
+    let medium = match block.take("medium") {
+        Some(mut statement) => Some(read_medium(&mut statement, Some(&transformation))?),
+        None => None
+    };
+    block.finish(kind)?;
+    let instance = Instance::transformed(object, transformation);
+    Ok(match medium {
+        Some(medium) => instance.with_medium(medium),
+        None => instance
+    })
+}
+
+fn read_light(kind: &str, mut block: Block) -> Result<Box<dyn Light>, ImportError> {
+    let color = block.color("color", Color::gray_scale(1.0))?;
+    let light: Box<dyn Light> = match kind {
+        "point" => {
+            let position = block.point("position", Point::origin())?;
+            Box::new(PointLight::new(position, block.required_number("power")?, color))
+        },
+        "spot" => {
+            let position = block.point("position", Point::origin())?;
+            let direction = Direction::from(block.vector("direction", Vector::new(0.0, -1.0, 0.0))?);
+            let inner = block.number("inner_angle", 22.5)?.to_radians();
+            let outer = block.number("outer_angle", 45.0)?.to_radians();
+            Box::new(SpotLight::new(position, direction, inner, outer, block.required_number("power")?, color))
+        },
+        "distant" => {
+            let direction = Direction::from(block.vector("direction", Vector::new(0.0, -1.0, 0.0))?);
+            Box::new(DistantLight::new(direction, block.required_number("irradiance")?, color))
+        },
+        "surface" => {
+            let surface_material = Box::new(Lambertian::new(Color::gray_scale(1.0)));
+            let surface = match block.take("points") {
+                Some(mut statement) => {
+                    let points = statement.points(4)?;
+                    statement.finish()?;
+                    Rectangle::new([points[0], points[1], points[2], points[3]], false, surface_material)
+                },
+                None => Rectangle::unit_square(false, surface_material)
+            };
+            let transformation = block.transformation()?;
+            Box::new(SurfaceLight::transformed(surface, transformation, block.required_number("power")?, color))
+        },
+        _ => return Err(block.error(format!("Unknown light {}", kind)))
+    };
+    block.finish(kind)?;
+    Ok(light)
+}
+
+// Everything after the kind of the material, e.g. phong diffuse 0.8 0.8 0.8 specular 0.2 0.2 0.2 exponent 50
+fn read_material(statement: &mut Statement) -> Result<Box<dyn Material>, ImportError> {
+    let kind = statement.word()?;
+    if !["lambertian", "phong", "thin_dielectric", "subsurface", "transparent"].contains(&kind.as_str()) {
+        return Err(statement.error(format!("Unknown material {}", kind)));
+    }
+    let (mut color, mut diffuse, mut specular, mut emission) = (Color::gray_scale(1.0), Color::gray_scale(1.0), Color::black(), Color::black());
+    let (mut exponent, mut dissolve, mut mirror) = (1.0, 1.0, false);
+    let (mut ior, mut mean_free_path, mut film) = (1.5, 0.1, None);
+
+    while let Some(option) = statement.next() {
+        match (kind.as_str(), option.as_str()) {
+            ("lambertian" | "thin_dielectric" | "subsurface", "color") => color = statement.color()?,
+            ("phong", "diffuse") => diffuse = statement.color()?,
+            ("phong", "specular") => specular = statement.color()?,
+            ("phong", "emission") => emission = statement.color()?,
+            ("phong", "exponent") => exponent = statement.number()?,
+            ("phong", "dissolve") => dissolve = statement.number()?,
+            ("phong", "mirror") => mirror = true,
+            ("thin_dielectric" | "subsurface", "ior") => ior = statement.number()?,
+            ("thin_dielectric", "film") => film = Some(ThinFilm::new(statement.number()?, statement.number()?)),
+            ("subsurface", "mean_free_path") => mean_free_path = statement.number()?,
+            _ => return Err(statement.error(format!("Unknown option {} of material {}", option, kind)))
+        }
+    }
+
+    Ok(match kind.as_str() {
+        "lambertian" => Box::new(Lambertian::new(color)),
+        "phong" => {
+            let phong = Phong::new(diffuse, specular, exponent).with_emission(emission).with_dissolve(dissolve);
+            Box::new(if mirror { phong.with_mirror() } else { phong })
+        },
+        "thin_dielectric" => Box::new(match film {
+            Some(film) => ThinDielectric::with_film(film, color),
+            None => ThinDielectric::new(ior, color)
+        }),
+        "subsurface" => Box::new(Subsurface::new(color, mean_free_path, ior)),
+        _ => Box::new(Transparent)
+    })
+}
+
+// Everything after the kind of the medium, e.g. homogeneous absorption 0.1 0.1 0.1 scattering 0.5 0.5 0.5 g 0.3.
+// Grids are placed by the transformation of their object, so they can not fill the whole scene.
+fn read_medium(statement: &mut Statement, transformation: Option<&Transformation>) -> Result<Box<dyn Medium>, ImportError> {
+    let kind = statement.word()?;
+    if !["homogeneous", "grid"].contains(&kind.as_str()) {
+        return Err(statement.error(format!("Unknown medium {}", kind)));
+    }
+    let (mut absorption, mut scattering, mut g) = (Color::black(), Color::black(), 0.0);
+    let (mut file, mut extinction, mut albedo) = (None, 1.0, Color::gray_scale(1.0));
+    while let Some(option) = statement.next() {
+        match (kind.as_str(), option.as_str()) {
+            ("homogeneous", "absorption") => absorption = statement.color()?,
+            ("homogeneous", "scattering") => scattering = statement.color()?,
+            ("grid", "file") => file = Some(statement.word()?),
+            ("grid", "extinction") => extinction = statement.number()?,
+            ("grid", "albedo") => albedo = statement.color()?,
+            (_, "g") => g = statement.number()?,
+            _ => return Err(statement.error(format!("Unknown option {} of medium {}", option, kind)))
+        }
+    }
+
+    if kind == "homogeneous" {
+        return Ok(Box::new(HomogeneousMedium::new(absorption, scattering, g)));
+    }
+    let file = file.ok_or_else(|| statement.error("Missing file of the grid".to_string()))?;
+    let transformation = transformation.ok_or_else(|| statement.error("A grid has to fill an object".to_string()))?;
+    let grid = GridMedium::load(&file, transformation.clone(), extinction, albedo, g)?;
+    Ok(Box::new(grid))
+}
+
+fn tokenize(file_path: &str, text: &str) -> Result<Vec<Statement>, ImportError> {
+    let mut statements = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let mut words = vec![];
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '#' => break,
+                '"' => {
+                    let mut word = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some(c) => word.push(c),
+                            None => return Err(ImportError::parse(file_path, index + 1, "Unterminated string".to_string()))
+                        }
+                    }
+                    words.push(word);
+                },
+                c if c.is_whitespace() => (),
+                c => {
+                    let mut word = c.to_string();
+                    while let Some(c) = chars.peek().cloned().filter(|c| !c.is_whitespace() && *c != '#' && *c != '"') {
+                        word.push(c);
+                        chars.next();
+                    }
+                    words.push(word);
+                }
+            }
+        }
+        if !words.is_empty() {
+            let keyword = words.remove(0);
+            statements.push(Statement{file_path: file_path.to_string(), line: index + 1, keyword, arguments: words, position: 0});
+        }
+    }
+    Ok(statements)
+}
+
+//////////////////
+//Statement
+//////////////////
+// One line of the file, the arguments are read from the front
+struct Statement {
+    file_path: String,
+    line: usize,
+    keyword: String,
+    arguments: Vec<String>,
+    position: usize
+}
+
+impl Statement {
+    fn error(&self, message: String) -> ImportError {
+        ImportError::parse(&self.file_path, self.line, message)
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let argument = self.arguments.get(self.position).cloned();
+        if argument.is_some() { self.position += 1 }
+        argument
+    }
+
+    fn remaining(&self) -> usize {
+        self.arguments.len() - self.position
+    }
+
+    fn word(&mut self) -> Result<String, ImportError> {
+        let keyword = self.keyword.clone();
+        self.next().ok_or_else(|| self.error(format!("Missing argument of {}", keyword)))
+    }
+
+    fn number(&mut self) -> Result<f64, ImportError> {
+        let word = self.word()?;
+        word.parse().map_err(|_| self.error(format!("Expected a number instead of {}", word)))
+    }
+
+    fn count(&mut self) -> Result<usize, ImportError> {
+        let word = self.word()?;
+        word.parse().map_err(|_| self.error(format!("Expected a non-negative integer instead of {}", word)))
+    }
+
+    fn numbers(&mut self, count: usize) -> Result<Vec<f64>, ImportError> {
+        (0..count).map(|_| self.number()).collect()
+    }
+
+    fn points(&mut self, count: usize) -> Result<Vec<Point>, ImportError> {
+        let numbers = self.numbers(3*count)?;
+        Ok(numbers.chunks(3).map(|p| Point::new(p[0], p[1], p[2])).collect())
+    }
+
+    // Three rgb components, or xyz followed by the components
+    fn color(&mut self) -> Result<Color, ImportError> {
+        if self.arguments.get(self.position).map(|word| word.as_str()) == Some("xyz") {
+            self.position += 1;
+            let c = self.numbers(3)?;
+            return Ok(Color::new_xyz(c[0], c[1], c[2]));
+        }
+        let c = self.numbers(3)?;
+        Ok(Color::new_rgb(c[0], c[1], c[2]))
+    }
+
+    fn finish(&self) -> Result<(), ImportError> {
+        match self.arguments.get(self.position) {
+            Some(argument) => Err(self.error(format!("Unexpected argument {} of {}", argument, self.keyword))),
+            None => Ok(())
+        }
+    }
+}
+
+//////////////////
+//Block
+//////////////////
+// Statements between a block header and its end, every statement has to be used exactly once
+struct Block<'a> {
+    header_line: usize,
+    file_path: &'a str,
+    statements: Vec<Option<Statement>>
+}
+
+impl<'a> Block<'a> {
+    fn error(&self, message: String) -> ImportError {
+        ImportError::parse(self.file_path, self.header_line, message)
+    }
+
+    fn has(&self, keyword: &str) -> bool {
+        self.statements.iter().flatten().any(|s| s.keyword == keyword)
+    }
+
+    fn take(&mut self, keyword: &str) -> Option<Statement> {
+        self.statements.iter_mut().find(|s| s.as_ref().is_some_and(|s| s.keyword == keyword)).and_then(|s| s.take())
+    }
+
+    fn require(&mut self, keyword: &str) -> Result<Statement, ImportError> {
+        self.take(keyword).ok_or_else(|| self.error(format!("Missing {}", keyword)))
+    }
+
+    fn flag(&mut self, keyword: &str) -> Result<bool, ImportError> {
+        match self.take(keyword) {
+            Some(statement) => statement.finish().map(|_| true),
+            None => Ok(false)
+        }
+    }
+
+    fn number(&mut self, keyword: &str, default: f64) -> Result<f64, ImportError> {
+        match self.take(keyword) {
+            Some(mut statement) => {
+                let number = statement.number()?;
+                statement.finish().map(|_| number)
+            },
+            None => Ok(default)
+        }
+    }
+
+    fn required_number(&mut self, keyword: &str) -> Result<f64, ImportError> {
+        let mut statement = self.require(keyword)?;
+        let number = statement.number()?;
+        statement.finish().map(|_| number)
+    }
+
+    fn point(&mut self, keyword: &str, default: Point) -> Result<Point, ImportError> {
+        match self.take(keyword) {
+            Some(mut statement) => {
+                let point = statement.points(1)?[0];
+                statement.finish().map(|_| point)
+            },
+            None => Ok(default)
+        }
+    }
+
+    fn vector(&mut self, keyword: &str, default: Vector) -> Result<Vector, ImportError> {
+        self.point(keyword, Point::origin() + default).map(|p| p - Point::origin())
+    }
+
+    fn color(&mut self, keyword: &str, default: Color) -> Result<Color, ImportError> {
+        match self.take(keyword) {
+            Some(mut statement) => {
+                let color = statement.color()?;
+                statement.finish().map(|_| color)
+            },
+            None => Ok(default)
+        }
+    }
+
+    fn material(&mut self) -> Result<Box<dyn Material>, ImportError> {
+        match self.take("material") {
+            Some(mut statement) => read_material(&mut statement),
+            None => Ok(Box::new(Lambertian::new(Color::gray_scale(1.0))))
+        }
+    }
+
+    // Takes all translate, scale, rotate and matrix statements in the order they are written
+    fn transformation(&mut self) -> Result<Transformation, ImportError> {
+        let mut transformation = Transformation::new();
+        for slot in self.statements.iter_mut() {
+            let keyword = match slot {
+                Some(statement) if ["translate", "scale", "rotate", "matrix"].contains(&statement.keyword.as_str()) => statement.keyword.clone(),
+                _ => continue
+            };
+            let mut statement = slot.take().unwrap();
+            transformation = match keyword.as_str() {
+                "translate" => {
+                    let v = statement.numbers(3)?;
+                    transformation.translate(Vector::new(v[0], v[1], v[2]))
+                },
+                "scale" if statement.remaining() == 1 => transformation.scale_all(statement.number()?),
+                "scale" => {
+                    let v = statement.numbers(3)?;
+                    transformation.scale(v[0], v[1], v[2])
+                },
+                "rotate" if statement.remaining() == 2 => {
+                    let axis = match statement.word()?.as_str() {
+                        "x" => RotationAxis::Xaxis,
+                        "y" => RotationAxis::Yaxis,
+                        "z" => RotationAxis::Zaxis,
+                        axis => return Err(statement.error(format!("Unknown rotation axis {}", axis)))
+                    };
+                    transformation.rotate(axis, statement.number()?.to_radians())
+                },
+                "rotate" => {
+                    let v = statement.numbers(4)?;
+                    transformation.rotate_axis(Vector::new(v[0], v[1], v[2]), v[3].to_radians())
+                },
+                _ => {
+                    let m = statement.numbers(16)?;
+                    let matrix = Matrix::from([[m[0], m[1], m[2], m[3]], [m[4], m[5], m[6], m[7]], [m[8], m[9], m[10], m[11]], [m[12], m[13], m[14], m[15]]]);
+                    let matrix = Transformation::from_matrix(matrix).ok_or_else(|| statement.error("Matrix can not be inverted".to_string()))?;
+                    transformation.then(&matrix)
+                }
+            };
+            statement.finish()?;
+        }
+        Ok(transformation)
+    }
+
+    // Statements left over were not understood by the block
+    fn finish(self, kind: &str) -> Result<(), ImportError> {
+        match self.statements.into_iter().flatten().next() {
+            Some(statement) => Err(statement.error(format!("Unexpected statement {} in {}", statement.keyword, kind))),
+            None => Ok(())
+        }
+    }
+}
+
+// Writes the scene together with the global settings. Objects, materials, media and lights this format can not
+// describe, like textures or meshes not read from a file, are left out with a comment.
+pub fn write_scene(file_path: &str, scene: &Scene) -> Result<(), io::Error> {
+    fs::write(file_path, scene_to_string(scene))
+}
+
+pub fn scene_to_string(scene: &Scene) -> String {
+    let mut out = String::new();
+    write_block(&mut out, "settings", describe_settings(settings::get()));
+
+    let camera = scene.camera();
+    write_block(&mut out, "camera", vec![
+        format!("position {}", point(camera.position())),
+        format!("direction {}", vector(*camera.direction())),
+        format!("up {}", vector(*camera.up())),
+        format!("fov {}", camera.fov().to_degrees())
+    ]);
+
+    for instance in scene.instances() {
+        match describe_object(instance.object()) {
+            Some((kind, mut statements)) => {
+                statements.extend(matrix(instance.transformation().matrix()));
+                statements.extend(instance.medium().map(|medium| match describe_medium(medium) {
+                    Some(medium) => format!("medium {}", medium),
+                    None => "# the medium can not be written".to_string()
+                }));
+                write_block(&mut out, &format!("object {}", kind), statements);
+            },
+            None => out.push_str("# an object that can not be written was left out\n\n")
+        }
+    }
+    for light in scene.lights() {
+        match describe_light(light.as_ref()) {
+            Some((kind, statements)) => write_block(&mut out, &format!("light {}", kind), statements),
+            None => out.push_str("# a light that can not be written was left out\n\n")
+        }
+    }
+    match scene.medium().map(describe_medium) {
+        Some(Some(medium)) => out.push_str(&format!("medium {}\n", medium)),
+        Some(None) => out.push_str("# the medium of the scene can not be written\n"),
+        None => ()
+    }
+    out
+}
+
+fn write_block(out: &mut String, header: &str, statements: Vec<String>) {
+    out.push_str(header);
+    out.push('\n');
+    for statement in statements {
+        out.push_str("    ");
+        out.push_str(&statement);
+        out.push('\n');
+    }
+    out.push_str("end\n\n");
+}
+
+fn describe_settings(settings: &Settings) -> Vec<String> {
+    let render_mode = match settings.render_mode {
+        RenderMode::Default => "default".to_string(),
+        RenderMode::Normals => "normals".to_string(),
+        RenderMode::Distance(distance) => format!("distance {}", distance),
+        RenderMode::BoundingBox => "bounding_box".to_string()
+    };
+    let color_model = match settings.color_model {
+        ColorModel::RGB => "rgb",
+        ColorModel::XYZ => "xyz"
+    };
+    let acceleration_structure = match settings.acceleration_structure {
+        AccelerationStructureKind::BruteForce => "brute_force",
+        AccelerationStructureKind::BVH => "bvh",
+        AccelerationStructureKind::BVH4 => "bvh4",
+        AccelerationStructureKind::BVH8 => "bvh8",
+        AccelerationStructureKind::KdTree => "kd_tree",
+        AccelerationStructureKind::Grid{hierarchical: false} => "grid",
+        AccelerationStructureKind::Grid{hierarchical: true} => "hierarchical_grid"
+    };
+    let volume_sampling = match settings.volume_sampling {
+        VolumeSampling::FreePath => "free_path",
+        VolumeSampling::Equiangular => "equiangular"
+    };
+    let mesh_caching = match settings.mesh_caching {
+        MeshCaching::Disabled => "disabled".to_string(),
+        MeshCaching::NextToSource => "next_to_source".to_string(),
+        MeshCaching::Directory(directory) => format!("directory {}", path(directory))
+    };
+    let light_sampling_technique = match settings.light_sampling_technique {
+        SamplingTechnique::Grid(multi_sample) => format!("grid {}", multi_sample),
+        SamplingTechnique::Random{multi_sample, seed} => format!("random {} {}", multi_sample, seed),
+        SamplingTechnique::Stratified{multi_sample, seed} => format!("stratified {} {}", multi_sample, seed)
+    };
+    vec![
+        format!("screen_width {}", settings.screen_width),
+        format!("screen_height {}", settings.screen_height),
+        format!("chunk_width {}", settings.chunk_width),
+        format!("chunk_height {}", settings.chunk_height),
+        format!("render_mode {}", render_mode),
+        format!("gamma {}", settings.gamma),
+        format!("color_model {}", color_model),
+        format!("acceleration_structure {}", acceleration_structure),
+        format!("amt_threads {}", settings.amt_threads),
+        format!("aa_multi_sample {}", settings.aa_multi_sample),
+        format!("packet_size {}", settings.packet_size),
+        format!("max_depth {}", settings.max_depth),
+        format!("subsurface_samples {}", settings.subsurface_samples),
+        format!("volume_sampling {}", volume_sampling),
+        format!("mesh_caching {}", mesh_caching),
+        format!("crease_angle {}", settings.crease_angle.to_degrees()),
+        format!("light_sampling_technique {}", light_sampling_technique)
+    ]
+}
+
+fn describe_object(object: &dyn Object) -> Option<(&'static str, Vec<String>)> {
+    let material = match describe_material(object.material()) {
+        Some(material) => format!("material {}", material),
+        None => "# the material can not be written".to_string()
+    };
+    let (kind, mut statements, double_sided) = match object.describe()? {
+        ObjectDescription::Sphere => ("sphere", vec![material], false),
+        ObjectDescription::Plane{point: p, normal, double_sided} =>
+            ("plane", vec![format!("point {}", point(p)), format!("normal {}", vector(*normal)), material], double_sided),
+        ObjectDescription::Box{min, max} => ("box", vec![format!("min {}", point(min)), format!("max {}", point(max)), material], false),
+        ObjectDescription::Triangle{vertices, normals, double_sided} => {
+            let mut statements = vec![format!("vertices {}", points(&vertices))];
+            if let Some(normals) = normals {
+                statements.push(format!("normals {}", numbers(&normals.iter().flat_map(|n| [n.x, n.y, n.z]).collect::<Vec<f64>>())));
+            }
+            statements.push(material);
+            ("triangle", statements, double_sided)
+        },
+        ObjectDescription::Rectangle{points: corners, double_sided} => ("rectangle", vec![format!("points {}", points(&corners)), material], double_sided),
+        // the boundary of a volume is always transparent
+        ObjectDescription::Volume => ("volume", vec![], false),
+        ObjectDescription::Mesh{file, group, material_replaced, double_sided} => {
+            let mut statements = vec![format!("file {}", path(file))];
+            if let Some(group) = group {
+                statements.push(format!("group {}", path(group)));
+            }
+            if material_replaced {
+                statements.push(material);
+            }
+            ("mesh", statements, double_sided)
+        }
+    };
+    if double_sided {
+        statements.push("double_sided".to_string());
+    }
+    Some((kind, statements))
+}
+
+fn describe_material(material: &dyn Material) -> Option<String> {
+    Some(match material.describe()? {
+        MaterialDescription::Lambertian{color: c} => format!("lambertian color {}", color(c)),
+        MaterialDescription::Phong{diffuse, specular, exponent, emission, dissolve, mirror} => {
+            let mut description = format!("phong diffuse {} specular {} exponent {}", color(diffuse), color(specular), exponent);
+            if emission != Color::black() {
+                description += &format!(" emission {}", color(emission));
+            }
+            if dissolve < 1.0 {
+                description += &format!(" dissolve {}", dissolve);
+            }
+            if mirror {
+                description += " mirror";
+            }
+            description
+        },
+        MaterialDescription::ThinDielectric{color: c, ior, film} => match film {
+            Some(film) => format!("thin_dielectric color {} film {} {}", color(c), film.thickness(), film.ior()),
+            None => format!("thin_dielectric color {} ior {}", color(c), ior)
+        },
+        MaterialDescription::Subsurface{color: c, mean_free_path, ior} =>
+            format!("subsurface color {} mean_free_path {} ior {}", color(c), mean_free_path, ior),
+        MaterialDescription::Transparent => "transparent".to_string()
+    })
+}
+
+fn describe_medium(medium: &dyn Medium) -> Option<String> {
+    Some(match medium.describe()? {
+        MediumDescription::Homogeneous{absorption, scattering, g} =>
+            format!("homogeneous absorption {} scattering {} g {}", color(absorption), color(scattering), g),
+        MediumDescription::Grid{file, extinction, albedo, g} =>
+            format!("grid file {} extinction {} albedo {} g {}", path(file), extinction, color(albedo), g)
+    })
+}
+
+fn describe_light(light: &dyn Light) -> Option<(&'static str, Vec<String>)> {
+    Some(match light.describe()? {
+        LightDescription::Point{position, power, color: c} => ("point", vec![
+            format!("position {}", point(position)),
+            format!("power {}", power),
+            format!("color {}", color(c))
+        ]),
+        LightDescription::Spot{position, direction, inner_angle, outer_angle, power, color: c} => ("spot", vec![
+            format!("position {}", point(position)),
+            format!("direction {}", vector(*direction)),
+            format!("inner_angle {}", inner_angle.to_degrees()),
+            format!("outer_angle {}", outer_angle.to_degrees()),
+            format!("power {}", power),
+            format!("color {}", color(c))
+        ]),
+        LightDescription::Distant{direction, irradiance, color: c} => ("distant", vec![
+            format!("direction {}", vector(*direction)),
+            format!("irradiance {}", irradiance),
+            format!("color {}", color(c))
+        ]),
+        LightDescription::Surface{points: corners, transformation, power, color: c} => {
+            let mut statements = vec![format!("points {}", points(&corners))];
+            statements.extend(matrix(transformation));
+            statements.push(format!("power {}", power));
+            statements.push(format!("color {}", color(c)));
+            ("surface", statements)
+        }
+    })
+}
+
+// Chains of transformations are written as their combined matrix
+fn matrix(matrix: Matrix) -> Option<String> {
+    if matrix == Matrix::identiy() { return None }
+    let values: Vec<f64> = (0..16).map(|i| matrix.get(i / 4, i % 4)).collect();
+    Some(format!("matrix {}", numbers(&values)))
+}
+
+fn numbers(values: &[f64]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" ")
+}
+
+fn point(p: Point) -> String {
+    numbers(&[p.x, p.y, p.z])
+}
+
+fn vector(v: Vector) -> String {
+    numbers(&[v.x, v.y, v.z])
+}
+
+fn points(points: &[Point]) -> String {
+    points.iter().map(|p| point(*p)).collect::<Vec<String>>().join(" ")
+}
+
+fn color(color: Color) -> String {
+    match color {
+        Color::RGB{r, g, b} => numbers(&[r, g, b]),
+        Color::XYZ{x, y, z} => format!("xyz {}", numbers(&[x, y, z]))
+    }
+}
+
+fn path(path: &str) -> String {
+    if path.is_empty() || path.contains(|c: char| c.is_whitespace() || c == '#') { format!("\"{}\"", path) } else { path.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::{Mesh, IndexedTriangle};
+
+    fn temp_file(name: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn parse(name: &str, text: &str) -> Result<Scene, ImportError> {
+        let file_path = temp_file(name, text);
+        let scene = parse_scene(&file_path);
+        fs::remove_file(&file_path).unwrap();
+        scene
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    // The angles of spot lights go through their cosine, so they only have to be close
+    fn same_light(a: LightDescription, b: LightDescription) -> bool {
+        match (a, b) {
+            (LightDescription::Spot{position, direction, inner_angle, outer_angle, power, color},
+                LightDescription::Spot{position: p, direction: d, inner_angle: i, outer_angle: o, power: w, color: c}) =>
+                (position, direction, power, color) == (p, d, w, c) && close(inner_angle, i) && close(outer_angle, o),
+            (a, b) => a == b
+        }
+    }
+
+    #[test]
+    fn written_scenes_are_read_back() {
+        let obj_path = temp_file("scene_file_round_trip.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\ng first\nf 1 2 3\ng second\nf 1 2 4\n");
+        let grid_path = std::env::temp_dir().join("scene_file_round_trip.raw");
+        let grid: Vec<u8> = [1u32, 1, 2].iter().flat_map(|n| n.to_le_bytes()).chain([0.5f32, 1.0].iter().flat_map(|d| d.to_le_bytes())).collect();
+        fs::write(&grid_path, grid).unwrap();
+        let grid_path = grid_path.to_string_lossy().into_owned();
+        let text = format!("\
+camera
+    position 0 1 -4
+    direction 0 0 1
+    fov 45
+end
+medium homogeneous absorption 0.01 0.02 0.03 scattering 0.05 0.05 0.05 g 0.3
+object sphere
+    material phong diffuse 0.5 0.5 0.5 specular 0.2 0.2 0.2 exponent 20 emission 1 0 0 dissolve 0.5 mirror
+    medium homogeneous absorption 1 0.5 0.25 g -0.2
+    scale 2 1 1
+    rotate z 45
+    translate 2 0 4
+end
+object plane
+    point 0 -1 0
+    double_sided
+    material lambertian color xyz 0.2 0.3 0.4
+end
+object box
+    min -1 -1 -1
+    max 1 2 3
+    material thin_dielectric color 0.9 0.9 0.9 film 400 1.33
+end
+object triangle
+    vertices 0 0 0 1 0 0 0 1 0
+    normals 0 0 -1 0 0 -1 0 0 -1
+    material subsurface color 1 0.5 0.5 mean_free_path 0.2 ior 1.4
+end
+object rectangle
+    points 0 0 0 1 0 0 1 1 0 0 1 0
+    material transparent
+end
+object mesh
+    file {}
+    group second
+    material thin_dielectric color 1 1 1 ior 1.2
+    double_sided
+    rotate 1 1 0 30
+end
+object volume
+    medium grid file {} extinction 4 albedo 0.9 0.9 0.9 g 0.2
+    scale 2
+    translate -1 -1 3
+end
+light point
+    position -2 2 0
+    power 600
+end
+light spot
+    position 0 3 0
+    inner_angle 20
+    outer_angle 35
+    power 100
+    color 1 0.8 0.6
+end
+light distant
+    direction -1 0 0
+    irradiance 2
+end
+light surface
+    points 0 0 0 1 0 0 1 0 1 0 0 1
+    translate 0 4 0
+    power 50
+end
+", path(&obj_path), path(&grid_path));
+        let scene = parse("scene_file_round_trip.scene", &text).unwrap();
+        let written = scene_to_string(&scene);
+        let read_back = parse("scene_file_round_trip_written.scene", &written).unwrap();
+        fs::remove_file(&obj_path).unwrap();
+        fs::remove_file(&grid_path).unwrap();
+
+        let (instances, read_instances) = (scene.instances(), read_back.instances());
+        assert_eq!(instances.len(), 7);
+        assert_eq!(read_instances.len(), instances.len());
+        for (inst, read) in instances.iter().zip(&read_instances) {
+            assert!(inst.object().describe().is_some());
+            assert_eq!(read.object().describe(), inst.object().describe());
+            assert_eq!(read.object().material().describe(), inst.object().material().describe());
+            assert!(read.transformation().matrix() == inst.transformation().matrix());
+            assert_eq!(read.medium().map(|medium| medium.describe()), inst.medium().map(|medium| medium.describe()));
+        }
+        assert!(instances[0].medium().is_some());
+        assert_eq!(instances[6].medium().and_then(|medium| medium.describe()),
+            Some(MediumDescription::Grid{file: &grid_path, extinction: 4.0, albedo: Color::gray_scale(0.9), g: 0.2}));
+        assert_eq!(read_back.medium().and_then(|medium| medium.describe()), Some(MediumDescription::Homogeneous{
+            absorption: Color::new_rgb(0.01, 0.02, 0.03), scattering: Color::gray_scale(0.05), g: 0.3}));
+        assert!(instances[0].transformation().matrix() != Matrix::identiy());
+
+        assert_eq!(read_back.lights().len(), 4);
+        for (light, read) in scene.lights().iter().zip(read_back.lights()) {
+            assert!(same_light(light.describe().unwrap(), read.describe().unwrap()));
+        }
+
+        let (camera, read_camera) = (scene.camera(), read_back.camera());
+        assert_eq!((read_camera.position(), read_camera.direction(), read_camera.up()), (camera.position(), camera.direction(), camera.up()));
+        assert!(close(read_camera.fov(), camera.fov()));
+    }
+
+    #[test]
+    fn objects_that_can_not_be_described_are_left_out() {
+        let camera = PerspectiveCamera::new(Point::origin(), Direction::new(0.0, 0.0, 1.0), Direction::up(), 60.0);
+        // a mesh built in code has no file to be read from
+        let triangle = IndexedTriangle{vertices: [0, 1, 2], normals: None, uvs: None, material: 0};
+        let mesh = Mesh::new(vec![Point::origin(), Point::new(1.0, 0.0, 0.0), Point::new(0.0, 1.0, 0.0)], vec![], vec![], vec![triangle],
+            vec![Box::new(Lambertian::new(Color::gray_scale(1.0)))]);
+        let written = scene_to_string(&Scene::new(vec![Instance::new(Arc::new(mesh))], vec![], camera));
+        assert!(written.contains("# an object that can not be written was left out"));
+        assert!(parse("scene_file_left_out.scene", &written).unwrap().instances().is_empty());
+    }
+
+    #[test]
+    fn errors_report_their_line() {
+        let text = "camera\n    fov 60\nend\n\nobject sphere\n    material lambertian shininess 2\nend\n";
+        match parse("scene_file_error.scene", text) {
+            Err(ImportError::Parse{line, message, ..}) => {
+                assert_eq!(line, 6);
+                assert!(message.contains("shininess"));
+            },
+            _ => panic!("expected a parse error")
+        }
+        match parse("scene_file_unclosed.scene", "camera\nend\nlight point\n    power 10\n") {
+            Err(ImportError::Parse{line, ..}) => assert_eq!(line, 3),
+            _ => panic!("expected a parse error")
+        }
+        assert!(matches!(parse("scene_file_no_camera.scene", "object sphere\nend\n"), Err(ImportError::Invalid{..})));
+        // a grid is placed by its object
+        match parse("scene_file_scene_grid.scene", "camera\nend\nmedium grid file smoke.raw\n") {
+            Err(ImportError::Parse{line, message, ..}) => assert!(line == 3 && message.contains("object")),
+            _ => panic!("expected a parse error")
+        }
+        match parse("scene_file_two_media.scene", "medium homogeneous\ncamera\nend\nmedium homogeneous g 2\n") {
+            Err(ImportError::Parse{line, ..}) => assert_eq!(line, 4),
+            _ => panic!("expected a parse error")
+        }
+    }
+}
@@ -2,6 +2,8 @@
 pub use crate::cg_tools::{SamplingTechnique,ColorModel};
 pub use crate::acceleration::AccelerationStructureKind;
 pub use crate::renderer::RenderMode;
+pub use crate::scene::VolumeSampling;
+pub use crate::objects::MeshCaching;
 
 #[derive(Clone)]
 pub struct Settings{
@@ -15,6 +17,12 @@ pub struct Settings{
     pub acceleration_structure: AccelerationStructureKind,
     pub amt_threads: usize,
     pub aa_multi_sample: u32,
+    pub packet_size: u32,
+    pub max_depth: u32,
+    pub subsurface_samples: u32,
+    pub volume_sampling: VolumeSampling,
+    pub mesh_caching: MeshCaching,
+    pub crease_angle: f64,
     pub light_sampling_technique: SamplingTechnique
 }
 
@@ -37,6 +45,12 @@ pub const DEFAULT_SETTINGS: Settings = Settings {
     acceleration_structure: AccelerationStructureKind::BVH,
     amt_threads: 4,
     aa_multi_sample: 1,
+    packet_size: 4,
+    max_depth: 5,
+    subsurface_samples: 16,
+    volume_sampling: VolumeSampling::Equiangular,
+    mesh_caching: MeshCaching::NextToSource,
+    crease_angle: std::f64::consts::FRAC_PI_3,
     light_sampling_technique: SamplingTechnique::Stratified{multi_sample: 1, seed: 0.0}
 };
 
@@ -1,3 +1,5 @@
+use std::cell::Cell;
+use std::time::Duration;
 
 struct Ratio {
     number: u32,
@@ -23,12 +25,20 @@ impl std::fmt::Display for Ratio {
 
 struct Statistics {
     object_intersections: Ratio,
-    triangle_intersections: Ratio
+    triangle_intersections: Ratio,
+    top_level_visits: u64,
+    bottom_level_visits: u64,
+    acceleration_structures: u32,
+    acceleration_build_time: Duration
 }
 
 static mut STATISTICS: Statistics = Statistics {
     object_intersections: Ratio::DEFAULT,
-    triangle_intersections: Ratio::DEFAULT
+    triangle_intersections: Ratio::DEFAULT,
+    top_level_visits: 0,
+    bottom_level_visits: 0,
+    acceleration_structures: 0,
+    acceleration_build_time: Duration::ZERO
 };
 
 pub fn object_intersection(succes: bool) {
@@ -43,11 +53,74 @@ pub fn triangle_intersection(succes: bool) {
     }
 }
 
+// Depth of instancing the current thread is traversing, 0 for the scene structure
+thread_local! {
+    static TRAVERSAL_LEVEL: Cell<u32> = const { Cell::new(0) };
+}
+
+pub fn node_visit() {
+    let top_level = TRAVERSAL_LEVEL.with(|level| level.get() == 0);
+    unsafe {
+        if top_level {
+            STATISTICS.top_level_visits += 1;
+        } else {
+            STATISTICS.bottom_level_visits += 1;
+        }
+    }
+}
+
+pub fn bottom_level<T, F>(traversal: F) -> T where F: FnOnce() -> T {
+    TRAVERSAL_LEVEL.with(|level| level.set(level.get() + 1));
+    let result = traversal();
+    TRAVERSAL_LEVEL.with(|level| level.set(level.get() - 1));
+    result
+}
+
+pub fn acceleration_build(build_time: Duration) {
+    unsafe {
+        STATISTICS.acceleration_structures += 1;
+        STATISTICS.acceleration_build_time += build_time;
+    }
+}
 
 pub fn print_statistics() {
     unsafe {
         println!("\nSTATISTICS:");
         println!("Object Intersections:\t\t{}", STATISTICS.object_intersections);
         println!("\tTriangle Intersections:\t{}", STATISTICS.triangle_intersections);
+        let (top_level, bottom_level) = (STATISTICS.top_level_visits, STATISTICS.bottom_level_visits);
+        println!("Acceleration Node Visits:\t{}", top_level + bottom_level);
+        println!("\tTop Level:\t\t{}", top_level);
+        println!("\tBottom Level:\t\t{}", bottom_level);
+        let (structures, build_time) = (STATISTICS.acceleration_structures, STATISTICS.acceleration_build_time);
+        println!("Acceleration Structures:\t{}", structures);
+        println!("\tBuild Time:\t\t{}.{:03}s", build_time.as_secs(), build_time.subsec_millis());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level() -> u32 {
+        TRAVERSAL_LEVEL.with(|level| level.get())
+    }
+
+    #[test]
+    fn nested_traversals_restore_the_level() {
+        assert_eq!(level(), 0);
+        let result = bottom_level(|| {
+            assert_eq!(level(), 1);
+            bottom_level(level)
+        });
+        assert_eq!(result, 2);
+        assert_eq!(level(), 0);
+    }
+
+    #[test]
+    fn levels_are_counted_per_thread() {
+        bottom_level(|| {
+            assert_eq!(std::thread::spawn(level).join().unwrap(), 0);
+        });
     }
 }